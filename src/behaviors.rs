@@ -1,258 +1,602 @@
 #![allow(unused_variables, dead_code)]
 use crate::gllib::*;
+use crate::ecs::*;
+use crate::collisions::*;
+use crate::snapshot::serde_instant;
+use crate::scripting::Script;
 use rapier2d::prelude::*;
 use beryllium::*;
-use std::{cell::{RefCell}, time::{Instant, Duration}};
+use std::{any::Any, collections::{BTreeMap, HashMap}, time::{Instant, Duration}};
 use ultraviolet::vec;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
-pub enum Behaviors {
-    BArrowControl,
-    BDebuggin,
-    BCameraTracking,
-    BSpawnBall,
-    BAttractionTo,
+/* objects to remove and objects to add, collected from every hook and applied at the end of the frame */
+pub type BehaviorOutput = (Vec<GameObjectID>, Vec<GameObject>);
+
+/* Each frame runs in a fixed order:
+input -> spawn hooks -> PrePhysics updates -> physics step -> collision and trigger hooks ->
+PostPhysics updates -> despawns, spawns and reparents -> world transforms -> render.
+PrePhysics is for behaviors that push bodies around, every one of them sees the positions
+from the end of the last step. PostPhysics is for behaviors that react to where things ended up.
+Within a stage behaviors run by priority, lowest first, then by object in store order,
+then in the order they were attached, so a frame always plays out the same way. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stage {
+    PrePhysics,
+    #[default]
+    PostPhysics,
+}
+
+/* priorities of the built in behaviors, anything can use values in between */
+pub const PRIORITY_DEFAULT: i32 = 0;
+/* after everything else has moved, e.g. camera tracking */
+pub const PRIORITY_LATE: i32 = 100;
+
+/* A behavior is attached to a GameObject and owns its own per-object state as struct fields.
+Every hook has a default that does nothing, so a behavior only implements what it needs.
+Behaviors are registered by name in a BehaviorRegistry so scene files and snapshots can create them. */
+pub trait Behavior: BehaviorState {
+    /* the name this behavior is registered under */
+    fn name(&self) -> &'static str;
+
+    /* which half of the frame update runs in and in what order, see Stage */
+    fn stage(&self) -> Stage {
+        Stage::PostPhysics
+    }
+
+    fn priority(&self) -> i32 {
+        PRIORITY_DEFAULT
+    }
+
+    /* runs once per frame in the behavior's stage */
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    /* runs on the frame this object starts touching another one */
+    fn on_collision_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    /* runs every following frame while the two keep touching */
+    fn on_collision_stay(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    /* runs on the frame they stop touching, also when the other object was despawned */
+    fn on_collision_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    /* like the collision hooks but for pairs involving a sensor collider,
+    on both the sensor's object and the one entering it. info.is_sensor tells which side this is */
+    fn on_trigger_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    fn on_trigger_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    /* runs on the first frame the object is in the world */
+    fn on_spawn(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        (vec![], vec![])
+    }
+
+    /* runs right before the object is removed from the world */
+    fn on_despawn(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        (vec![], vec![])
+    }
 }
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
+/* implemented for every serializable behavior, lets snapshots save the state
+and lets game code get at a behavior's concrete type */
+pub trait BehaviorState: Any {
+    fn save_state(&self) -> Result<ron::Value, String>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+impl<B: Serialize + Any> BehaviorState for B {
+    fn save_state(&self) -> Result<ron::Value, String> {
+        to_ron_value(self)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
 
-pub enum BehaviorData {
-    Behaviors(Behaviors),
+pub fn to_ron_value<S: Serialize + ?Sized>(value: &S) -> Result<ron::Value, String> {
+    let text = ron::to_string(value).map_err(|e| format!("Could not serialize value: {}", e))?;
+    ron::from_str(&text).map_err(|e| format!("Could not convert value: {}", e))
 }
 
-pub trait BehaviorDataContainer {}
+pub type BehaviorFactory = Box<dyn Fn(ron::Value) -> Result<Box<dyn Behavior>, String>>;
 
-pub fn apply_behaviors(
-    loop_ctx: &mut LoopContext<BehaviorDataContainerEnum>,
-) -> (Vec<GameObjectID>, Vec<GameObject<BehaviorDataContainerEnum>>) {
-    let mut behaviors_copy = vec![];
-    for b in loop_ctx.go.behaviors.iter() {
-        behaviors_copy.push(*b);
+pub struct BehaviorRegistry {
+    factories: HashMap<String, BehaviorFactory>,
+}
+impl BehaviorRegistry {
+    pub fn new() -> Self {
+        Self { factories: HashMap::new() }
+    }
+    pub fn register<B: Behavior + DeserializeOwned>(&mut self, name: &str) {
+        let owned_name = name.to_string();
+        self.factories.insert(name.to_string(), Box::new(move |state: ron::Value| {
+            let behavior: B = state.into_rust()
+                .map_err(|e| format!("Bad data for behavior {}: {}", owned_name, e))?;
+            Ok(Box::new(behavior) as Box<dyn Behavior>)
+        }));
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
     }
+    pub fn create(&self, name: &str, state: ron::Value) -> Result<Box<dyn Behavior>, String> {
+        let factory = self.factories.get(name)
+            .ok_or_else(|| format!("No behavior registered as '{}'", name))?;
+        factory(state)
+    }
+}
+impl Default for BehaviorRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn default_behavior_registry() -> BehaviorRegistry {
+    let mut registry = BehaviorRegistry::new();
+    registry.register::<ArrowControl>("ArrowControl");
+    registry.register::<Debuggin>("Debuggin");
+    registry.register::<CameraTracking>("CameraTracking");
+    registry.register::<SpawnBall>("SpawnBall");
+    registry.register::<AttractionTo>("AttractionTo");
+    registry.register::<GroundDetection>("GroundDetection");
+    registry.register::<TriggerDespawnSelf>("TriggerDespawnSelf");
+    registry.register::<TriggerDespawnOther>("TriggerDespawnOther");
+    registry.register::<TriggerLoadScene>("TriggerLoadScene");
+    registry.register::<TriggerApplyForce>("TriggerApplyForce");
+    registry.register::<FadeOut>("FadeOut");
+    registry.register::<Script>("Script");
+    registry
+}
+
+/* The behaviors are taken out of the object while they run so each one can get
+&mut self alongside the LoopContext. Anything added to the object during the hooks is kept. */
+fn run_hooks(
+    loop_ctx: &mut LoopContext,
+    mut hook: impl FnMut(&mut dyn Behavior, &mut LoopContext) -> BehaviorOutput
+) -> BehaviorOutput {
+    let mut behaviors = std::mem::take(&mut loop_ctx.go.behaviors);
     let mut objs_to_remove = vec![];
     let mut objs_to_add = vec![];
-    for b in behaviors_copy {
-        match b {
-            Behaviors::BArrowControl => {
-                let (mut to_remove, mut to_add) = arrow_control(loop_ctx);
-                objs_to_remove.append(&mut to_remove);
-                objs_to_add.append(&mut to_add);
-            },
-            Behaviors::BDebuggin => {
-                let (mut to_remove, mut to_add) = debuggin(loop_ctx);
-                objs_to_remove.append(&mut to_remove);
-                objs_to_add.append(&mut to_add);
-            },
-            Behaviors::BCameraTracking => {
-                let (mut to_remove, mut to_add) = camera_tracking(loop_ctx);
-                objs_to_remove.append(&mut to_remove);
-                objs_to_add.append(&mut to_add);
-            },
-            Behaviors::BSpawnBall => {
-                let (mut to_remove, mut to_add) = spawn_ball(loop_ctx);
-                objs_to_remove.append(&mut to_remove);
-                objs_to_add.append(&mut to_add);
-            },
-            Behaviors::BAttractionTo => {
-                let (mut to_remove, mut to_add) = attraction_to(loop_ctx);
-                objs_to_remove.append(&mut to_remove);
-                objs_to_add.append(&mut to_add);
-            }
-        }
+    for b in behaviors.iter_mut() {
+        let (mut to_remove, mut to_add) = hook(b.as_mut(), loop_ctx);
+        objs_to_remove.append(&mut to_remove);
+        objs_to_add.append(&mut to_add);
     }
+    behaviors.append(&mut loop_ctx.go.behaviors);
+    loop_ctx.go.behaviors = behaviors;
     (objs_to_remove, objs_to_add)
 }
 
-pub fn arrow_control(
-    loop_ctx: &mut LoopContext<BehaviorDataContainerEnum>
-) -> (Vec<GameObjectID>, Vec<GameObject<BehaviorDataContainerEnum>>) {
-    if let Some(rb_handle) = loop_ctx.go.rigid_body_handle {
-        if let Some(BehaviorDataContainerEnum::ArrowControlData(arrow_control_data)) = loop_ctx.go.behaviors_data.get(&BehaviorData::Behaviors(Behaviors::BArrowControl)) {
-            let mut impulse = vector![0.0,0.0];
-            let mut moved = false;  
-            if loop_ctx.keys_held.contains(&Keycode::RIGHT) { impulse.x += arrow_control_data.accel ; moved = true;}
-            if loop_ctx.keys_held.contains(&Keycode::LEFT) { impulse.x -= arrow_control_data.accel ; moved = true;}
-            if loop_ctx.go.grounded && loop_ctx.keys_held.contains(&Keycode::SPACE) { impulse.y = arrow_control_data.accel * 2.0; loop_ctx.go.grounded = false;}
-            loop_ctx.rigid_body_set[rb_handle].apply_impulse(impulse, true);
-            let mut linvel = loop_ctx.rigid_body_set[rb_handle].linvel().clone();
-            if loop_ctx.go.grounded && !moved {
-                linvel.x = linvel.x * 0.95;
-            } 
-            linvel.x = f32::max(f32::min(linvel.x, arrow_control_data.max_speed), -arrow_control_data.max_speed);
-            loop_ctx.rigid_body_set[rb_handle].set_linvel(linvel, true);
+pub fn apply_behaviors(loop_ctx: &mut LoopContext) -> BehaviorOutput {
+    run_hooks(loop_ctx, |b, loop_ctx| b.update(loop_ctx))
+}
+
+/* runs update for the object's behaviors in `stage` with `priority` */
+pub fn apply_stage_behaviors(loop_ctx: &mut LoopContext, stage: Stage, priority: i32) -> BehaviorOutput {
+    run_hooks(loop_ctx, |b, loop_ctx| {
+        if b.stage() == stage && b.priority() == priority {
+            b.update(loop_ctx)
+        } else {
+            (vec![], vec![])
+        }
+    })
+}
+
+/* the priorities used in `stage`, lowest first, each with the objects to run in store order */
+pub fn stage_schedule(store: &GameObjectStore, stage: Stage) -> Vec<(i32, Vec<GameObjectID>)> {
+    let mut schedule: BTreeMap<i32, Vec<GameObjectID>> = BTreeMap::new();
+    for id in store.ids() {
+        let mut priorities: Vec<i32> = store.behaviors.get(&id).map_or(vec![], |behaviors| {
+            behaviors.iter().filter(|b| b.stage() == stage).map(|b| b.priority()).collect()
+        });
+        priorities.sort();
+        priorities.dedup();
+        for priority in priorities {
+            schedule.entry(priority).or_default().push(id);
         }
     }
-    (vec![], vec![])
+    schedule.into_iter().collect()
 }
-#[derive(Debug)]
 
-pub struct ArrowControlData{
+pub fn apply_collision_behaviors(loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+    run_hooks(loop_ctx, |b, loop_ctx| match (info.is_trigger(), info.phase) {
+        (false, CollisionPhase::Enter) => b.on_collision_enter(loop_ctx, info),
+        (false, CollisionPhase::Stay) => b.on_collision_stay(loop_ctx, info),
+        (false, CollisionPhase::Exit) => b.on_collision_exit(loop_ctx, info),
+        (true, CollisionPhase::Enter) => b.on_trigger_enter(loop_ctx, info),
+        (true, CollisionPhase::Stay) => (vec![], vec![]),
+        (true, CollisionPhase::Exit) => b.on_trigger_exit(loop_ctx, info),
+    })
+}
+
+pub fn apply_spawn_behaviors(loop_ctx: &mut LoopContext) -> BehaviorOutput {
+    run_hooks(loop_ctx, |b, loop_ctx| b.on_spawn(loop_ctx))
+}
+
+pub fn apply_despawn_behaviors(loop_ctx: &mut LoopContext) -> BehaviorOutput {
+    run_hooks(loop_ctx, |b, loop_ctx| b.on_despawn(loop_ctx))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArrowControl {
     pub accel: f32,
     pub max_speed: f32
 }
-impl BehaviorDataContainer for ArrowControlData{}
+impl Behavior for ArrowControl {
+    fn name(&self) -> &'static str { "ArrowControl" }
+
+    fn stage(&self) -> Stage { Stage::PrePhysics }
 
-pub fn debuggin(
-    loop_ctx: &mut LoopContext<BehaviorDataContainerEnum>
-) -> (Vec<GameObjectID>, Vec<GameObject<BehaviorDataContainerEnum>>) {
-    let (mut rx, mut ry, rz) = (0.0_f32, 0.0_f32, 0.0_f32);
-    if loop_ctx.keys_held.contains(&Keycode::I) { ry += 1.0; } 
-    if loop_ctx.keys_held.contains(&Keycode::K) { ry -= 1.0; } 
-    if loop_ctx.keys_held.contains(&Keycode::L) { rx += 1.0; }
-    if loop_ctx.keys_held.contains(&Keycode::J) { rx -= 1.0; }
-    if let Some(draw_obj) = &mut loop_ctx.go.drawable_object {
-        if loop_ctx.keys_held.contains(&Keycode::SPACE) { println!("{:?}", draw_obj.position) }
-        draw_obj.position.x += rx * loop_ctx.deltasecs;
-        draw_obj.position.y += ry * loop_ctx.deltasecs;
-        draw_obj.position.z += rz * loop_ctx.deltasecs;
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        if let Some(rb_handle) = loop_ctx.go.rigid_body_handle {
+            let mut impulse = vector![0.0,0.0];
+            let mut moved = false;
+            if loop_ctx.keys_held.contains(&Keycode::RIGHT) { impulse.x += self.accel ; moved = true;}
+            if loop_ctx.keys_held.contains(&Keycode::LEFT) { impulse.x -= self.accel ; moved = true;}
+            if loop_ctx.go.grounded && loop_ctx.keys_held.contains(&Keycode::SPACE) { impulse.y = self.accel * 2.0; loop_ctx.go.grounded = false;}
+            loop_ctx.rigid_body_set[rb_handle].apply_impulse(impulse, true);
+            let mut linvel = *loop_ctx.rigid_body_set[rb_handle].linvel();
+            if loop_ctx.go.grounded && !moved {
+                linvel.x *= 0.95;
+            }
+            linvel.x = f32::max(f32::min(linvel.x, self.max_speed), -self.max_speed);
+            loop_ctx.rigid_body_set[rb_handle].set_linvel(linvel, true);
+        }
+        (vec![], vec![])
     }
-    (vec![], vec![])
 }
 
-pub fn camera_tracking(
-    loop_ctx: &mut LoopContext<BehaviorDataContainerEnum>
-) -> (Vec<GameObjectID>, Vec<GameObject<BehaviorDataContainerEnum>>) {
-    if let Some(BehaviorDataContainerEnum::CameraTrackingData(camera_tracking_data)) = loop_ctx.go.behaviors_data.get(&BehaviorData::Behaviors(Behaviors::BCameraTracking)) {
-        loop_ctx.camera.view_pos.x = loop_ctx.go.position.x + camera_tracking_data.x_off;
-        loop_ctx.camera.view_pos.y = loop_ctx.go.position.y + camera_tracking_data.y_off;
-        loop_ctx.camera.view_pos.z = loop_ctx.go.position.z + camera_tracking_data.z_off;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Debuggin;
+impl Behavior for Debuggin {
+    fn name(&self) -> &'static str { "Debuggin" }
+
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        let (mut rx, mut ry, rz) = (0.0_f32, 0.0_f32, 0.0_f32);
+        if loop_ctx.keys_held.contains(&Keycode::I) { ry += 1.0; }
+        if loop_ctx.keys_held.contains(&Keycode::K) { ry -= 1.0; }
+        if loop_ctx.keys_held.contains(&Keycode::L) { rx += 1.0; }
+        if loop_ctx.keys_held.contains(&Keycode::J) { rx -= 1.0; }
+        if let Some(draw_obj) = &mut loop_ctx.go.drawable_object {
+            if loop_ctx.keys_held.contains(&Keycode::SPACE) { println!("{:?}", draw_obj.position) }
+            draw_obj.position.x += rx * loop_ctx.deltasecs;
+            draw_obj.position.y += ry * loop_ctx.deltasecs;
+            draw_obj.position.z += rz * loop_ctx.deltasecs;
+        }
+        (vec![], vec![])
     }
-    (vec![], vec![])
 }
-#[derive(Debug)]
 
-pub struct CameraTrackingData{
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CameraTracking {
     pub x_off: f32,
     pub y_off: f32,
     pub z_off: f32,
 }
-impl BehaviorDataContainer for CameraTrackingData{}
+impl Behavior for CameraTracking {
+    fn name(&self) -> &'static str { "CameraTracking" }
 
-pub fn spawn_ball (
-    loop_ctx: &mut LoopContext<BehaviorDataContainerEnum>
-) -> (Vec<GameObjectID>, Vec<GameObject<BehaviorDataContainerEnum>>) {
-    let mut objs_to_add = vec![];
-    if loop_ctx.keys_held.contains(&Keycode::LCTRL) {
-        if let Some(BehaviorDataContainerEnum::SpawnBallData(spawn_ball_data)) = loop_ctx.go.behaviors_data.get_mut(&BehaviorData::Behaviors(Behaviors::BSpawnBall)) {
-            if spawn_ball_data.last_use.elapsed() >= spawn_ball_data.cooldown_length {
-                let posxyz = loop_ctx.go.position;
-                let ball_body_handler = loop_ctx.rigid_body_set.insert(
-                    RigidBodyBuilder::dynamic().translation(vector![posxyz.x, posxyz.y]).build()
-                );
-                loop_ctx.collider_set.insert_with_parent(
-                    ColliderBuilder::ball(1.0).active_events(ActiveEvents::COLLISION_EVENTS).build(),
-                    ball_body_handler,
-                    loop_ctx.rigid_body_set
-                );
-                let new_ball_obj = make_go_rb(
-                    vec::Vec3::zero(), 
-                    vec::Vec3::zero(),
-                    vec::Vec3::one(),
-                    vec::Vec3::zero(),
-                    vec::Vec3::zero(),
-                    vec::Vec3::one(),
-                    loop_ctx.model_map["ball"],
-                    ball_body_handler
-                    ).add_behavior(Behaviors::BAttractionTo)
-                    .add_behavior_data(
-                        Behaviors::BAttractionTo, 
-                        BehaviorDataContainerEnum::AttractionToData(AttractionToData{
-                            target: loop_ctx.go.id,
-                            force: 1.0
-                        })
-                    );
-                objs_to_add.push(new_ball_obj);
-                spawn_ball_data.last_use = Instant::now();
-            }
-        }
+    fn priority(&self) -> i32 { PRIORITY_LATE }
+
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        loop_ctx.camera.view_pos.x = loop_ctx.go.position.x + self.x_off;
+        loop_ctx.camera.view_pos.y = loop_ctx.go.position.y + self.y_off;
+        loop_ctx.camera.view_pos.z = loop_ctx.go.position.z + self.z_off;
+        (vec![], vec![])
     }
-    
-    (vec![], objs_to_add)
 }
 
-#[derive(Debug)]
-pub struct SpawnBallData{
+fn long_ago() -> Instant {
+    serde_instant::before_now(Duration::from_secs(3600))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpawnBall {
+    #[serde(with = "serde_instant", default = "long_ago")]
     pub last_use: Instant,
     pub cooldown_length: Duration,
 }
-impl BehaviorDataContainer for SpawnBallData{}
+impl Behavior for SpawnBall {
+    fn name(&self) -> &'static str { "SpawnBall" }
 
-pub fn attraction_to (
-    loop_ctx: &mut LoopContext<BehaviorDataContainerEnum>
-) -> (Vec<GameObjectID>, Vec<GameObject<BehaviorDataContainerEnum>>) {
-    if let Some(BehaviorDataContainerEnum::AttractionToData(attraction_to_data)) = loop_ctx.go.behaviors_data.get_mut(&BehaviorData::Behaviors(Behaviors::BAttractionTo)) {
-        if let Some(rb_handle) = loop_ctx.go.rigid_body_handle {
-            if let Some(tar_rb_handle) = loop_ctx.game_obj_store.0.get(&attraction_to_data.target).unwrap().borrow().rigid_body_handle {
-                let a = loop_ctx.rigid_body_set[rb_handle].translation().clone();
-                let b = loop_ctx.rigid_body_set[tar_rb_handle].translation().clone();
-                let c = (b - a).normalize() * attraction_to_data.force;
-                loop_ctx.rigid_body_set[rb_handle].apply_impulse(c, true);
-            }
+    fn stage(&self) -> Stage { Stage::PrePhysics }
+
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        let mut objs_to_add = vec![];
+        if loop_ctx.keys_held.contains(&Keycode::LCTRL) && self.last_use.elapsed() >= self.cooldown_length {
+            let posxyz = loop_ctx.go.position;
+            let ball_body_handler = loop_ctx.rigid_body_set.insert(
+                RigidBodyBuilder::dynamic().translation(vector![posxyz.x, posxyz.y]).build()
+            );
+            loop_ctx.collider_set.insert_with_parent(
+                ColliderBuilder::ball(1.0).active_events(ActiveEvents::COLLISION_EVENTS).build(),
+                ball_body_handler,
+                loop_ctx.rigid_body_set
+            );
+            let new_ball_obj = make_go_rb(
+                vec::Vec3::zero(),
+                vec::Vec3::zero(),
+                vec::Vec3::one(),
+                vec::Vec3::zero(),
+                vec::Vec3::zero(),
+                vec::Vec3::one(),
+                loop_ctx.model_map["ball"],
+                ball_body_handler
+                ).add_behavior(AttractionTo{
+                    target: loop_ctx.go.id,
+                    force: 1.0
+                });
+            objs_to_add.push(new_ball_obj);
+            self.last_use = Instant::now();
         }
+        (vec![], objs_to_add)
     }
-    
-    (vec![], vec![])
 }
 
-#[derive(Debug)]
-pub struct AttractionToData{
+/* pulls the object towards target. Once the target is despawned the id goes stale
+and the behavior stays idle, see GameObjectID for the policy */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttractionTo {
     pub target: GameObjectID,
     pub force: f32
 }
-impl BehaviorDataContainer for AttractionToData{}
+impl Behavior for AttractionTo {
+    fn name(&self) -> &'static str { "AttractionTo" }
+
+    fn stage(&self) -> Stage { Stage::PrePhysics }
 
-#[derive(Debug)]
-pub enum BehaviorDataContainerEnum {
-    ArrowControlData(ArrowControlData),
-    CameraTrackingData(CameraTrackingData),
-    SpawnBallData(SpawnBallData),
-    AttractionToData(AttractionToData),
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        let tar_rb_handle = loop_ctx.game_obj_store.rigid_bodies.get(&self.target).copied();
+        if let (Some(rb_handle), Some(tar_rb_handle)) = (loop_ctx.go.rigid_body_handle, tar_rb_handle) {
+            if let Some(b) = loop_ctx.rigid_body_set.get(tar_rb_handle).map(|rb| *rb.translation()) {
+                let a = *loop_ctx.rigid_body_set[rb_handle].translation();
+                let c = (b - a).normalize() * self.force;
+                loop_ctx.rigid_body_set[rb_handle].apply_impulse(c, true);
+            }
+        }
+        (vec![], vec![])
+    }
 }
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
-pub enum CollisionBehaviors {
-    CHandleFloorCollision,
+fn default_max_slope() -> f32 { 45.0 }
+
+/* Keeps GameObject::grounded in sync with what the object is standing on.
+A contact counts as ground when its normal points down from this object within
+max_slope degrees of straight down, and with floor_only set the other body must also be
+in the floor set. grounded is on while any contact counts as ground and off once the last
+support is left, so walking off a ledge clears it. Something that stops counting, e.g. a box that
+got pushed aside, is dropped on the next Stay. */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroundDetection {
+    #[serde(default = "default_max_slope")]
+    pub max_slope: f32,
+    #[serde(default)]
+    pub floor_only: bool,
+    #[serde(default)]
+    supports: Vec<GameObjectID>,
 }
+impl GroundDetection {
+    pub fn new(max_slope: f32, floor_only: bool) -> Self {
+        Self { max_slope, floor_only, supports: vec![] }
+    }
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
+    fn is_ground(&self, loop_ctx: &LoopContext, info: &CollisionInfo) -> bool {
+        if self.floor_only && !info.other_rigid_body.is_some_and(|rb_handle| loop_ctx.floor_set.contains(&rb_handle)) {
+            return false;
+        }
+        !info.contacts.is_empty() && -info.normal.y >= self.max_slope.to_radians().cos()
+    }
 
-pub enum CollisionData {
-    CollisionBehaviors(CollisionBehaviors),
+    fn track(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) {
+        let known = self.supports.contains(&info.other);
+        if self.is_ground(loop_ctx, info) {
+            if !known {
+                self.supports.push(info.other);
+            }
+            /* also on a Stay, behaviors like ArrowControl clear it when they jump */
+            loop_ctx.go.grounded = true;
+        } else if known {
+            self.leave(loop_ctx, info.other);
+        }
+    }
+
+    fn leave(&mut self, loop_ctx: &mut LoopContext, other: GameObjectID) {
+        self.supports.retain(|id| *id != other);
+        if self.supports.is_empty() {
+            loop_ctx.go.grounded = false;
+        }
+    }
 }
+impl Behavior for GroundDetection {
+    fn name(&self) -> &'static str { "GroundDetection" }
 
-pub trait CollisionDataContainer{}
+    fn on_spawn(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        loop_ctx.go.grounded = !self.supports.is_empty();
+        (vec![], vec![])
+    }
 
-pub fn apply_collision_behaviors<T>(
-    loop_ctx: &mut LoopContext<T>,
-    other: &RefCell<GameObject<T>>
-) -> (Vec<GameObjectID>, Vec<GameObject<T>>) {
-    let mut collision_behaviors_copy = vec![];
-    for b in loop_ctx.go.collision_behaviors.iter() {
-        collision_behaviors_copy.push(*b);
+    fn on_collision_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.track(loop_ctx, info);
+        (vec![], vec![])
     }
-    let mut objs_to_remove = vec![];
-    let mut objs_to_add = vec![];
-    for c in collision_behaviors_copy {
-        match c {
-            CollisionBehaviors::CHandleFloorCollision => {
-                let (mut to_remove, mut to_add) = handle_floor_behaviors(loop_ctx, other);
-                objs_to_remove.append(&mut to_remove);
-                objs_to_add.append(&mut to_add);
-            },
+
+    fn on_collision_stay(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.track(loop_ctx, info);
+        (vec![], vec![])
+    }
+
+    fn on_collision_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        if self.supports.contains(&info.other) {
+            self.leave(loop_ctx, info.other);
         }
+        (vec![], vec![])
     }
-    (objs_to_remove, objs_to_add)
 }
 
-pub fn handle_floor_behaviors<T>(
-    loop_ctx: &mut LoopContext<T>, 
-    other: &RefCell<GameObject<T>>, 
-) -> (Vec<GameObjectID>, Vec<GameObject<T>>) {
-    let other_go = other.borrow_mut();
-    if let Some(other_rb_handle) = other_go.rigid_body_handle {
-        if loop_ctx.floor_set.contains(&other_rb_handle) && (loop_ctx.go.position.y - 0.2) >= loop_ctx.rigid_body_set[other_rb_handle].translation().y {
-            loop_ctx.go.grounded = true;
+/* Trigger behaviors go on the object owning the sensor collider and fire when something
+enters it, or leaves it with on_exit set. `only` restricts them to one object,
+e.g. only: Some("@player") in a scene file. */
+fn trigger_fires(only: Option<GameObjectID>, on_exit: bool, info: &CollisionInfo) -> bool {
+    info.is_sensor
+        && on_exit == (info.phase == CollisionPhase::Exit)
+        && only.is_none_or(|id| id == info.other)
+}
+
+/* pickups: the sensor's object removes itself */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TriggerDespawnSelf {
+    #[serde(default)]
+    pub only: Option<GameObjectID>,
+    #[serde(default)]
+    pub on_exit: bool,
+}
+impl TriggerDespawnSelf {
+    fn fire(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        if trigger_fires(self.only, self.on_exit, info) {
+            return (vec![loop_ctx.go.id], vec![]);
+        }
+        (vec![], vec![])
+    }
+}
+impl Behavior for TriggerDespawnSelf {
+    fn name(&self) -> &'static str { "TriggerDespawnSelf" }
+
+    fn on_trigger_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+
+    fn on_trigger_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+}
+
+/* kill zones: whatever enters is removed */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TriggerDespawnOther {
+    #[serde(default)]
+    pub only: Option<GameObjectID>,
+    #[serde(default)]
+    pub on_exit: bool,
+}
+impl TriggerDespawnOther {
+    fn fire(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        if trigger_fires(self.only, self.on_exit, info) {
+            return (vec![info.other], vec![]);
         }
+        (vec![], vec![])
+    }
+}
+impl Behavior for TriggerDespawnOther {
+    fn name(&self) -> &'static str { "TriggerDespawnOther" }
+
+    fn on_trigger_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+
+    fn on_trigger_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
     }
-    (vec![], vec![])
-}
\ No newline at end of file
+}
+
+/* level exits: switches to `scene` at the end of the frame */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TriggerLoadScene {
+    pub scene: String,
+    #[serde(default)]
+    pub only: Option<GameObjectID>,
+    #[serde(default)]
+    pub on_exit: bool,
+}
+impl TriggerLoadScene {
+    fn fire(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        if trigger_fires(self.only, self.on_exit, info) {
+            *loop_ctx.next_scene = Some(self.scene.clone());
+        }
+        (vec![], vec![])
+    }
+}
+impl Behavior for TriggerLoadScene {
+    fn name(&self) -> &'static str { "TriggerLoadScene" }
+
+    fn on_trigger_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+
+    fn on_trigger_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+}
+
+/* jump pads and wind zones: gives whatever enters an impulse */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TriggerApplyForce {
+    pub x: f32,
+    pub y: f32,
+    #[serde(default)]
+    pub only: Option<GameObjectID>,
+    #[serde(default)]
+    pub on_exit: bool,
+}
+impl TriggerApplyForce {
+    fn fire(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        if trigger_fires(self.only, self.on_exit, info) {
+            if let Some(rb) = info.other_rigid_body.and_then(|rb_handle| loop_ctx.rigid_body_set.get_mut(rb_handle)) {
+                rb.apply_impulse(vector![self.x, self.y], true);
+            }
+        }
+        (vec![], vec![])
+    }
+}
+impl Behavior for TriggerApplyForce {
+    fn name(&self) -> &'static str { "TriggerApplyForce" }
+
+    fn on_trigger_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+
+    fn on_trigger_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.fire(loop_ctx, info)
+    }
+}
+
+/* despawn effects: fades the object's model out over `seconds` by lowering its opacity,
+then removes it. Attach it when the object should start going away */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FadeOut {
+    pub seconds: f32,
+    #[serde(default)]
+    pub elapsed: f32,
+}
+impl FadeOut {
+    pub fn new(seconds: f32) -> Self {
+        Self { seconds, elapsed: 0.0 }
+    }
+}
+impl Behavior for FadeOut {
+    fn name(&self) -> &'static str { "FadeOut" }
+
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        self.elapsed += loop_ctx.deltasecs;
+        if self.elapsed >= self.seconds {
+            return (vec![loop_ctx.go.id], vec![]);
+        }
+        if let Some(draw_obj) = &mut loop_ctx.go.drawable_object {
+            draw_obj.material_override.opacity = Some(1.0 - self.elapsed / self.seconds);
+        }
+        (vec![], vec![])
+    }
+}
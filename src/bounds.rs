@@ -0,0 +1,231 @@
+use ultraviolet::{mat, vec};
+
+/* Bounding volumes for culling. Every mesh of a model gets a box and a sphere around its
+vertices when the model is loaded, and the model one around all of its meshes
+(MeshData::bounds, Context::mesh_bounds). Each frame render_frame moves the model's bounds
+into the world with the object's transform and skips objects whose bounds are entirely
+outside the camera's frustum, shadow casters are tested against the shadow light's instead.
+The counts end up in Context::render_stats. */
+
+/* axis aligned bounding box */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: vec::Vec3,
+    pub max: vec::Vec3,
+}
+impl Aabb {
+    /* a box of no size at the origin when there are no points */
+    pub fn from_points(points: impl IntoIterator<Item = vec::Vec3>) -> Self {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(first) => first,
+            None => return Self { min: vec::Vec3::zero(), max: vec::Vec3::zero() },
+        };
+        points.fold(Self { min: first, max: first }, |aabb, point| Self {
+            min: aabb.min.min_by_component(point),
+            max: aabb.max.max_by_component(point),
+        })
+    }
+    pub fn center(&self) -> vec::Vec3 {
+        (self.min + self.max) * 0.5
+    }
+    /* half the size along each axis */
+    pub fn extents(&self) -> vec::Vec3 {
+        (self.max - self.min) * 0.5
+    }
+    pub fn union(&self, other: &Self) -> Self {
+        Self { min: self.min.min_by_component(other.min), max: self.max.max_by_component(other.max) }
+    }
+    /* the box around this box after the transform, which may be bigger than needed once rotated */
+    pub fn transformed(&self, matrix: &mat::Mat4) -> Self {
+        let center = matrix.transform_point3(self.center());
+        let extents = self.extents();
+        /* each axis of the new box spans the absolute values of the matrix times the old extents */
+        let mut new_extents = vec::Vec3::zero();
+        for (col, extent) in [extents.x, extents.y, extents.z].into_iter().enumerate() {
+            let axis = matrix.cols[col].xyz();
+            new_extents += vec::Vec3::new(axis.x.abs(), axis.y.abs(), axis.z.abs()) * extent;
+        }
+        Self { min: center - new_extents, max: center + new_extents }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: vec::Vec3,
+    pub radius: f32,
+}
+impl BoundingSphere {
+    /* around the points, centered on their box */
+    pub fn from_points(points: impl IntoIterator<Item = vec::Vec3> + Clone) -> Self {
+        let center = Aabb::from_points(points.clone()).center();
+        let radius = points.into_iter().map(|point| (point - center).mag()).fold(0.0, f32::max);
+        Self { center, radius }
+    }
+    /* scaled by the largest scale of the transform */
+    pub fn transformed(&self, matrix: &mat::Mat4) -> Self {
+        let scale = (0..3).map(|col| matrix.cols[col].xyz().mag()).fold(0.0, f32::max);
+        Self { center: matrix.transform_point3(self.center), radius: self.radius * scale }
+    }
+}
+
+/* both volumes of the same points, the sphere is quicker to test and the box tighter */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+}
+impl Bounds {
+    pub fn from_points(points: impl IntoIterator<Item = vec::Vec3> + Clone) -> Self {
+        Self { aabb: Aabb::from_points(points.clone()), sphere: BoundingSphere::from_points(points) }
+    }
+    /* vertices as they are in MeshData::point_data or a tobj mesh's positions */
+    pub fn from_positions(positions: &[f32], stride: usize) -> Self {
+        Self::from_points(positions.chunks_exact(stride).map(|v| vec::Vec3::new(v[0], v[1], v[2])))
+    }
+    /* around both, the sphere centered on the new box */
+    pub fn union(&self, other: &Self) -> Self {
+        let aabb = self.aabb.union(&other.aabb);
+        let center = aabb.center();
+        let radius = [self.sphere, other.sphere].iter()
+            .map(|sphere| (sphere.center - center).mag() + sphere.radius)
+            .fold(0.0, f32::max);
+        Self { aabb, sphere: BoundingSphere { center, radius } }
+    }
+    pub fn transformed(&self, matrix: &mat::Mat4) -> Self {
+        Self { aabb: self.aabb.transformed(matrix), sphere: self.sphere.transformed(matrix) }
+    }
+}
+
+/* the six planes of what a view projection matrix can see, normals pointing inwards */
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    /* xyz the unit normal, w the distance, a point p is inside when dot(xyz, p) + w >= 0 */
+    pub planes: [vec::Vec4; 6],
+}
+impl Frustum {
+    /* for GL's clip space, where -w <= x, y, z <= w is visible */
+    pub fn from_view_projection(matrix: &mat::Mat4) -> Self {
+        let row = |i: usize| vec::Vec4::new(matrix.cols[0][i], matrix.cols[1][i], matrix.cols[2][i], matrix.cols[3][i]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let planes = [w + x, w - x, w + y, w - y, w + z, w - z].map(|plane| {
+            let length = plane.xyz().mag();
+            if length > 0.0 { plane / length } else { plane }
+        });
+        Self { planes }
+    }
+
+    fn distance(plane: &vec::Vec4, point: vec::Vec3) -> f32 {
+        plane.xyz().dot(point) + plane.w
+    }
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|plane| Self::distance(plane, sphere.center) >= -sphere.radius)
+    }
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            /* the corner furthest along the plane's normal */
+            let corner = vec::Vec3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            Self::distance(plane, corner) >= 0.0
+        })
+    }
+    /* whether anything inside the bounds might be visible */
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        self.intersects_sphere(&bounds.sphere) && self.intersects_aabb(&bounds.aabb)
+    }
+}
+
+/* what render_frame did with the objects of the last frame */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /* objects with a model */
+    pub objects: usize,
+    pub drawn: usize,
+    pub culled: usize,
+    /* batches the drawn objects were put in, see batch_calls */
+    pub batches: usize,
+    /* instanced draws they took, one per mesh of each batch and of each transparent object */
+    pub draws: usize,
+    pub shadow_casters_drawn: usize,
+    pub shadow_casters_culled: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+    use ultraviolet::projection;
+    use super::*;
+
+    fn bounds(min: [f32; 3], max: [f32; 3]) -> Bounds {
+        let (min, max) = (vec::Vec3::from(min), vec::Vec3::from(max));
+        Bounds::from_points([min, max, vec::Vec3::new(min.x, max.y, min.z), vec::Vec3::new(max.x, min.y, max.z)])
+    }
+
+    fn assert_close(a: vec::Vec3, b: vec::Vec3) {
+        assert!((a - b).mag() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    /* at the origin looking down -z, 90 degrees up and down and across: at z = -10 it sees 10 each way */
+    fn frustum() -> Frustum {
+        let view = mat::Mat4::look_at(vec::Vec3::zero(), vec::Vec3::new(0.0, 0.0, -1.0), vec::Vec3::unit_y());
+        let projection = projection::perspective_gl(FRAC_PI_2, 1.0, 0.1, 100.0);
+        Frustum::from_view_projection(&(projection * view))
+    }
+
+    #[test]
+    fn frustum_keeps_boxes_in_view() {
+        let frustum = frustum();
+        assert!(frustum.intersects(&bounds([-1.0, -1.0, -11.0], [1.0, 1.0, -9.0])));
+        assert!(frustum.intersects(&bounds([8.0, 8.0, -30.0], [9.0, 9.0, -29.0])));
+    }
+
+    #[test]
+    fn frustum_culls_boxes_out_of_view() {
+        let frustum = frustum();
+        /* to the side, above, behind the camera and past the far plane */
+        assert!(!frustum.intersects(&bounds([20.0, -1.0, -11.0], [22.0, 1.0, -9.0])));
+        assert!(!frustum.intersects(&bounds([-1.0, 20.0, -11.0], [1.0, 22.0, -9.0])));
+        assert!(!frustum.intersects(&bounds([-1.0, -1.0, 5.0], [1.0, 1.0, 7.0])));
+        assert!(!frustum.intersects(&bounds([-1.0, -1.0, -150.0], [1.0, 1.0, -120.0])));
+    }
+
+    #[test]
+    fn frustum_keeps_boxes_across_a_plane() {
+        let frustum = frustum();
+        /* over the right edge, and through the near plane */
+        assert!(frustum.intersects(&bounds([9.0, -1.0, -11.0], [11.0, 1.0, -9.0])));
+        assert!(frustum.intersects(&bounds([-1.0, -1.0, -1.0], [1.0, 1.0, 1.0])));
+        /* through the far plane */
+        assert!(frustum.intersects(&bounds([-1.0, -1.0, -101.0], [1.0, 1.0, -99.0])));
+    }
+
+    #[test]
+    fn transformed_box_covers_the_rotated_and_scaled_box() {
+        let aabb = Aabb { min: vec::Vec3::new(-1.0, -2.0, -3.0), max: vec::Vec3::new(1.0, 2.0, 3.0) };
+        /* scaled by 2, turned a quarter around z so x and y swap, then moved */
+        let matrix = mat::Mat4::from_translation(vec::Vec3::new(5.0, 0.0, 0.0))
+            * mat::Mat4::from_rotation_z(FRAC_PI_2)
+            * mat::Mat4::from_scale(2.0);
+        let world = aabb.transformed(&matrix);
+        assert_close(world.min, vec::Vec3::new(1.0, -2.0, -6.0));
+        assert_close(world.max, vec::Vec3::new(9.0, 2.0, 6.0));
+
+        /* an eighth of a turn is where the box around it grows the most */
+        let unit = Aabb { min: vec::Vec3::broadcast(-1.0), max: vec::Vec3::one() };
+        let world = unit.transformed(&mat::Mat4::from_rotation_z(FRAC_PI_2 / 2.0));
+        let diagonal = 2.0_f32.sqrt();
+        assert_close(world.min, vec::Vec3::new(-diagonal, -diagonal, -1.0));
+        assert_close(world.max, vec::Vec3::new(diagonal, diagonal, 1.0));
+    }
+
+    #[test]
+    fn sphere_scales_with_the_largest_axis() {
+        let sphere = BoundingSphere { center: vec::Vec3::unit_x(), radius: 1.5 };
+        let world = sphere.transformed(&(mat::Mat4::from_translation(vec::Vec3::unit_y()) * mat::Mat4::from_nonuniform_scale(vec::Vec3::new(1.0, 3.0, 2.0))));
+        assert_close(world.center, vec::Vec3::new(1.0, 1.0, 0.0));
+        assert!((world.radius - 4.5).abs() < 1e-5);
+    }
+}
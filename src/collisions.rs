@@ -0,0 +1,234 @@
+use rapier2d::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::ecs::*;
+
+/* rapier only reports when two colliders start and stop touching. The CollisionTracker
+remembers which pairs are touching and turns the events of each physics step into
+per object callbacks: Enter on the step contact starts, Stay on every step after that
+while it lasts and Exit once it ends, even if the other object was despawned in between.
+Contact points, normal and solver impulses come from the narrow phase's contact manifolds.
+Pairs where either collider is a sensor are triggers: they only get Enter and Exit,
+never carry contacts and are routed to the trigger hooks instead of the collision ones. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionPhase {
+    Enter,
+    Stay,
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ContactPoint {
+    /* world space position on this object's collider */
+    pub point: Point<Real>,
+    /* how far the colliders overlap here, 0 when they are just touching */
+    pub depth: Real,
+    /* size of the impulse the solver applied along the normal at this point during the last step,
+    never negative and the same on both objects since it pushes them apart equally */
+    pub impulse: Real,
+}
+
+#[derive(Debug, Clone)]
+pub struct CollisionInfo {
+    pub phase: CollisionPhase,
+    pub other: GameObjectID,
+    pub other_rigid_body: Option<RigidBodyHandle>,
+    pub collider: ColliderHandle,
+    pub other_collider: ColliderHandle,
+    /* whether this object's / the other object's collider in the pair is a sensor */
+    pub is_sensor: bool,
+    pub other_is_sensor: bool,
+    /* world space unit normal pointing from this object towards the other,
+    zero when there are no contact points (always the case on Exit) */
+    pub normal: Vector<Real>,
+    pub contacts: Vec<ContactPoint>,
+}
+impl CollisionInfo {
+    pub fn is_trigger(&self) -> bool {
+        self.is_sensor || self.other_is_sensor
+    }
+    pub fn depth(&self) -> Real {
+        self.contacts.iter().fold(0.0, |depth, contact| depth.max(contact.depth))
+    }
+    pub fn impulse(&self) -> Real {
+        self.contacts.iter().map(|contact| contact.impulse).sum()
+    }
+}
+
+/* a pair of touching colliders and the objects they belonged to when contact started */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ActivePair {
+    collider1: ColliderHandle,
+    collider2: ColliderHandle,
+    object1: GameObjectID,
+    object2: GameObjectID,
+    rigid_body1: RigidBodyHandle,
+    rigid_body2: RigidBodyHandle,
+    #[serde(default)]
+    sensor1: bool,
+    #[serde(default)]
+    sensor2: bool,
+}
+impl ActivePair {
+    fn is_trigger(&self) -> bool {
+        self.sensor1 || self.sensor2
+    }
+
+    fn is(&self, collider1: ColliderHandle, collider2: ColliderHandle) -> bool {
+        (self.collider1 == collider1 && self.collider2 == collider2)
+            || (self.collider1 == collider2 && self.collider2 == collider1)
+    }
+}
+
+/* lives in PhysicsState so snapshots and scene unloads keep it in step with the narrow phase */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CollisionTracker {
+    /* kept in the order contact started so callbacks run in a stable order */
+    active: Vec<ActivePair>,
+}
+impl CollisionTracker {
+    pub fn new() -> Self {
+        Self { active: vec![] }
+    }
+
+    /* takes the collision events of one physics step and returns the callbacks to run,
+    as (object receiving it, collision), Enters and Exits in event order followed by Stays */
+    pub fn update(
+        &mut self,
+        events: impl IntoIterator<Item = CollisionEvent>,
+        collider_set: &ColliderSet,
+        narrow_phase: &NarrowPhase,
+        game_obj_store: &GameObjectStore,
+    ) -> Vec<(GameObjectID, CollisionInfo)> {
+        let mut collisions = vec![];
+        let mut entered = vec![];
+        for event in events {
+            match event {
+                CollisionEvent::Started(c1, c2, _) => {
+                    if self.active.iter().any(|pair| pair.is(c1, c2)) {
+                        continue;
+                    }
+                    let (collider1, collider2) = match (collider_set.get(c1), collider_set.get(c2)) {
+                        (Some(collider1), Some(collider2)) => (collider1, collider2),
+                        _ => continue,
+                    };
+                    let (rigid_body1, rigid_body2) = match (collider1.parent(), collider2.parent()) {
+                        (Some(rigid_body1), Some(rigid_body2)) => (rigid_body1, rigid_body2),
+                        _ => continue,
+                    };
+                    /* colliders on bodies without an object, or on the same object, are ignored */
+                    let (object1, object2) = match (game_obj_store.lookup_by_rb_handle(&rigid_body1), game_obj_store.lookup_by_rb_handle(&rigid_body2)) {
+                        (Some(object1), Some(object2)) if object1 != object2 => (object1, object2),
+                        _ => continue,
+                    };
+                    let pair = ActivePair {
+                        collider1: c1,
+                        collider2: c2,
+                        object1,
+                        object2,
+                        rigid_body1,
+                        rigid_body2,
+                        sensor1: collider1.is_sensor(),
+                        sensor2: collider2.is_sensor(),
+                    };
+                    self.active.push(pair);
+                    entered.push((c1, c2));
+                    push_pair(&mut collisions, &pair, CollisionPhase::Enter, collider_set, narrow_phase);
+                },
+                CollisionEvent::Stopped(c1, c2, _) => {
+                    if let Some(i) = self.active.iter().position(|pair| pair.is(c1, c2)) {
+                        let pair = self.active.remove(i);
+                        push_pair(&mut collisions, &pair, CollisionPhase::Exit, collider_set, narrow_phase);
+                    }
+                },
+            }
+        }
+        for pair in &self.active {
+            if !pair.is_trigger() && !entered.contains(&(pair.collider1, pair.collider2)) {
+                push_pair(&mut collisions, pair, CollisionPhase::Stay, collider_set, narrow_phase);
+            }
+        }
+        collisions
+    }
+
+    pub fn clear(&mut self) {
+        self.active.clear();
+    }
+}
+
+fn push_pair(
+    collisions: &mut Vec<(GameObjectID, CollisionInfo)>,
+    pair: &ActivePair,
+    phase: CollisionPhase,
+    collider_set: &ColliderSet,
+    narrow_phase: &NarrowPhase,
+) {
+    let (normal, contacts1, contacts2) = if phase == CollisionPhase::Exit || pair.is_trigger() {
+        (Vector::zeros(), vec![], vec![])
+    } else {
+        contact_data(pair.collider1, pair.collider2, collider_set, narrow_phase)
+    };
+    collisions.push((pair.object1, CollisionInfo {
+        phase,
+        other: pair.object2,
+        other_rigid_body: Some(pair.rigid_body2),
+        collider: pair.collider1,
+        other_collider: pair.collider2,
+        is_sensor: pair.sensor1,
+        other_is_sensor: pair.sensor2,
+        normal,
+        contacts: contacts1,
+    }));
+    collisions.push((pair.object2, CollisionInfo {
+        phase,
+        other: pair.object1,
+        other_rigid_body: Some(pair.rigid_body1),
+        collider: pair.collider2,
+        other_collider: pair.collider1,
+        is_sensor: pair.sensor2,
+        other_is_sensor: pair.sensor1,
+        normal: -normal,
+        contacts: contacts2,
+    }));
+}
+
+/* normal from collider1 towards collider2 and the contact points as seen from each side */
+fn contact_data(
+    collider1: ColliderHandle,
+    collider2: ColliderHandle,
+    collider_set: &ColliderSet,
+    narrow_phase: &NarrowPhase,
+) -> (Vector<Real>, Vec<ContactPoint>, Vec<ContactPoint>) {
+    let mut normal = Vector::zeros();
+    let mut contacts1 = vec![];
+    let mut contacts2 = vec![];
+    let contact_pair = match narrow_phase.contact_pair(collider1, collider2) {
+        Some(contact_pair) => contact_pair,
+        None => return (normal, contacts1, contacts2),
+    };
+    /* the narrow phase may store the pair the other way around */
+    let flipped = contact_pair.collider1 != collider1;
+    let (position1, position2) = match (collider_set.get(contact_pair.collider1), collider_set.get(contact_pair.collider2)) {
+        (Some(c1), Some(c2)) => (*c1.position(), *c2.position()),
+        _ => return (normal, contacts1, contacts2),
+    };
+    for manifold in &contact_pair.manifolds {
+        if manifold.points.is_empty() {
+            continue;
+        }
+        normal += manifold.data.normal;
+        for point in &manifold.points {
+            let depth = (-point.dist).max(0.0);
+            contacts1.push(ContactPoint { point: position1 * point.local_p1, depth, impulse: point.data.impulse });
+            contacts2.push(ContactPoint { point: position2 * point.local_p2, depth, impulse: point.data.impulse });
+        }
+    }
+    if normal.norm_squared() > 0.0 {
+        normal = normal.normalize();
+    }
+    if flipped {
+        (-normal, contacts2, contacts1)
+    } else {
+        (normal, contacts1, contacts2)
+    }
+}
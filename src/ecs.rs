@@ -0,0 +1,684 @@
+use std::collections::HashMap;
+use std::fmt;
+use rapier2d::prelude::*;
+use serde::{Serialize, Deserialize};
+use ultraviolet::{mat, vec};
+use crate::gllib::*;
+use crate::behaviors::*;
+use crate::lights::*;
+
+/* Object storage. Every object is an id and each kind of data it can have is a component
+kept in its own SparseSet: a dense array of values with their ids, plus a sparse array
+mapping id index to dense position. Adding, removing and looking up a component are O(1) and
+iterating a component walks a packed array in a fixed order (insertion order, with the last
+entry moved into a removed one's place), so frames run the same way every time.
+
+Queries join component sets by id, e.g. store.transforms joined with store.drawables
+for drawing, see join(). GameObject is still how objects are built and handed to behaviors:
+add() splits one into components, checkout()/checkin() hand a frame's copy to behaviors and
+write it back, and remove() puts one back together, so make_go/make_go_rb scenes keep working. */
+
+/* Handle to an object in a GameObjectStore. Slots are reused once their object is removed
+and the slot's generation goes up every time, so an id kept around after its object is gone
+(a stale id) never resolves to whatever moves into the slot later: every lookup with it fails.
+
+Stale targets: behaviors that hold ids of other objects (AttractionTo targets, GroundDetection
+supports, trigger filters, script targets) treat an id that no longer resolves as an object
+that is gone for good. They skip whatever they would have done with it and keep running,
+they never panic and never pick a new target on their own. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct GameObjectID {
+    pub index: u32,
+    pub generation: u32,
+}
+impl GameObjectID {
+    /* id of an object that hasn't been added to a store, never resolves */
+    pub const NONE: Self = Self { index: u32::MAX, generation: 0 };
+
+    /* packed into one number for places like scripts that can't hold the struct */
+    pub fn to_bits(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+    pub fn from_bits(bits: u64) -> Self {
+        Self { index: bits as u32, generation: (bits >> 32) as u32 }
+    }
+}
+impl fmt::Display for GameObjectID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SparseSet<T> {
+    /* entity index -> position in dense/data, NO_ENTRY when the entity has no value */
+    sparse: Vec<u32>,
+    dense: Vec<GameObjectID>,
+    data: Vec<T>,
+}
+const NO_ENTRY: u32 = u32::MAX;
+impl<T> SparseSet<T> {
+    pub fn new() -> Self {
+        Self { sparse: vec![], dense: vec![], data: vec![] }
+    }
+    fn position(&self, id: &GameObjectID) -> Option<usize> {
+        let pos = *self.sparse.get(id.index as usize)?;
+        if pos != NO_ENTRY && self.dense[pos as usize] == *id {
+            Some(pos as usize)
+        } else {
+            None
+        }
+    }
+    /* sets the value for id, returning the one it replaces */
+    pub fn insert(&mut self, id: GameObjectID, value: T) -> Option<T> {
+        if let Some(pos) = self.position(&id) {
+            return Some(std::mem::replace(&mut self.data[pos], value));
+        }
+        let index = id.index as usize;
+        if index >= self.sparse.len() {
+            self.sparse.resize(index + 1, NO_ENTRY);
+        }
+        /* a stale id's value for the same slot is dropped */
+        let old = match self.sparse[index] {
+            NO_ENTRY => None,
+            pos => {
+                let stale = self.dense[pos as usize];
+                self.remove(&stale)
+            },
+        };
+        self.sparse[index] = self.dense.len() as u32;
+        self.dense.push(id);
+        self.data.push(value);
+        old
+    }
+    pub fn remove(&mut self, id: &GameObjectID) -> Option<T> {
+        let pos = self.position(id)?;
+        self.sparse[id.index as usize] = NO_ENTRY;
+        self.dense.swap_remove(pos);
+        let value = self.data.swap_remove(pos);
+        if let Some(moved) = self.dense.get(pos) {
+            self.sparse[moved.index as usize] = pos as u32;
+        }
+        Some(value)
+    }
+    pub fn get(&self, id: &GameObjectID) -> Option<&T> {
+        self.position(id).map(|pos| &self.data[pos])
+    }
+    pub fn get_mut(&mut self, id: &GameObjectID) -> Option<&mut T> {
+        self.position(id).map(move |pos| &mut self.data[pos])
+    }
+    pub fn contains(&self, id: &GameObjectID) -> bool {
+        self.position(id).is_some()
+    }
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+    pub fn ids(&self) -> &[GameObjectID] {
+        &self.dense
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (GameObjectID, &T)> {
+        self.dense.iter().copied().zip(self.data.iter())
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (GameObjectID, &mut T)> {
+        self.dense.iter().copied().zip(self.data.iter_mut())
+    }
+    pub fn clear(&mut self) {
+        self.sparse.clear();
+        self.dense.clear();
+        self.data.clear();
+    }
+}
+impl<T> Default for SparseSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* objects that have both components, in the order of `a` */
+pub fn join<'a, A, B>(a: &'a SparseSet<A>, b: &'a SparseSet<B>) -> impl Iterator<Item = (GameObjectID, &'a A, &'a B)> {
+    a.iter().filter_map(move |(id, a)| b.get(&id).map(|b| (id, a, b)))
+}
+
+/* local position, rotation and scale plus the world transform the transform pass computes from them */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Transform {
+    pub position: vec::Vec3,
+    pub rotation: vec::Vec3,
+    pub scale: vec::Vec3,
+    #[serde(skip, default = "mat::Mat4::identity")]
+    pub world_matrix: mat::Mat4,
+    #[serde(skip, default = "mat::Mat4::identity")]
+    pub world_rotation: mat::Mat4,
+}
+impl Transform {
+    pub fn local_matrix(&self) -> mat::Mat4 {
+        let pos = mat::Mat4::from_translation(self.position);
+        let [roll, pitch, yaw] = *self.rotation.as_array();
+        let rot = mat::Mat4::from_euler_angles(roll, pitch, yaw);
+        let sca = mat::Mat4::from_nonuniform_scale(self.scale);
+        sca * pos * rot
+    }
+    pub fn local_rotation(&self) -> mat::Mat4 {
+        let [roll, pitch, yaw] = *self.rotation.as_array();
+        mat::Mat4::from_euler_angles(roll, pitch, yaw)
+    }
+}
+
+/* where a rigid body object was after the physics step before the last one, rendering
+interpolates from here to its current transform so motion stays smooth between fixed steps */
+#[derive(Clone, Copy)]
+pub struct PhysicsPose {
+    pub position: vec::Vec3,
+    pub rotation: vec::Vec3,
+}
+impl PhysicsPose {
+    /* the transform `alpha` of the way from this pose to `current` */
+    pub fn lerp(&self, current: &Transform, alpha: f32) -> Transform {
+        let mut transform = current.clone();
+        transform.position = self.position + (current.position - self.position) * alpha;
+        let [x, y, z] = *self.rotation.as_array();
+        let [cx, cy, cz] = *current.rotation.as_array();
+        transform.rotation = vec::Vec3::new(lerp_angle(x, cx, alpha), lerp_angle(y, cy, alpha), lerp_angle(z, cz, alpha));
+        transform
+    }
+}
+
+/* interpolates the short way around, rapier wraps angles at +-pi */
+fn lerp_angle(from: f32, to: f32, alpha: f32) -> f32 {
+    use std::f32::consts::{PI, TAU};
+    let mut diff = (to - from) % TAU;
+    if diff > PI {
+        diff -= TAU;
+    } else if diff < -PI {
+        diff += TAU;
+    }
+    from + diff * alpha
+}
+
+/* only objects that have a parent or children carry one */
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Hierarchy {
+    pub parent: Option<GameObjectID>,
+    pub children: Vec<GameObjectID>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntitySlot {
+    generation: u32,
+    alive: bool,
+}
+
+/* The component sets are public for queries. Objects are created and destroyed through
+add/remove, which keep every set and the rigid body lookup in step, so code outside only
+changes component values and never inserts or removes entries on its own. */
+#[derive(Serialize, Deserialize)]
+pub struct GameObjectStore {
+    slots: Vec<EntitySlot>,
+    /* indices of dead slots, reused before the store grows */
+    free: Vec<u32>,
+    by_rb_handle: HashMap<RigidBodyHandle, GameObjectID>,
+    #[serde(skip)]
+    newly_added: Vec<GameObjectID>,
+    pub transforms: SparseSet<Transform>,
+    pub hierarchy: SparseSet<Hierarchy>,
+    pub drawables: SparseSet<DrawableObject>,
+    pub rigid_bodies: SparseSet<RigidBodyHandle>,
+    pub grounded: SparseSet<bool>,
+    #[serde(default)]
+    pub lights: SparseSet<Light>,
+    /* filled in by sync_from_physics, render only so not saved */
+    #[serde(skip)]
+    pub previous_poses: SparseSet<PhysicsPose>,
+    /* saved separately by snapshots since they need the BehaviorRegistry to be rebuilt */
+    #[serde(skip)]
+    pub behaviors: SparseSet<Vec<Box<dyn Behavior>>>,
+}
+impl Default for GameObjectStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl GameObjectStore {
+    pub fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+            by_rb_handle: HashMap::new(),
+            newly_added: vec![],
+            transforms: SparseSet::new(),
+            hierarchy: SparseSet::new(),
+            drawables: SparseSet::new(),
+            rigid_bodies: SparseSet::new(),
+            grounded: SparseSet::new(),
+            lights: SparseSet::new(),
+            previous_poses: SparseSet::new(),
+            behaviors: SparseSet::new(),
+        }
+    }
+    pub fn add(&mut self, go: GameObject) -> GameObjectID {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(EntitySlot { generation: 0, alive: false });
+                (self.slots.len() - 1) as u32
+            },
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.alive = true;
+        let id = GameObjectID { index, generation: slot.generation };
+        /* on_spawn hooks for these run at the start of the next frame */
+        self.newly_added.push(id);
+        self.write_components(id, go);
+        id
+    }
+    pub fn take_newly_added(&mut self) -> Vec<GameObjectID> {
+        std::mem::take(&mut self.newly_added)
+    }
+    pub fn contains(&self, id: &GameObjectID) -> bool {
+        self.slots.get(id.index as usize).is_some_and(|slot| slot.alive && slot.generation == id.generation)
+    }
+    /* every live object in slot order, which stays the same from run to run */
+    pub fn ids(&self) -> Vec<GameObjectID> {
+        self.slots.iter().enumerate()
+            .filter(|(_, slot)| slot.alive)
+            .map(|(index, slot)| GameObjectID { index: index as u32, generation: slot.generation })
+            .collect()
+    }
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn lookup_by_rb_handle(&self, rb_handle: &RigidBodyHandle) -> Option<GameObjectID> {
+        self.by_rb_handle.get(rb_handle).copied()
+    }
+
+    /* the object as a GameObject for behaviors to work on. Its behaviors are moved out
+    while it is checked out, everything else is copied, so other objects still see its
+    components as they were. checkin writes the changes back. */
+    pub fn checkout(&mut self, id: &GameObjectID) -> Option<GameObject> {
+        if !self.contains(id) {
+            return None;
+        }
+        let mut go = self.read_components(id);
+        go.behaviors = self.behaviors.get_mut(id).map(std::mem::take).unwrap_or_default();
+        Some(go)
+    }
+    /* dropped if the object was removed in the meantime */
+    pub fn checkin(&mut self, go: GameObject) {
+        if self.contains(&go.id) {
+            self.write_components(go.id, go);
+        }
+    }
+
+    /* takes the object out of the store, None if the id is stale.
+    Only this object is removed, its children become roots. main_loop despawns them too
+    by queueing the returned object's children. */
+    pub fn remove(&mut self, id: &GameObjectID) -> Option<GameObject> {
+        if !self.contains(id) {
+            return None;
+        }
+        let mut go = self.read_components(id);
+        go.behaviors = self.behaviors.remove(id).unwrap_or_default();
+        self.transforms.remove(id);
+        self.hierarchy.remove(id);
+        self.drawables.remove(id);
+        self.grounded.remove(id);
+        self.lights.remove(id);
+        self.previous_poses.remove(id);
+        if let Some(rb_handle) = self.rigid_bodies.remove(id) {
+            self.by_rb_handle.remove(&rb_handle);
+        }
+        let slot = &mut self.slots[id.index as usize];
+        slot.alive = false;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+
+        if let Some(parent) = go.parent {
+            self.unlink_child(&parent, id);
+        }
+        for child in &go.children {
+            self.set_hierarchy(child, |hierarchy| hierarchy.parent = None);
+        }
+        Some(go)
+    }
+
+    /* moves child under parent, or makes it a root with None. Its local transform is kept,
+    so it ends up at the same offset from the new parent as it had from the old one */
+    pub fn set_parent(&mut self, child: &GameObjectID, parent: Option<GameObjectID>) -> Result<(), String> {
+        if !self.contains(child) {
+            return Err(format!("Can't reparent missing object {}", child));
+        }
+        if let Some(parent) = parent {
+            if !self.contains(&parent) {
+                return Err(format!("Can't parent {} to missing object {}", child, parent));
+            }
+            /* walking up from the new parent must not reach the child */
+            let mut ancestor = Some(parent);
+            while let Some(id) = ancestor {
+                if id == *child {
+                    return Err(format!("Can't parent {} to its own descendant {}", child, parent));
+                }
+                ancestor = self.parent(&id);
+            }
+        }
+        if let Some(old_parent) = self.parent(child) {
+            self.unlink_child(&old_parent, child);
+        }
+        self.set_hierarchy(child, |hierarchy| hierarchy.parent = parent);
+        if let Some(parent) = parent {
+            self.set_hierarchy(&parent, |hierarchy| hierarchy.children.push(*child));
+        }
+        Ok(())
+    }
+    pub fn parent(&self, id: &GameObjectID) -> Option<GameObjectID> {
+        self.hierarchy.get(id)?.parent
+    }
+    pub fn children(&self, id: &GameObjectID) -> Vec<GameObjectID> {
+        self.hierarchy.get(id).map_or(vec![], |hierarchy| hierarchy.children.clone())
+    }
+
+    /* copies rigid body positions and angles into the transforms of the objects they belong to,
+    keeping what they were before as the pose to interpolate from */
+    pub fn sync_from_physics(&mut self, rigid_body_set: &RigidBodySet) {
+        for (id, rb_handle) in self.rigid_bodies.iter() {
+            if let (Some(transform), Some(rb)) = (self.transforms.get_mut(&id), rigid_body_set.get(*rb_handle)) {
+                self.previous_poses.insert(id, PhysicsPose { position: transform.position, rotation: transform.rotation });
+                transform.position.x = rb.translation().x;
+                transform.position.y = rb.translation().y;
+                transform.rotation.z = rb.rotation().angle();
+            }
+        }
+    }
+
+    /* composes every object's local transform with its parent's, parents before children.
+    Objects with a rigid body are placed in the world by physics and ignore their parent,
+    they are drawn `alpha` of the way from their previous pose to their current one */
+    pub fn update_world_transforms(&mut self, alpha: f32) {
+        let mut stack: Vec<(GameObjectID, mat::Mat4, mat::Mat4)> = self.transforms.ids().iter()
+            .filter(|id| self.parent(id).is_none())
+            .map(|id| (*id, mat::Mat4::identity(), mat::Mat4::identity()))
+            .collect();
+        stack.reverse();
+        while let Some((id, parent_matrix, parent_rotation)) = stack.pop() {
+            let physics_owned = self.rigid_bodies.contains(&id);
+            let previous_pose = self.previous_poses.get(&id).copied();
+            let transform = match self.transforms.get_mut(&id) {
+                Some(transform) => transform,
+                None => continue,
+            };
+            if physics_owned {
+                let drawn = match previous_pose {
+                    Some(pose) => pose.lerp(transform, alpha),
+                    None => transform.clone(),
+                };
+                transform.world_matrix = drawn.local_matrix();
+                transform.world_rotation = drawn.local_rotation();
+            } else {
+                transform.world_matrix = parent_matrix * transform.local_matrix();
+                transform.world_rotation = parent_rotation * transform.local_rotation();
+            }
+            let (world_matrix, world_rotation) = (transform.world_matrix, transform.world_rotation);
+            if let Some(hierarchy) = self.hierarchy.get(&id) {
+                for child in hierarchy.children.iter().rev() {
+                    stack.push((*child, world_matrix, world_rotation));
+                }
+            }
+        }
+    }
+
+    fn read_components(&self, id: &GameObjectID) -> GameObject {
+        let mut go = GameObject::empty();
+        go.id = *id;
+        if let Some(transform) = self.transforms.get(id) {
+            go.position = transform.position;
+            go.rotation = transform.rotation;
+            go.scale = transform.scale;
+            go.world_matrix = transform.world_matrix;
+            go.world_rotation = transform.world_rotation;
+        }
+        if let Some(hierarchy) = self.hierarchy.get(id) {
+            go.parent = hierarchy.parent;
+            go.children = hierarchy.children.clone();
+        }
+        go.drawable_object = self.drawables.get(id).cloned();
+        go.rigid_body_handle = self.rigid_bodies.get(id).copied();
+        go.grounded = self.grounded.get(id).copied().unwrap_or(true);
+        go.light = self.lights.get(id).cloned();
+        go
+    }
+
+    fn write_components(&mut self, id: GameObjectID, mut go: GameObject) {
+        let pending_children = std::mem::take(&mut go.pending_children);
+        self.transforms.insert(id, Transform {
+            position: go.position,
+            rotation: go.rotation,
+            scale: go.scale,
+            world_matrix: go.world_matrix,
+            world_rotation: go.world_rotation,
+        });
+        if go.parent.is_some() || !go.children.is_empty() {
+            self.hierarchy.insert(id, Hierarchy { parent: go.parent, children: go.children });
+        } else {
+            self.hierarchy.remove(&id);
+        }
+        match go.drawable_object {
+            Some(drawable_object) => { self.drawables.insert(id, drawable_object); },
+            None => { self.drawables.remove(&id); },
+        }
+        let old_rb_handle = match go.rigid_body_handle {
+            Some(rb_handle) => {
+                self.by_rb_handle.insert(rb_handle, id);
+                self.rigid_bodies.insert(id, rb_handle).filter(|old| *old != rb_handle)
+            },
+            None => self.rigid_bodies.remove(&id),
+        };
+        if let Some(old_rb_handle) = old_rb_handle {
+            self.by_rb_handle.remove(&old_rb_handle);
+        }
+        self.grounded.insert(id, go.grounded);
+        match go.light {
+            Some(light) => { self.lights.insert(id, light); },
+            None => { self.lights.remove(&id); },
+        }
+        /* behaviors added while the object was checked out are kept after its own */
+        let mut behaviors = go.behaviors;
+        if let Some(mut added) = self.behaviors.remove(&id) {
+            behaviors.append(&mut added);
+        }
+        self.behaviors.insert(id, behaviors);
+
+        for mut child in pending_children {
+            child.parent = Some(id);
+            let child_id = self.add(child);
+            self.set_hierarchy(&id, |hierarchy| hierarchy.children.push(child_id));
+        }
+    }
+
+    fn set_hierarchy(&mut self, id: &GameObjectID, change: impl FnOnce(&mut Hierarchy)) {
+        if !self.contains(id) {
+            return;
+        }
+        if !self.hierarchy.contains(id) {
+            self.hierarchy.insert(*id, Hierarchy::default());
+        }
+        if let Some(hierarchy) = self.hierarchy.get_mut(id) {
+            change(hierarchy);
+            if hierarchy.parent.is_none() && hierarchy.children.is_empty() {
+                self.hierarchy.remove(id);
+            }
+        }
+    }
+
+    fn unlink_child(&mut self, parent: &GameObjectID, child: &GameObjectID) {
+        self.set_hierarchy(parent, |hierarchy| hierarchy.children.retain(|id| id != child));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(index: u32, generation: u32) -> GameObjectID {
+        GameObjectID { index, generation }
+    }
+
+    #[test]
+    fn sparse_set_insert_and_replace() {
+        let mut set = SparseSet::new();
+        assert_eq!(set.insert(id(3, 0), "a"), None);
+        assert_eq!(set.insert(id(0, 0), "b"), None);
+        assert_eq!(set.insert(id(3, 0), "c"), Some("a"));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.get(&id(3, 0)), Some(&"c"));
+        assert_eq!(set.get(&id(1, 0)), None);
+        assert_eq!(set.ids(), &[id(3, 0), id(0, 0)]);
+    }
+
+    #[test]
+    fn sparse_set_remove_moves_the_last_entry() {
+        let mut set = SparseSet::new();
+        for i in 0..4 {
+            set.insert(id(i, 0), i);
+        }
+        assert_eq!(set.remove(&id(1, 0)), Some(1));
+        assert_eq!(set.remove(&id(1, 0)), None);
+        assert_eq!(set.ids(), &[id(0, 0), id(3, 0), id(2, 0)]);
+        for i in [0, 2, 3] {
+            assert_eq!(set.get(&id(i, 0)), Some(&i));
+        }
+        assert!(!set.contains(&id(1, 0)));
+    }
+
+    #[test]
+    fn sparse_set_stale_generations_never_resolve() {
+        let mut set = SparseSet::new();
+        set.insert(id(2, 0), "old");
+        assert_eq!(set.get(&id(2, 1)), None);
+        assert_eq!(set.remove(&id(2, 1)), None);
+
+        /* the slot's next generation drops the stale value */
+        assert_eq!(set.insert(id(2, 1), "new"), Some("old"));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.get(&id(2, 0)), None);
+        assert_eq!(set.get(&id(2, 1)), Some(&"new"));
+    }
+
+    #[test]
+    fn store_reuses_slots_with_a_new_generation() {
+        let mut store = GameObjectStore::new();
+        let first = store.add(GameObject::empty());
+        let second = store.add(GameObject::empty());
+        assert!(store.remove(&first).is_some());
+        assert!(store.remove(&first).is_none());
+
+        let reused = store.add(GameObject::empty());
+        assert_eq!(reused.index, first.index);
+        assert_ne!(reused.generation, first.generation);
+        assert!(!store.contains(&first));
+        assert!(store.contains(&reused) && store.contains(&second));
+        assert_eq!(store.len(), 2);
+    }
+
+    fn at(position: vec::Vec3) -> GameObject {
+        let mut go = GameObject::empty();
+        go.position = position;
+        go
+    }
+
+    fn world_position(store: &GameObjectStore, id: &GameObjectID) -> vec::Vec3 {
+        store.transforms.get(id).unwrap().world_matrix.cols[3].xyz()
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut store = GameObjectStore::new();
+        let root = store.add(GameObject::empty());
+        let child = store.add(GameObject::empty());
+        let grandchild = store.add(GameObject::empty());
+        store.set_parent(&child, Some(root)).unwrap();
+        store.set_parent(&grandchild, Some(child)).unwrap();
+
+        assert!(store.set_parent(&root, Some(grandchild)).is_err());
+        assert!(store.set_parent(&root, Some(root)).is_err());
+        assert!(store.set_parent(&child, Some(grandchild)).is_err());
+        /* nothing changed */
+        assert_eq!(store.parent(&root), None);
+        assert_eq!(store.children(&root), vec![child]);
+        assert_eq!(store.children(&child), vec![grandchild]);
+
+        /* moving a subtree elsewhere is fine */
+        store.set_parent(&grandchild, Some(root)).unwrap();
+        assert_eq!(store.children(&root), vec![child, grandchild]);
+        assert!(store.children(&child).is_empty());
+        store.set_parent(&child, None).unwrap();
+        assert_eq!(store.parent(&child), None);
+        assert!(!store.hierarchy.contains(&child));
+    }
+
+    #[test]
+    fn children_compose_their_parents_transform() {
+        let mut parent = at(vec::Vec3::new(10.0, 0.0, 0.0));
+        parent.rotation = vec::Vec3::new(0.0, 0.0, std::f32::consts::FRAC_PI_2);
+        parent.scale = vec::Vec3::broadcast(2.0);
+        let mut store = GameObjectStore::new();
+        let parent_id = store.add(parent.add_child(at(vec::Vec3::new(1.0, 0.0, 0.0)).add_child(at(vec::Vec3::new(0.0, 1.0, 0.0)))));
+        let child_id = store.children(&parent_id)[0];
+        let grandchild_id = store.children(&child_id)[0];
+        store.update_world_transforms(1.0);
+
+        let transform = |id: &GameObjectID| store.transforms.get(id).unwrap().clone();
+        let (parent, child, grandchild) = (transform(&parent_id), transform(&child_id), transform(&grandchild_id));
+        assert_eq!(parent.world_matrix, parent.local_matrix());
+        assert_eq!(child.world_matrix, parent.world_matrix * child.local_matrix());
+        assert_eq!(child.world_rotation, parent.world_rotation * child.local_rotation());
+        assert_eq!(grandchild.world_matrix, child.world_matrix * grandchild.local_matrix());
+
+        /* moving the parent moves the children with it */
+        store.transforms.get_mut(&parent_id).unwrap().position.x += 1.0;
+        let before = world_position(&store, &grandchild_id);
+        store.update_world_transforms(1.0);
+        let moved = world_position(&store, &grandchild_id) - before;
+        assert!((moved - vec::Vec3::new(2.0, 0.0, 0.0)).mag() < 1e-5, "{:?}", moved);
+    }
+
+    #[test]
+    fn rigid_body_children_ignore_their_parent() {
+        let mut rigid_body_set = RigidBodySet::new();
+        let handle = rigid_body_set.insert(RigidBodyBuilder::dynamic().translation(vector![3.0, 4.0]).build());
+        let mut body = at(vec::Vec3::new(3.0, 4.0, 0.0));
+        body.rigid_body_handle = Some(handle);
+
+        let mut store = GameObjectStore::new();
+        let parent_id = store.add(at(vec::Vec3::new(10.0, 10.0, 0.0)).add_child(body));
+        let body_id = store.children(&parent_id)[0];
+        assert_eq!(store.lookup_by_rb_handle(&handle), Some(body_id));
+        store.sync_from_physics(&rigid_body_set);
+        store.update_world_transforms(1.0);
+        assert_eq!(world_position(&store, &body_id), vec::Vec3::new(3.0, 4.0, 0.0));
+
+        /* halfway from the pose before the last physics step */
+        rigid_body_set[handle].set_translation(vector![5.0, 4.0], true);
+        store.sync_from_physics(&rigid_body_set);
+        store.update_world_transforms(0.5);
+        assert_eq!(world_position(&store, &body_id), vec::Vec3::new(4.0, 4.0, 0.0));
+    }
+
+    #[test]
+    fn removing_an_object_unlinks_it() {
+        let mut store = GameObjectStore::new();
+        let parent = store.add(GameObject::empty().add_child(GameObject::empty().add_child(GameObject::empty())));
+        let child = store.children(&parent)[0];
+        let grandchild = store.children(&child)[0];
+
+        let removed = store.remove(&child).unwrap();
+        assert_eq!(removed.parent, Some(parent));
+        assert_eq!(removed.children, vec![grandchild]);
+        assert!(store.children(&parent).is_empty());
+        assert_eq!(store.parent(&grandchild), None);
+    }
+}
@@ -0,0 +1,224 @@
+use std::f32::consts::PI;
+use serde::{Serialize, Deserialize};
+use ultraviolet::vec;
+
+/* The environment is what surrounds a scene: a cubemap the renderer draws wherever the opaque
+objects left the background showing, after them so the covered pixels aren't shaded for nothing,
+and which materials with a reflectivity mirror. A scene sets it with `skybox` in its file or
+Context::environment in code, without one the background is the clear color.
+Cubemaps are loaded and uploaded like textures, once per source, see Context::load_cubemap. */
+
+fn one() -> f32 { 1.0 }
+
+/* where a cubemap's faces come from, paths are relative to the working directory like model paths */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SkyboxSource {
+    /* one square image per face, in GL's order: +x, -x, +y, -y, +z, -z */
+    Faces([String; 6]),
+    /* one panorama 360 degrees around and 180 up and down, e.g. an .hdr, resampled to faces */
+    Equirectangular(String),
+    /* colors straight up, at the horizon and straight down, blended in between. Needs no files */
+    Gradient { top: [f32; 3], horizon: [f32; 3], bottom: [f32; 3] },
+}
+
+/* `skybox` in a scene file */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SkyboxDesc {
+    pub source: SkyboxSource,
+    /* multiplies the sky's colors, for HDR panoramas that are too bright or too dark */
+    #[serde(default = "one")]
+    pub intensity: f32,
+    /* width of a face made from a panorama or gradient, a quarter of the panorama's width when unset */
+    #[serde(default)]
+    pub face_size: Option<u32>,
+}
+
+/* the environment of a scene, `cubemap` is an index into Context::cubemaps */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Environment {
+    pub cubemap: usize,
+    pub intensity: f32,
+}
+
+/* six square faces of linear RGB floats, in GL's face order, each face top row first */
+#[derive(Debug, Clone)]
+pub struct CubemapData {
+    pub size: u32,
+    pub faces: [Vec<f32>; 6],
+}
+impl CubemapData {
+    pub fn load(source: &SkyboxSource, face_size: Option<u32>) -> Result<Self, String> {
+        match source {
+            SkyboxSource::Faces(paths) => Self::from_faces(paths),
+            SkyboxSource::Equirectangular(path) => Self::from_equirectangular(path, face_size),
+            SkyboxSource::Gradient { top, horizon, bottom } => {
+                let (top, horizon, bottom) = (vec::Vec3::from(*top), vec::Vec3::from(*horizon), vec::Vec3::from(*bottom));
+                Ok(Self::from_fn(face_size.unwrap_or(64), |dir| {
+                    let height = dir.y.clamp(-1.0, 1.0);
+                    if height >= 0.0 {
+                        horizon + (top - horizon) * height
+                    } else {
+                        horizon + (bottom - horizon) * -height
+                    }
+                }))
+            },
+        }
+    }
+
+    pub fn from_faces(paths: &[String; 6]) -> Result<Self, String> {
+        let mut size = None;
+        let mut faces: [Vec<f32>; 6] = Default::default();
+        for (face, path) in faces.iter_mut().zip(paths) {
+            let image = image::open(path)
+                .map_err(|e| format!("Could not load skybox face {}: {}", path, e))?
+                .into_rgb32f();
+            if image.width() != image.height() || size.is_some_and(|size| size != image.width()) {
+                return Err(format!("Skybox face {} is {}x{}, faces have to be squares of the same size", path, image.width(), image.height()));
+            }
+            size = Some(image.width());
+            *face = image.into_raw();
+        }
+        Ok(Self { size: size.unwrap_or(0), faces })
+    }
+
+    pub fn from_equirectangular(path: &str, face_size: Option<u32>) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("Could not load skybox panorama {}: {}", path, e))?
+            .into_rgb32f();
+        let (width, height) = (image.width(), image.height());
+        if width == 0 || height == 0 {
+            return Err(format!("Skybox panorama {} is empty", path));
+        }
+        Ok(Self::from_fn(face_size.unwrap_or((width / 4).max(1)), |dir| {
+            /* longitude around y, 0 looking down -z, and latitude from straight up */
+            let u = 0.5 + dir.x.atan2(-dir.z) / (2.0 * PI);
+            let v = dir.y.clamp(-1.0, 1.0).acos() / PI;
+            let x = ((u * width as f32) as u32).min(width - 1);
+            let y = ((v * height as f32) as u32).min(height - 1);
+            vec::Vec3::from(image.get_pixel(x, y).0)
+        }))
+    }
+
+    /* every texel set to `color(direction)`, the direction through the texel's center, normalized */
+    pub fn from_fn(size: u32, color: impl Fn(vec::Vec3) -> vec::Vec3) -> Self {
+        let mut faces: [Vec<f32>; 6] = Default::default();
+        for (face_idx, face) in faces.iter_mut().enumerate() {
+            face.reserve((size * size * 3) as usize);
+            for y in 0..size {
+                for x in 0..size {
+                    let s = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                    let t = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                    face.extend_from_slice(color(face_direction(face_idx, s, t).normalized()).as_array());
+                }
+            }
+        }
+        Self { size, faces }
+    }
+
+    /* the texel the direction points at, the way GL picks a face and a spot on it */
+    pub fn sample(&self, dir: vec::Vec3) -> vec::Vec3 {
+        if self.size == 0 {
+            return vec::Vec3::zero();
+        }
+        let (ax, ay, az) = (dir.x.abs(), dir.y.abs(), dir.z.abs());
+        let (face_idx, s, t, major) = if ax >= ay && ax >= az {
+            if dir.x > 0.0 { (0, -dir.z, -dir.y, ax) } else { (1, dir.z, -dir.y, ax) }
+        } else if ay >= az {
+            if dir.y > 0.0 { (2, dir.x, dir.z, ay) } else { (3, dir.x, -dir.z, ay) }
+        } else if dir.z > 0.0 {
+            (4, dir.x, -dir.y, az)
+        } else {
+            (5, -dir.x, -dir.y, az)
+        };
+        if major <= 0.0 {
+            return vec::Vec3::zero();
+        }
+        let texel = |coord: f32| (((coord / major + 1.0) * 0.5 * self.size as f32) as u32).min(self.size - 1);
+        let i = ((texel(t) * self.size + texel(s)) * 3) as usize;
+        match self.faces[face_idx].get(i..i + 3) {
+            Some(rgb) => vec::Vec3::new(rgb[0], rgb[1], rgb[2]),
+            None => vec::Vec3::zero(),
+        }
+    }
+}
+
+/* the inverse of sample's face choice, s and t from -1 to 1 across the face */
+fn face_direction(face_idx: usize, s: f32, t: f32) -> vec::Vec3 {
+    match face_idx {
+        0 => vec::Vec3::new(1.0, -t, -s),
+        1 => vec::Vec3::new(-1.0, -t, s),
+        2 => vec::Vec3::new(s, 1.0, t),
+        3 => vec::Vec3::new(s, -1.0, -t),
+        4 => vec::Vec3::new(s, -t, 1.0),
+        _ => vec::Vec3::new(-s, -t, -1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn assert_close(a: vec::Vec3, b: vec::Vec3) {
+        assert!((a - b).mag() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    /* a folder of its own under the system temp dir for each test */
+    fn temp_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustgraphics-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn faces_round_trip_through_sample() {
+        /* an odd size puts a texel on the face's center, every texel holds its own direction */
+        let size = 5;
+        let cubemap = CubemapData::from_fn(size, |dir| dir);
+        let center = |texel: u32| (texel as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+        for face_idx in 0..6 {
+            for (x, y) in [(2, 2), (0, 0), (size - 1, 0), (0, size - 1), (size - 1, size - 1)] {
+                let dir = face_direction(face_idx, center(x), center(y));
+                assert_close(cubemap.sample(dir), dir.normalized());
+                assert_close(cubemap.sample(dir * 3.0), dir.normalized());
+            }
+        }
+        assert_close(cubemap.sample(vec::Vec3::unit_x()), vec::Vec3::unit_x());
+        assert_close(cubemap.sample(-vec::Vec3::unit_z()), -vec::Vec3::unit_z());
+        assert_eq!(cubemap.sample(vec::Vec3::zero()), vec::Vec3::zero());
+    }
+
+    #[test]
+    fn gradient_blends_from_bottom_to_top() {
+        let (top, horizon, bottom) = ([0.0, 0.0, 1.0], [1.0, 1.0, 1.0], [0.2, 0.1, 0.0]);
+        let cubemap = CubemapData::load(&SkyboxSource::Gradient { top, horizon, bottom }, Some(5)).unwrap();
+        assert_eq!(cubemap.size, 5);
+        assert_close(cubemap.sample(vec::Vec3::unit_y()), vec::Vec3::from(top));
+        assert_close(cubemap.sample(-vec::Vec3::unit_y()), vec::Vec3::from(bottom));
+        for dir in [vec::Vec3::unit_x(), -vec::Vec3::unit_x(), vec::Vec3::unit_z(), -vec::Vec3::unit_z()] {
+            assert_close(cubemap.sample(dir), vec::Vec3::from(horizon));
+        }
+    }
+
+    #[test]
+    fn faces_have_to_be_squares_of_one_size() {
+        let dir = temp_dir("skybox-faces");
+        let face = |name: &str, width: u32, height: u32| {
+            let path = dir.join(name);
+            RgbImage::new(width, height).save(&path).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let square = face("square.png", 4, 4);
+        let wide = face("wide.png", 4, 2);
+        let small = face("small.png", 2, 2);
+
+        let faces = |odd: &str| [square.clone(), square.clone(), odd.to_string(), square.clone(), square.clone(), square.clone()];
+        let cubemap = CubemapData::from_faces(&faces(&square)).unwrap();
+        assert_eq!(cubemap.size, 4);
+        assert!(cubemap.faces.iter().all(|face| face.len() == 4 * 4 * 3));
+        assert!(CubemapData::from_faces(&faces(&wide)).is_err());
+        assert!(CubemapData::from_faces(&faces(&small)).is_err());
+        assert!(CubemapData::from_faces(&faces("missing.png")).is_err());
+    }
+}
@@ -1,32 +1,61 @@
 #![allow(dead_code)]
 
-use log::{Level, SetLoggerError, LevelFilter, info};
+use log::{Level, SetLoggerError, LevelFilter, info, warn};
 use ogl33::*;
 use beryllium::*;
 // use rapier2d::prelude::*;
 // use rapier2d::math::Vector;
 use rapier2d::{prelude::*, pipeline::ChannelEventCollector, crossbeam};
 use std::fs;
-use std::cell::RefCell;
-use std::collections::{HashSet, HashMap};
-use std::str::FromStr;
+use std::path::Path;
+use std::collections::{BTreeMap, HashSet, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
 // use rand::Rng;
 use image::io::Reader as ImageReader;
+use serde::{Serialize, Deserialize};
 use ultraviolet::{mat, vec, projection};
 use tobj::Model;
-use core::{
-    convert::TryInto,
-    mem::size_of
-};
-use std::time::{Instant, Duration};
+use core::convert::TryInto;
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
 use crate::behaviors::*;
 use crate::camera::*;
+use crate::snapshot::*;
+use crate::scenes::*;
+use crate::collisions::*;
+use crate::ecs::*;
+use crate::offscreen::*;
+use crate::render::*;
+use crate::materials::*;
+use crate::bounds::*;
+use crate::software_render::*;
+use crate::lights::*;
+use crate::environment::*;
+use crate::post_process::*;
 
 // function to wrap clear color and allow it to be labelled safe because nothing should be able to go wrong with glclearcolor
 pub fn clear_color(r:f32, g:f32, b:f32, a:f32) {
     unsafe { glClearColor(r,g,b,a) }
 }
 
+/* None draws over what is there. Blending only mixes the color, the target's alpha is kept
+so images read back stay opaque. Depth writes have to be back on before clearing depth */
+pub fn set_blend_state(blend: Option<BlendMode>, depth_write: bool) {
+    unsafe {
+        match blend {
+            Some(BlendMode::Alpha) => {
+                glEnable(GL_BLEND);
+                glBlendFuncSeparate(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA, GL_ZERO, GL_ONE);
+            },
+            Some(BlendMode::Additive) => {
+                glEnable(GL_BLEND);
+                glBlendFuncSeparate(GL_SRC_ALPHA, GL_ONE, GL_ZERO, GL_ONE);
+            },
+            None => glDisable(GL_BLEND),
+        }
+        glDepthMask(if depth_write { GL_TRUE } else { GL_FALSE });
+    }
+}
+
 pub const LT_MAIN_LOOP: &str = "MainLoop";
 pub const LT_BEHAVIORS: &str = "Behaviors";
 
@@ -57,6 +86,7 @@ pub fn init_log() -> Result<(), SetLoggerError> {
         .map(|()| log::set_max_level(LevelFilter::Info))
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct CameraParams {
     pub view_pos: vec::Vec3,
     pub view_rot: vec::Vec3,
@@ -83,32 +113,52 @@ impl CameraParams {
     }
 }
 
-// verts + norms, tri indices, # of tris, shaderidx
+// verts + norms, tri indices, # of tris, index into Context::materials, bounds in model space
 pub struct MeshData {
     pub point_data: Vec<f32>, 
     pub point_indices: Vec<u32>, 
     pub tri_count: usize, 
-    pub shader_idx: usize
+    pub material_idx: usize,
+    pub bounds: Bounds
 }
 pub struct MeshDataGroup(pub Vec<MeshData>);
-// vao, vbo, ebo, tris, shaderidx
+// vao, vbo, ebo, tris, index into Context::materials
 pub struct Drawable{
     pub vao: VertexArray, 
     pub vbo: Buffer, 
     pub ebo: Buffer, 
     pub tri_count: usize, 
-    pub shader_idx: usize
+    pub material_idx: usize
 }
 pub struct DrawableGroup(pub Vec<Drawable>);
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DrawableObject {
     pub position: vec::Vec3, 
     pub rotation: vec::Vec3,
     pub scale: vec::Vec3,
     pub drawable_group_idx: usize, 
+    /* whether it shows up in the shadow map and whether shadows fall on it */
+    #[serde(default = "default_true")]
+    pub cast_shadows: bool,
+    #[serde(default = "default_true")]
+    pub receive_shadows: bool,
+    /* this object's changes to the model's materials, see materials.rs */
+    #[serde(default)]
+    pub material_override: MaterialOverride,
 }
+fn default_true() -> bool { true }
 impl DrawableObject {
     pub fn new(position: vec::Vec3, rotation: vec::Vec3, scale: vec::Vec3, drawable_group_idx: usize) -> Self {
-        Self {position, rotation, scale, drawable_group_idx}
+        Self {position, rotation, scale, drawable_group_idx, cast_shadows: true, receive_shadows: true, material_override: MaterialOverride::default()}
+    }
+    pub fn with_shadows(mut self, cast_shadows: bool, receive_shadows: bool) -> Self {
+        self.cast_shadows = cast_shadows;
+        self.receive_shadows = receive_shadows;
+        self
+    }
+    pub fn with_material_override(mut self, material_override: MaterialOverride) -> Self {
+        self.material_override = material_override;
+        self
     }
     pub fn model_matrix(&self) -> mat::Mat4 {
         let pos = mat::Mat4::from_translation(self.position);
@@ -123,21 +173,35 @@ impl DrawableObject {
     }
 }
 
-pub struct GameObject<T> {
+/* position, rotation and scale are local, relative to the parent when there is one.
+Objects with a rigid body are the exception, physics places them in the world directly
+and the parent only groups them (despawning the parent still despawns them). */
+#[derive(Serialize, Deserialize)]
+pub struct GameObject {
     pub position: vec::Vec3,
     pub rotation: vec::Vec3,
     pub scale: vec::Vec3,
-    pub children: Vec<Self>,
+    pub parent: Option<GameObjectID>,
+    pub children: Vec<GameObjectID>,
+    /* children given to new/add_child, the store adds them as real children along with this object */
+    #[serde(skip)]
+    pub pending_children: Vec<Self>,
+    /* local transform composed with the parent's, updated every frame before drawing */
+    #[serde(skip, default = "mat::Mat4::identity")]
+    pub world_matrix: mat::Mat4,
+    #[serde(skip, default = "mat::Mat4::identity")]
+    pub world_rotation: mat::Mat4,
     pub drawable_object: Option<DrawableObject>,
     pub rigid_body_handle: Option<RigidBodyHandle>,
-    pub behaviors: HashSet<Behaviors>,
-    pub collision_behaviors: HashSet<CollisionBehaviors>,
-    pub behaviors_data: HashMap<BehaviorData, T>,
-    pub collisions_behavior_data: HashMap<CollisionData, Box<dyn CollisionDataContainer>>,
+    /* behaviors are saved separately by snapshots since they need the BehaviorRegistry to be rebuilt */
+    #[serde(skip)]
+    pub behaviors: Vec<Box<dyn Behavior>>,
     pub id: GameObjectID,
     pub grounded: bool,
+    #[serde(default)]
+    pub light: Option<Light>,
 }
-impl<T> GameObject<T> {
+impl GameObject {
     pub fn new(
         position: vec::Vec3, 
         rotation: vec::Vec3, 
@@ -145,24 +209,23 @@ impl<T> GameObject<T> {
         children: Vec<Self>, 
         drawable_object: Option<DrawableObject>, 
         rigid_body_handle: Option<RigidBodyHandle>, 
-        behaviors: HashSet<Behaviors>, 
-        collision_behaviors: HashSet<CollisionBehaviors>,
-        behaviors_data: HashMap<BehaviorData, T>,
-        collisions_behavior_data: HashMap<CollisionData, Box<dyn CollisionDataContainer>>,
+        behaviors: Vec<Box<dyn Behavior>>, 
     ) -> Self {
         Self{
             position, 
             rotation, 
             scale, 
-            children, 
+            parent: None,
+            children: vec![],
+            pending_children: children,
+            world_matrix: mat::Mat4::identity(),
+            world_rotation: mat::Mat4::identity(),
             drawable_object, 
             rigid_body_handle, 
             behaviors, 
-            collision_behaviors, 
-            behaviors_data,
-            collisions_behavior_data,
-            id: 0, 
-            grounded: true
+            id: GameObjectID::NONE, 
+            grounded: true,
+            light: None
         }
     }
     pub fn empty() -> Self {
@@ -170,15 +233,17 @@ impl<T> GameObject<T> {
             position: vec::Vec3::zero(), 
             rotation: vec::Vec3::zero(), 
             scale: vec::Vec3::one(), 
-            children: vec![], 
+            parent: None,
+            children: vec![],
+            pending_children: vec![],
+            world_matrix: mat::Mat4::identity(),
+            world_rotation: mat::Mat4::identity(),
             drawable_object: None, 
             rigid_body_handle: None, 
-            behaviors: HashSet::new(), 
-            collision_behaviors: HashSet::new(), 
-            behaviors_data: HashMap::new(),
-            collisions_behavior_data: HashMap::new(),
-            id: 0, 
-            grounded: true
+            behaviors: vec![], 
+            id: GameObjectID::NONE, 
+            grounded: true,
+            light: None
         }
     }
     pub fn model_matrix(&self) -> mat::Mat4 {
@@ -199,59 +264,32 @@ impl<T> GameObject<T> {
             self.rotation.z = rigid_body_set[*rigid_body_idx].rotation().angle();
         }
     }
-    pub fn add_behavior(mut self, behavior: Behaviors) -> Self {
-        self.behaviors.insert(behavior);
+    pub fn add_behavior<B: Behavior>(mut self, behavior: B) -> Self {
+        self.behaviors.push(Box::new(behavior));
         self
     }
-    pub fn add_collision_behavior(mut self, collision_behavior: CollisionBehaviors) -> Self {
-        self.collision_behaviors.insert(collision_behavior);
-        self
+    /* first behavior of the given type on this object, for reading another behavior's state */
+    pub fn get_behavior<B: Behavior>(&self) -> Option<&B> {
+        self.behaviors.iter().find_map(|b| b.as_any().downcast_ref::<B>())
     }
-    pub fn add_behavior_data(mut self, behavior: Behaviors, behavior_data: T) -> Self {
-        self.behaviors_data.insert(BehaviorData::Behaviors(behavior), behavior_data);
-        self
+    pub fn get_behavior_mut<B: Behavior>(&mut self) -> Option<&mut B> {
+        self.behaviors.iter_mut().find_map(|b| b.as_any_mut().downcast_mut::<B>())
     }
-    pub fn add_collision_behavior_data(mut self, collision_behavior: CollisionBehaviors, collision_behavior_data: Box<dyn CollisionDataContainer>) -> Self {
-        self.collisions_behavior_data.insert( CollisionData::CollisionBehaviors(collision_behavior), collision_behavior_data);
+    pub fn add_light(mut self, light: Light) -> Self {
+        self.light = Some(light);
         self
     }
     pub fn add_child(mut self, child: Self) -> Self {
-        self.children.push(child);
+        self.pending_children.push(child);
         self
     }
-}
-
-pub type GameObjectID = usize;
-pub struct GameObjectStore<T>(pub HashMap<GameObjectID, RefCell<GameObject<T>>>, pub HashMap<RigidBodyHandle, GameObjectID>, GameObjectID);
-impl<T> GameObjectStore<T> {
-    pub fn new() -> Self {
-        Self(HashMap::new(), HashMap::new(), 0)
-    }
-    pub fn add(&mut self, mut go: GameObject<T>) -> GameObjectID {
-        self.2 += 1;
-        go.id = self.2;
-        if let Some(rb_handle) = go.rigid_body_handle {
-            self.1.insert(rb_handle, self.2);
-        }
-        self.0.insert(self.2, RefCell::new(go));
-        self.2
-    }
-    pub fn remove(&mut self, id: &GameObjectID) {
-        if self.0.contains_key(id) {
-            if let Some(rb_handle) = self.0[&id].borrow().rigid_body_handle {
-                self.1.remove(&rb_handle);
-            }
-            self.0.remove(id);
-        } else {
-            // some error i guess
-        }
-    }
-    pub fn lookup_by_rb_handle(&self, rb_handle: &RigidBodyHandle) -> GameObjectID {
-        *self.1.get(rb_handle).expect("rb_handle not in rb to obj map")
+    /* world space position, valid once the frame's transforms are updated */
+    pub fn world_position(&self) -> vec::Vec3 {
+        self.world_matrix.cols[3].xyz()
     }
 }
 
-pub fn make_go<T>(position: vec::Vec3, rotation: vec::Vec3, scale: vec::Vec3, model_position: vec::Vec3, model_rotation: vec::Vec3, model_scale: vec::Vec3, drawable_obj_idx: usize) -> GameObject<T> {
+pub fn make_go(position: vec::Vec3, rotation: vec::Vec3, scale: vec::Vec3, model_position: vec::Vec3, model_rotation: vec::Vec3, model_scale: vec::Vec3, drawable_obj_idx: usize) -> GameObject {
     let mut go = GameObject::empty();
     go.position = position;
     go.rotation = rotation;
@@ -260,7 +298,7 @@ pub fn make_go<T>(position: vec::Vec3, rotation: vec::Vec3, scale: vec::Vec3, mo
     go
 }
 
-pub fn make_go_rb<T>(position: vec::Vec3, rotation: vec::Vec3, scale: vec::Vec3, model_position: vec::Vec3, model_rotation: vec::Vec3, model_scale: vec::Vec3, drawable_obj_idx: usize, rigid_body_handle: RigidBodyHandle) -> GameObject<T> {
+pub fn make_go_rb(position: vec::Vec3, rotation: vec::Vec3, scale: vec::Vec3, model_position: vec::Vec3, model_rotation: vec::Vec3, model_scale: vec::Vec3, drawable_obj_idx: usize, rigid_body_handle: RigidBodyHandle) -> GameObject {
     let mut go = GameObject::empty();
     go.position = position;
     go.rotation = rotation;
@@ -299,6 +337,8 @@ pub enum BufferType {
     Array = GL_ARRAY_BUFFER as isize,
     // holds indexes of what vertices to use for drawing
     ElementArray = GL_ELEMENT_ARRAY_BUFFER as isize,
+    // holds uniform blocks shared by several shader programs
+    Uniform = GL_UNIFORM_BUFFER as isize,
 }
 
 // struct to wrap creation of buffers with functions to bind the buffer to a target and unbind it
@@ -323,6 +363,11 @@ impl Buffer {
     pub fn unbind(ty: BufferType) {
         unsafe { glBindBuffer(ty as GLenum, 0) }
     }
+
+    /* makes the buffer the uniform block binding point `binding` */
+    pub fn bind_base(&self, ty: BufferType, binding: GLuint) {
+        unsafe { glBindBufferBase(ty as GLenum, binding, self.0) }
+    }
 }
 
 // load data into the bound buffer
@@ -337,6 +382,27 @@ pub fn buffer_data(ty: BufferType, data: &[u8], usage: GLenum) {
     }
 }
 
+// overwrite part of the bound buffer, starting at offset bytes
+pub fn buffer_sub_data(ty: BufferType, offset: usize, data: &[u8]) {
+    unsafe {
+        glBufferSubData(
+            ty as GLenum,
+            offset as GLintptr,
+            data.len().try_into().unwrap(),
+            data.as_ptr().cast(),
+        );
+    }
+}
+
+/* GLSL wants #version first, so the defines go on the line after it */
+pub fn add_defines(source: &str, defines: &[&str]) -> String {
+    let define_lines: String = defines.iter().map(|define| format!("#define {}\n", define)).collect();
+    match source.find("#version").and_then(|start| source[start..].find('\n').map(|end| start + end + 1)) {
+        Some(split) => format!("{}{}{}", &source[..split], define_lines, &source[split..]),
+        None => format!("{}{}", define_lines, source),
+    }
+}
+
 #[derive(PartialEq)]
 pub enum ShaderType {
     // shader type for determining and modifying position of geometry on the screen
@@ -346,7 +412,7 @@ pub enum ShaderType {
     Fragment = GL_FRAGMENT_SHADER as isize,
 }
 
-pub const UNI_ID: [&str; 14] = [
+pub const UNI_ID: [&str; 43] = [
     "rotation\0",
     "model\0",
     "view\0",
@@ -360,7 +426,36 @@ pub const UNI_ID: [&str; 14] = [
     "optical_density\0",
     "dissolve\0",
     "our_texture\0",
-    "our_texture2\0"
+    "our_texture2\0",
+    "shininess\0",
+    "lightSpace\0",
+    "shadow_map\0",
+    "shadow_light\0",
+    "shadow_bias\0",
+    "shadow_slope_bias\0",
+    "shadow_pcf\0",
+    "receive_shadows\0",
+    "diffuse_map\0",
+    "specular_map\0",
+    "bump_map\0",
+    "alpha_cutoff\0",
+    "environment_map\0",
+    "has_environment\0",
+    "environment_intensity\0",
+    "reflectivity\0",
+    "scene\0",
+    "bloom\0",
+    "texel_size\0",
+    "exposure\0",
+    "tonemap_operator\0",
+    "gamma\0",
+    "threshold\0",
+    "intensity\0",
+    "direction\0",
+    "strength\0",
+    "radius\0",
+    "lut\0",
+    "lut_size\0"
 ];
 pub enum UniEnum {
     Rotation,
@@ -376,9 +471,41 @@ pub enum UniEnum {
     OpticalDensity,
     Dissolve,
     Texture,
-    Texture2
+    Texture2,
+    Shininess,
+    LightSpace,
+    ShadowMap,
+    ShadowLight,
+    ShadowBias,
+    ShadowSlopeBias,
+    ShadowPcf,
+    ReceiveShadows,
+    DiffuseMap,
+    SpecularMap,
+    BumpMap,
+    AlphaCutoff,
+    EnvironmentMap,
+    HasEnvironment,
+    EnvironmentIntensity,
+    Reflectivity,
+    Scene,
+    Bloom,
+    TexelSize,
+    Exposure,
+    TonemapOperator,
+    Gamma,
+    Threshold,
+    Intensity,
+    Direction,
+    Strength,
+    Radius,
+    Lut,
+    LutSize
 }
 
+/* the program glUseProgram was last called with */
+static CURRENT_PROGRAM: AtomicU32 = AtomicU32::new(0);
+
 // struct to wrap creation of shader with functions to operate
 // the creation, setting of source, compilation, and error detection
 // and a function to fully create+compile a shader in a single call
@@ -496,13 +623,23 @@ impl ShaderProgram {
 
     pub fn from_files(vert_source_path: &str, frag_source_path: &str) -> Result<Self, String> {
         let vert_source = fs::read_to_string(vert_source_path)
-            .expect("Failed to read vert shader file from source path");
+            .map_err(|e| format!("Failed to read vert shader {}: {}", vert_source_path, e))?;
         let frag_source = fs::read_to_string(frag_source_path)
-            .expect("Failed to read frag shader file from source path");
+            .map_err(|e| format!("Failed to read frag shader {}: {}", frag_source_path, e))?;
 
         Self::from_sources(&vert_source, &frag_source)
     }
 
+    /* the same, with `#define NAME` lines added after #version, for shaders that come in variants */
+    pub fn from_files_with_defines(vert_source_path: &str, frag_source_path: &str, defines: &[&str]) -> Result<Self, String> {
+        let vert_source = fs::read_to_string(vert_source_path)
+            .map_err(|e| format!("Failed to read vert shader {}: {}", vert_source_path, e))?;
+        let frag_source = fs::read_to_string(frag_source_path)
+            .map_err(|e| format!("Failed to read frag shader {}: {}", frag_source_path, e))?;
+
+        Self::from_sources(&add_defines(&vert_source, defines), &add_defines(&frag_source, defines))
+    }
+
     pub fn from_files_with_texture(vert_source_path: &str, frag_source_path: &str, texture: &Texture, texture_uniform_name: &str) -> Result<Self, String> {
         let prog = Self::from_files(vert_source_path, frag_source_path);
         match prog {
@@ -545,14 +682,33 @@ impl ShaderProgram {
         String::from_utf8_lossy(&v).into_owned()
     }
 
+    /* does nothing while the program is already in use, every uniform setter calls it */
     pub fn use_program(&self) {
-        unsafe { glUseProgram(self.0) };
+        if CURRENT_PROGRAM.swap(self.0, Ordering::Relaxed) != self.0 {
+            unsafe { glUseProgram(self.0) };
+        }
+    }
+
+    /* for a new GL context, where no program is in use yet */
+    pub fn forget_current() {
+        CURRENT_PROGRAM.store(0, Ordering::Relaxed);
     }
 
     pub fn delete(&self) {
+        let _ = CURRENT_PROGRAM.compare_exchange(self.0, 0, Ordering::Relaxed, Ordering::Relaxed);
         unsafe { glDeleteProgram(self.0) };
     }
 
+    /* connects the program's uniform block to a buffer binding point, programs without the block are left alone */
+    pub fn bind_uniform_block(&self, block_name: &str, binding: GLuint) {
+        unsafe {
+            let index = glGetUniformBlockIndex(self.0, block_name.as_ptr().cast());
+            if index != GL_INVALID_INDEX {
+                glUniformBlockBinding(self.0, index, binding);
+            }
+        }
+    }
+
     pub fn set_int_bool(&self, uniform_name: &str, value: GLint) {
         self.use_program();
         unsafe { 
@@ -602,12 +758,12 @@ impl ShaderProgram {
         }
     }
 
-    pub fn set_4_float_matrix(&self, uniform_name: &str, value: *const f32 ) {
+    pub fn set_4_float_matrix(&self, uniform_name: &str, value: &mat::Mat4) {
         self.use_program();
         unsafe { 
             glUniformMatrix4fv(
                 glGetUniformLocation(self.0, uniform_name.as_ptr().cast()), 
-                1, GL_FALSE, value
+                1, GL_FALSE, value.as_ptr()
             ); 
         }
     }
@@ -641,6 +797,14 @@ impl Texture {
         unsafe { glBindTexture(GL_TEXTURE_2D, self.0); }
     }
 
+    /* binds it to some other unit than its own, for textures several shaders sample at different units */
+    pub fn bind_to_unit(&self, texture_unit: GLenum) {
+        unsafe {
+            glActiveTexture(texture_unit);
+            glBindTexture(GL_TEXTURE_2D, self.0);
+        }
+    }
+
     pub fn bind_and_set_params(&self) {
         self.activate_and_bind();
         unsafe {
@@ -699,88 +863,260 @@ impl Texture {
     }
 }
 
-pub fn color_program<'a>(
-    base_folder: &'a str,
-    shader_folder: &'a str,
-    color: &vec::Vec3,
-    model: &mat::Mat4, 
-    view: &mat::Mat4, 
-    projection: &mat::Mat4
-) -> ShaderProgram {
-    let vert = format!("{}/{}/{}", base_folder, shader_folder, "vertex.GLSL");
-    let frag = format!("{}/{}/{}", base_folder, shader_folder, "fragment.GLSL");
-    let shader = ShaderProgram::from_files(&vert, &frag).unwrap();
-    let [v1, v2, v3] = *((*color).as_array());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Rotation as usize], mat::Mat4::identity().as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Model as usize], model.as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::View as usize], view.as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Projection as usize], projection.as_ptr().cast());
-    shader.set_3_float(UNI_ID[UniEnum::Color as usize], v1, v2, v3);
-    shader.set_3_float(UNI_ID[UniEnum::LightPos as usize], 0.0, 0.0, 0.0);
-    shader.set_3_float(UNI_ID[UniEnum::ViewPos as usize], 0.0, 0.0, 0.0);
-    shader
+/* a cube map texture, six faces sampled by direction. Kept in half floats so HDR skies keep their range */
+pub struct Cubemap(pub GLuint);
+impl Cubemap {
+    pub fn new() -> Option<Self> {
+        let mut texture = 0;
+        unsafe { glGenTextures(1, &mut texture); }
+        if texture != 0 {
+            Some(Self(texture))
+        } else {
+            None
+        }
+    }
+
+    pub fn bind_to_unit(&self, texture_unit: GLenum) {
+        unsafe {
+            glActiveTexture(texture_unit);
+            glBindTexture(GL_TEXTURE_CUBE_MAP, self.0);
+        }
+    }
+
+    /* the faces as in CubemapData, RGB floats in the order +x, -x, +y, -y, +z, -z */
+    pub fn from_data(size: u32, faces: &[Vec<f32>; 6]) -> Result<Self, String> {
+        let cubemap = Self::new().ok_or_else(|| "Couldn't make a new cubemap".to_string())?;
+        cubemap.bind_to_unit(GL_TEXTURE0);
+        unsafe {
+            for (face_idx, face) in faces.iter().enumerate() {
+                glTexImage2D(
+                    GL_TEXTURE_CUBE_MAP_POSITIVE_X + face_idx as GLenum, 0, GL_RGB16F as i32,
+                    size as i32, size as i32, 0, GL_RGB,
+                    GL_FLOAT, face.as_ptr().cast()
+                );
+            }
+            glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_EDGE as GLint);
+            glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_EDGE as GLint);
+            glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_WRAP_R, GL_CLAMP_TO_EDGE as GLint);
+            glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_MIN_FILTER, GL_LINEAR as GLint);
+            glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_MAG_FILTER, GL_LINEAR as GLint);
+        }
+        Ok(cubemap)
+    }
+
+    pub fn delete(&self) {
+        unsafe { glDeleteTextures(1, &(self.0)); }
+    }
+}
+
+/* struct to wrap a framebuffer object with color and depth renderbuffers attached,
+for drawing offscreen. While bound everything draws into it and read_pixels reads from it */
+pub struct Framebuffer {
+    pub fbo: GLuint,
+    pub color: GLuint,
+    pub depth: GLuint,
+    pub width: u32,
+    pub height: u32,
+}
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Result<Self, String> {
+        let (mut fbo, mut color, mut depth) = (0, 0, 0);
+        unsafe {
+            glGenFramebuffers(1, &mut fbo);
+            glBindFramebuffer(GL_FRAMEBUFFER, fbo);
+
+            glGenRenderbuffers(1, &mut color);
+            glBindRenderbuffer(GL_RENDERBUFFER, color);
+            glRenderbufferStorage(GL_RENDERBUFFER, GL_RGBA8, width as GLsizei, height as GLsizei);
+            glFramebufferRenderbuffer(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_RENDERBUFFER, color);
+
+            glGenRenderbuffers(1, &mut depth);
+            glBindRenderbuffer(GL_RENDERBUFFER, depth);
+            glRenderbufferStorage(GL_RENDERBUFFER, GL_DEPTH24_STENCIL8, width as GLsizei, height as GLsizei);
+            glFramebufferRenderbuffer(GL_FRAMEBUFFER, GL_DEPTH_STENCIL_ATTACHMENT, GL_RENDERBUFFER, depth);
+
+            glBindRenderbuffer(GL_RENDERBUFFER, 0);
+        }
+        let framebuffer = Self { fbo, color, depth, width, height };
+        let status = unsafe { glCheckFramebufferStatus(GL_FRAMEBUFFER) };
+        Self::clear_binding();
+        if fbo == 0 || status != GL_FRAMEBUFFER_COMPLETE {
+            framebuffer.delete();
+            return Err(format!("Could not create a {}x{} framebuffer, status {:#x}", width, height, status));
+        }
+        Ok(framebuffer)
+    }
+
+    /* binds it and sets the viewport to its size */
+    pub fn bind(&self) {
+        unsafe {
+            glBindFramebuffer(GL_FRAMEBUFFER, self.fbo);
+            glViewport(0, 0, self.width as GLsizei, self.height as GLsizei);
+        }
+    }
+
+    /* back to drawing into the window */
+    pub fn clear_binding() {
+        unsafe { glBindFramebuffer(GL_FRAMEBUFFER, 0) }
+    }
+
+    pub fn delete(&self) {
+        unsafe {
+            glDeleteRenderbuffers(1, &self.color);
+            glDeleteRenderbuffers(1, &self.depth);
+            glDeleteFramebuffers(1, &self.fbo);
+        }
+    }
+}
+
+/* a framebuffer with only a depth texture, what shadow maps are rendered into.
+Reads outside the texture give the far plane, so nothing out of the light's view is in shadow */
+pub struct ShadowMap {
+    pub fbo: GLuint,
+    pub depth: Texture,
+    pub size: u32,
+}
+impl ShadowMap {
+    pub fn new(size: u32, texture_unit: GLenum) -> Result<Self, String> {
+        let depth = Texture::new(texture_unit).ok_or_else(|| "Couldn't make a new texture".to_string())?;
+        let mut fbo = 0;
+        depth.activate_and_bind();
+        unsafe {
+            glTexImage2D(
+                GL_TEXTURE_2D, 0, GL_DEPTH_COMPONENT24 as GLint,
+                size as GLsizei, size as GLsizei, 0, GL_DEPTH_COMPONENT,
+                GL_FLOAT, std::ptr::null()
+            );
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, GL_NEAREST as GLint);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_NEAREST as GLint);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_BORDER as GLint);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_BORDER as GLint);
+            let border = [1.0_f32; 4];
+            glTexParameterfv(GL_TEXTURE_2D, GL_TEXTURE_BORDER_COLOR, border.as_ptr());
+
+            glGenFramebuffers(1, &mut fbo);
+            glBindFramebuffer(GL_FRAMEBUFFER, fbo);
+            glFramebufferTexture2D(GL_FRAMEBUFFER, GL_DEPTH_ATTACHMENT, GL_TEXTURE_2D, depth.0, 0);
+            glDrawBuffer(GL_NONE);
+            glReadBuffer(GL_NONE);
+        }
+        let shadow_map = Self { fbo, depth, size };
+        let status = unsafe { glCheckFramebufferStatus(GL_FRAMEBUFFER) };
+        Framebuffer::clear_binding();
+        if fbo == 0 || status != GL_FRAMEBUFFER_COMPLETE {
+            shadow_map.delete();
+            return Err(format!("Could not create a {}x{} shadow map, status {:#x}", size, size, status));
+        }
+        Ok(shadow_map)
+    }
+
+    /* binds it for drawing and sets the viewport to its size */
+    pub fn bind(&self) {
+        unsafe {
+            glBindFramebuffer(GL_FRAMEBUFFER, self.fbo);
+            glViewport(0, 0, self.size as GLsizei, self.size as GLsizei);
+        }
+    }
+
+    pub fn delete(&self) {
+        unsafe {
+            glDeleteTextures(1, &self.depth.0);
+            glDeleteFramebuffers(1, &self.fbo);
+        }
+    }
+}
+
+/* a framebuffer drawing into a half float color texture, what post-processing passes read
+the frame from. Lighting can go above 1 in it until tone mapping brings it back.
+Only the scene's target needs a depth renderbuffer, the passes draw without depth */
+pub struct RenderTexture {
+    pub fbo: GLuint,
+    pub color: Texture,
+    /* 0 without one */
+    pub depth: GLuint,
+    pub width: u32,
+    pub height: u32,
 }
+impl RenderTexture {
+    pub fn new(width: u32, height: u32, with_depth: bool) -> Result<Self, String> {
+        let color = Texture::new(GL_TEXTURE0).ok_or_else(|| "Couldn't make a new texture".to_string())?;
+        let (mut fbo, mut depth) = (0, 0);
+        color.activate_and_bind();
+        unsafe {
+            glTexImage2D(
+                GL_TEXTURE_2D, 0, GL_RGBA16F as GLint,
+                width as GLsizei, height as GLsizei, 0, GL_RGBA,
+                GL_FLOAT, std::ptr::null()
+            );
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, GL_LINEAR as GLint);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_LINEAR as GLint);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_EDGE as GLint);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_EDGE as GLint);
+
+            glGenFramebuffers(1, &mut fbo);
+            glBindFramebuffer(GL_FRAMEBUFFER, fbo);
+            glFramebufferTexture2D(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_TEXTURE_2D, color.0, 0);
+            if with_depth {
+                glGenRenderbuffers(1, &mut depth);
+                glBindRenderbuffer(GL_RENDERBUFFER, depth);
+                glRenderbufferStorage(GL_RENDERBUFFER, GL_DEPTH24_STENCIL8, width as GLsizei, height as GLsizei);
+                glFramebufferRenderbuffer(GL_FRAMEBUFFER, GL_DEPTH_STENCIL_ATTACHMENT, GL_RENDERBUFFER, depth);
+                glBindRenderbuffer(GL_RENDERBUFFER, 0);
+            }
+        }
+        let render_texture = Self { fbo, color, depth, width, height };
+        let status = unsafe { glCheckFramebufferStatus(GL_FRAMEBUFFER) };
+        Framebuffer::clear_binding();
+        if fbo == 0 || status != GL_FRAMEBUFFER_COMPLETE {
+            render_texture.delete();
+            return Err(format!("Could not create a {}x{} render texture, status {:#x}", width, height, status));
+        }
+        Ok(render_texture)
+    }
 
-pub fn param_color_program<'a>(
-    base_folder: &'a str,
-    shader_folder: &'a str,
-    optical_density: f32,
-    ambient_color: &vec::Vec3,
-    diffuse_color: &vec::Vec3,
-    specular_color: &vec::Vec3,
-    dissolve: f32,
-    model: &mat::Mat4, 
-    view: &mat::Mat4, 
-    projection: &mat::Mat4
-) -> ShaderProgram {
-    let vert = format!("{}/{}/{}", base_folder, shader_folder, "vertex.GLSL");
-    let frag = format!("{}/{}/{}", base_folder, shader_folder, "fragment.GLSL");
-    let shader = ShaderProgram::from_files(&vert, &frag).unwrap();
-    let [v1, v2, v3] = *((*ambient_color).as_array());
-    let [v4, v5, v6] = *((*diffuse_color).as_array());
-    let [v7, v8, v9] = *((*specular_color).as_array());
-    shader.set_3_float(UNI_ID[UniEnum::AmbientColor as usize], v1, v2, v3);
-    shader.set_3_float(UNI_ID[UniEnum::DiffuseColor as usize], v4, v5, v6);
-    shader.set_3_float(UNI_ID[UniEnum::SpecularColor as usize], v7, v8, v9);
-    shader.set_1_float(UNI_ID[UniEnum::OpticalDensity as usize], optical_density);
-    shader.set_1_float(UNI_ID[UniEnum::Dissolve as usize], dissolve);
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Rotation as usize], mat::Mat4::identity().as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Model as usize], model.as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::View as usize], view.as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Projection as usize], projection.as_ptr().cast());
-    shader.set_3_float(UNI_ID[UniEnum::LightPos as usize], 0.0, 0.0, 0.0);
-    shader.set_3_float(UNI_ID[UniEnum::ViewPos as usize], 0.0, 0.0, 0.0);
-    shader
+    /* binds it for drawing and sets the viewport to its size */
+    pub fn bind(&self) {
+        unsafe {
+            glBindFramebuffer(GL_FRAMEBUFFER, self.fbo);
+            glViewport(0, 0, self.width as GLsizei, self.height as GLsizei);
+        }
+    }
+
+    pub fn delete(&self) {
+        unsafe {
+            glDeleteTextures(1, &self.color.0);
+            if self.depth != 0 {
+                glDeleteRenderbuffers(1, &self.depth);
+            }
+            glDeleteFramebuffers(1, &self.fbo);
+        }
+    }
 }
 
-pub fn texture_program<'a>(
-    base_folder: &'a str, 
-    shader_folder: &'a str,
-    texture: &'a Texture,
-    model: &mat::Mat4, 
-    view: &mat::Mat4, 
-    projection: &mat::Mat4
-) -> ShaderProgram {
-    let vert = format!("{}/{}/{}", base_folder, shader_folder, "vertex.GLSL");
-    let frag = format!("{}/{}/{}", base_folder, shader_folder, "fragment.GLSL");
-    let shader = ShaderProgram::from_files_with_texture(
-        &vert, 
-        &frag,
-        &texture,
-        UNI_ID[UniEnum::Texture as usize]
-    ).unwrap();
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Rotation as usize], mat::Mat4::identity().as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Model as usize], model.as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::View as usize], view.as_ptr().cast());
-    shader.set_4_float_matrix(UNI_ID[UniEnum::Projection as usize], projection.as_ptr().cast());
-    shader
+/* reads the bound framebuffer back, flipped so the first row is the top of the image */
+pub fn read_pixels(width: u32, height: u32) -> image::RgbaImage {
+    let mut pixels = vec![0_u8; (width * height * 4) as usize];
+    unsafe {
+        glPixelStorei(GL_PACK_ALIGNMENT, 1);
+        glReadPixels(0, 0, width as GLsizei, height as GLsizei, GL_RGBA, GL_UNSIGNED_BYTE, pixels.as_mut_ptr().cast());
+    }
+    let mut image = image::RgbaImage::from_raw(width, height, pixels).expect("pixel buffer has the image's size");
+    image::imageops::flip_vertical_in_place(&mut image);
+    image
 }
 
 pub type Vertex = [f32; 3];
 pub type TexelVertex = [f32; 3 + 2];
 pub type NormalVertex = [f32; 3 + 3];
+/* position, normal and texture coordinates, the layout of MeshData::point_data */
+pub type ModelVertex = [f32; 3 + 3 + 2];
 
 pub struct Mesh(pub Vec<TexelVertex>);
+impl Default for Mesh {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Mesh {
     pub fn new() -> Mesh {
         Self(Vec::new())
@@ -791,17 +1127,23 @@ impl Mesh {
     }
 }
 
+/* interleaves the model's vertices as ModelVertex, meshes without normals
+or texture coordinates get zeros for them */
 pub fn combine_loaded_data<'a> (
     loaded_data: &'a Model,
 ) -> Vec<f32> {
-    let mut output_vec = Vec::new();
-    let num = loaded_data.mesh.positions.len();
+    let mesh = &loaded_data.mesh;
+    let num = mesh.positions.len() / 3;
+    let mut output_vec = Vec::with_capacity(num * 8);
     for i in 0..num {
-        output_vec.push(loaded_data.mesh.positions[i]);
-        if i % 3 == 2 {
-            for j in (i-2)..=(i) {
-                output_vec.push((*loaded_data).mesh.normals[j]);
-            }
+        output_vec.extend_from_slice(&mesh.positions[i * 3..i * 3 + 3]);
+        match mesh.normals.get(i * 3..i * 3 + 3) {
+            Some(normal) => output_vec.extend_from_slice(normal),
+            None => output_vec.extend_from_slice(&[0.0; 3]),
+        }
+        match mesh.texcoords.get(i * 2..i * 2 + 2) {
+            Some(uv) => output_vec.extend_from_slice(uv),
+            None => output_vec.extend_from_slice(&[0.0; 2]),
         }
     }
 
@@ -847,8 +1189,8 @@ pub fn init_sdl() -> SDL {
     return sdl;
 }
 
-pub struct LoopContext<'a, T> {
-    pub go: &'a mut GameObject<T>, 
+pub struct LoopContext<'a> {
+    pub go: &'a mut GameObject, 
     pub keys_held: &'a HashSet<Keycode>,
     pub mouse_deltas: &'a (f32, f32), 
     pub camera: &'a mut CameraParams,
@@ -858,105 +1200,330 @@ pub struct LoopContext<'a, T> {
     pub collider_set: &'a mut ColliderSet,
     pub floor_set: &'a mut HashSet<RigidBodyHandle>,
     pub model_map: &'a HashMap<&'a str, usize>,
-    pub game_obj_store: &'a GameObjectStore<T>
+    pub game_obj_store: &'a GameObjectStore,
+    /* set to a scene name to switch scenes at the end of the frame */
+    pub next_scene: &'a mut Option<String>,
+    /* (child, new parent) changes applied at the end of the frame */
+    pub reparents: &'a mut Vec<(GameObjectID, Option<GameObjectID>)>,
 }
 
-pub struct Context<T> {
+/* everything rapier needs to step the simulation besides the body and collider sets,
+kept on the Context so it can be snapshotted and reset along with the rest of the world */
+#[derive(Serialize, Deserialize)]
+pub struct PhysicsState {
+    pub gravity: Vector<Real>,
+    pub integration_parameters: IntegrationParameters,
+    pub island_manager: IslandManager,
+    pub broad_phase: BroadPhase,
+    pub narrow_phase: NarrowPhase,
+    pub impulse_joint_set: ImpulseJointSet,
+    pub multibody_joint_set: MultibodyJointSet,
+    pub ccd_solver: CCDSolver,
+    #[serde(default)]
+    pub collisions: CollisionTracker,
+    #[serde(default)]
+    pub timestep: FixedTimestep,
+}
+impl Default for PhysicsState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PhysicsState {
+    pub fn new() -> Self {
+        Self {
+            gravity: vector![0.0, -9.81],
+            integration_parameters: IntegrationParameters::default(),
+            island_manager: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            impulse_joint_set: ImpulseJointSet::new(),
+            multibody_joint_set: MultibodyJointSet::new(),
+            ccd_solver: CCDSolver::new(),
+            collisions: CollisionTracker::new(),
+            timestep: FixedTimestep::default(),
+        }
+    }
+}
+
+/* Physics and behaviors advance in steps of a fixed length no matter the frame rate, so a jump
+is as high at 30 FPS as at 300. Frame time piles up in the accumulator and every whole step in it
+is simulated. When a frame took so long that more than max_steps are due the rest is dropped and
+the game slows down instead of spending ever longer frames catching up. What is left over is how
+far the frame is between the last two steps, rendering interpolates rigid bodies by that much. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixedTimestep {
+    /* seconds per step, also the dt behaviors get */
+    pub step: f32,
+    pub max_steps: u32,
+    accumulator: f32,
+    /* steps simulated so far, game time is counted in these so it is the same every run */
+    steps_done: u64,
+}
+impl FixedTimestep {
+    pub fn new(step: f32, max_steps: u32) -> Self {
+        Self { step, max_steps, accumulator: 0.0, steps_done: 0 }
+    }
+
+    /* adds a frame's worth of time and returns how many steps to simulate for it */
+    pub fn advance(&mut self, frame_secs: f32) -> u32 {
+        self.accumulator += frame_secs;
+        let due = (self.accumulator / self.step) as u32;
+        if due > self.max_steps {
+            self.accumulator %= self.step;
+            self.max_steps
+        } else {
+            self.accumulator = (self.accumulator - due as f32 * self.step).max(0.0);
+            due
+        }
+    }
+
+    pub fn count_step(&mut self) {
+        self.steps_done += 1;
+    }
+
+    /* simulated seconds since the scene started */
+    pub fn time(&self) -> f32 {
+        (self.steps_done as f64 * self.step as f64) as f32
+    }
+
+    /* how far the frame is between the previous step and the last one, 0 to 1 */
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.step).min(1.0)
+    }
+}
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        Self::new(1.0 / 60.0, 5)
+    }
+}
+
+/* a scene's hook to set uniforms for each object right before it's drawn. Objects can't share
+instanced draws while a scene has one, so it costs a draw call per object on GL */
+pub type PreDraw = Box<dyn Fn(&ShaderProgram, &DrawableObject)>;
+
+/* the SDL instance and the window with its GL context */
+pub struct Display {
     pub sdl: SDL,
     pub window: GlWindow,
+}
+
+pub struct Context {
+    /* None for headless contexts, which have no window and never touch GL */
+    pub display: Option<Display>,
+    /* GL without a window for offscreen contexts, see Context::offscreen */
+    pub surfaceless: Option<SurfacelessGl>,
+    /* size of the window or offscreen image frames are drawn at */
+    pub viewport_size: (u32, u32),
     pub camera: CameraParams,
-    pub shader_folder_path: String,
-    pub param_blinn_phong_shader_folder: String,
-    pub shaders: Vec<ShaderProgram>,
-    pub shader_map: HashMap<String, usize>,
-    pub drawable_groups: Vec<DrawableGroup>,
+    /* None for headless contexts, see render.rs */
+    pub renderer: Option<Box<dyn RenderBackend>>,
+    /* CPU side copy of every loaded texture, material and model, uploaded to the renderer in the same order */
+    pub textures: Vec<TextureData>,
+    /* keyed by path, so a texture several materials use is loaded once */
+    pub texture_map: HashMap<String, usize>,
+    pub materials: Vec<Material>,
+    /* keyed by name, the last material added under a name wins */
+    pub material_map: HashMap<String, usize>,
+    pub meshes: Vec<MeshDataGroup>,
+    /* around every mesh of each model in meshes, see bounds.rs */
+    pub mesh_bounds: Vec<Bounds>,
+    pub render_stats: RenderStats,
+    pub clear_color: [f32; 4],
+    /* skyboxes, keyed by where they were loaded from like textures */
+    pub cubemaps: Vec<CubemapData>,
+    pub cubemap_map: HashMap<String, usize>,
+    /* drawn behind the scene and reflected by it, see environment.rs */
+    pub environment: Option<Environment>,
+    /* fullscreen passes over every frame, in order, see post_process.rs */
+    pub post_process: Vec<PostPass>,
     pub rigid_body_set: RigidBodySet,
     pub collider_set: ColliderSet,
     pub floor_set: HashSet<RigidBodyHandle>,
-    pub game_obj_store: GameObjectStore<T>,
-    pub pre_draw: Box<dyn Fn(&ShaderProgram, &DrawableObject)>,
-    pub handleit: Box<dyn Fn(&mut LoopContext<T>) -> (Vec<GameObjectID>, Vec<GameObject<T>>)>,
-    pub handlecollisionit: Box<dyn Fn(&mut LoopContext<T>, &RefCell<GameObject<T>>) -> (Vec<GameObjectID>, Vec<GameObject<T>>)>,
+    pub physics: PhysicsState,
+    pub game_obj_store: GameObjectStore,
+    pub behavior_registry: BehaviorRegistry,
+    pub scenes: SceneRegistry,
+    pub current_scene: Option<String>,
+    pub next_scene: Option<String>,
+    pub reparents: Vec<(GameObjectID, Option<GameObjectID>)>,
+    /* None for scenes without one, see PreDraw */
+    pub pre_draw: Option<PreDraw>,
 }
-impl<T> Context<T> {
+impl Context {
     pub fn new(window_width: u32, window_height: u32) -> Result<Self, String> {
-        let def_shader_folder_path = String::from_str("src/shaders").expect("string failed");
-        let def_param_blinn_phong_shader_folder = String::from_str("param_blinn_phong_shader").expect("string failed");
-
         let sdl = init_sdl();
+        let window = sdl.create_gl_window("OpenGL", WindowPosition::Centered, window_width, window_height, WindowFlags::Shown)?;
+        let mut ctx = Self::with_display(Some(Display { sdl, window }), window_width, window_height);
+
+        /* set vsync on to block program until rendered screen has been shown */
+        // ctx.window.set_swap_interval(SwapInterval::Vsync);
+        ctx.init_ogl();
+        ctx.renderer = Some(Box::new(GlBackend::new(window_width, window_height)?));
+
+        Ok(ctx)
+    }
+    /* a context without SDL, window or renderer for tests and batch simulations.
+    Models load CPU side only and main_loop can't run it, step it with a HeadlessRun instead */
+    pub fn headless(window_width: u32, window_height: u32) -> Self {
+        Self::with_display(None, window_width, window_height)
+    }
+    /* a headless context that can still render, into images with render_to_image,
+    using a surfaceless EGL context. Made for golden-image tests on machines without a display */
+    pub fn offscreen(width: u32, height: u32) -> Result<Self, String> {
+        let mut ctx = Self::with_display(None, width, height);
+        ctx.surfaceless = Some(SurfacelessGl::new()?);
+        ctx.init_ogl();
+        ctx.renderer = Some(Box::new(GlBackend::new(width, height)?));
+        Ok(ctx)
+    }
+    /* a headless context that renders into images on the CPU, needs no GPU or GL driver at all */
+    pub fn software(width: u32, height: u32) -> Self {
+        let mut ctx = Self::with_display(None, width, height);
+        ctx.renderer = Some(Box::new(SoftwareBackend::new(width, height)));
+        ctx
+    }
+    fn with_display(display: Option<Display>, window_width: u32, window_height: u32) -> Self {
         let camera = CameraParams::new(
             vec::Vec3::zero(),
             vec::Vec3::new(0.0, 0.0, 90.0),
             vec::Vec3::new(0.0, 10.0, -10.0),
             projection::perspective_gl(45.0_f32, (window_width as f32) / (window_height as f32), 0.1, 100.0)
         );
-        
-        let window = sdl.create_gl_window("OpenGL", WindowPosition::Centered, window_width, window_height, WindowFlags::Shown);
-        match window {
-            Ok(window) => {
-                let ctx = Context{
-                    sdl,
-                    window,
-                    camera,
-                    shader_folder_path: def_shader_folder_path,
-                    param_blinn_phong_shader_folder: def_param_blinn_phong_shader_folder,
-                    shaders: vec![],
-                    shader_map: HashMap::new(),
-                    // meshes: vec![],
-                    drawable_groups: vec![],
-                    rigid_body_set: RigidBodySet::new(),
-                    collider_set: ColliderSet::new(),
-                    floor_set: HashSet::new(),
-                    game_obj_store: GameObjectStore::new(),
-                    pre_draw: Box::new(move |_shader: &ShaderProgram, _draw: &DrawableObject| {}),
-                    handleit: Box::new(move |_loop_context: &mut LoopContext<T>| -> (Vec<GameObjectID>, Vec<GameObject<T>>) {(vec![], vec![])}),
-                    handlecollisionit: Box::new(move |_loop_context: &mut LoopContext<T>, _other: &RefCell<GameObject<T>>| -> (Vec<GameObjectID>, Vec<GameObject<T>>) {(vec![], vec![])})
-                };
-                
-                /* set vsync on to block program until rendered screen has been shown */
-                // ctx.window.set_swap_interval(SwapInterval::Vsync);
-                ctx.init_ogl();
-                
-                Ok(ctx)
-            },
-            Err(e) => {
-                Err(e)
-            }
+
+        Context{
+            display,
+            surfaceless: None,
+            viewport_size: (window_width, window_height),
+            camera,
+            renderer: None,
+            textures: vec![],
+            texture_map: HashMap::new(),
+            materials: vec![],
+            material_map: HashMap::new(),
+            meshes: vec![],
+            mesh_bounds: vec![],
+            render_stats: RenderStats::default(),
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            cubemaps: vec![],
+            cubemap_map: HashMap::new(),
+            environment: None,
+            post_process: vec![],
+            rigid_body_set: RigidBodySet::new(),
+            collider_set: ColliderSet::new(),
+            floor_set: HashSet::new(),
+            physics: PhysicsState::new(),
+            game_obj_store: GameObjectStore::new(),
+            behavior_registry: default_behavior_registry(),
+            scenes: SceneRegistry::new(),
+            current_scene: None,
+            next_scene: None,
+            reparents: vec![],
+            pre_draw: None,
         }
     }
+    pub fn is_headless(&self) -> bool {
+        self.display.is_none()
+    }
     pub fn init_ogl(&self) {
         unsafe {
-            load_gl_with(|f_name| self.window.get_proc_address(f_name));
+            match (&self.display, &self.surfaceless) {
+                (Some(display), _) => load_gl_with(|f_name| display.window.get_proc_address(f_name)),
+                (None, Some(surfaceless)) => load_gl_with(|f_name| surfaceless.get_proc_address(f_name)),
+                (None, None) => return,
+            }
     
             glEnable(GL_DEPTH_TEST);
+            /* filter across the edges of cube map faces, skyboxes show seams otherwise */
+            glEnable(GL_TEXTURE_CUBE_MAP_SEAMLESS);
         }
+        ShaderProgram::forget_current();
         
         clear_color(0.0, 0.0, 0.0, 1.0);
     }
+    /* loads the image once per path and hands it to the renderer, the index is for MaterialParams' maps */
+    pub fn load_texture(&mut self, path: &str) -> Result<usize, String> {
+        if let Some(texture_idx) = self.texture_map.get(path) {
+            return Ok(*texture_idx);
+        }
+        let texture = TextureData::from_file(path)?;
+        if let Some(renderer) = &mut self.renderer {
+            renderer.upload_texture(&texture)?;
+        }
+        let texture_idx = self.textures.len();
+        self.texture_map.insert(path.to_string(), texture_idx);
+        self.textures.push(texture);
+        Ok(texture_idx)
+    }
+
+    /* loads the skybox once per source and face size and hands it to the renderer, the index is for Environment */
+    pub fn load_cubemap(&mut self, source: &SkyboxSource, face_size: Option<u32>) -> Result<usize, String> {
+        let key = format!("{:?} {:?}", source, face_size);
+        if let Some(cubemap_idx) = self.cubemap_map.get(&key) {
+            return Ok(*cubemap_idx);
+        }
+        let cubemap = CubemapData::load(source, face_size)?;
+        if let Some(renderer) = &mut self.renderer {
+            renderer.upload_cubemap(&cubemap)?;
+        }
+        let cubemap_idx = self.cubemaps.len();
+        self.cubemap_map.insert(key, cubemap_idx);
+        self.cubemaps.push(cubemap);
+        Ok(cubemap_idx)
+    }
+
+    /* makes the material available to models and overrides under its name. A material that has the
+    name of one already added replaces it, also for the meshes that were using the old one */
+    pub fn add_material(&mut self, material: Material) -> Result<usize, String> {
+        if let Some(material_idx) = self.material_map.get(&material.name).copied() {
+            if let Some(renderer) = &mut self.renderer {
+                renderer.replace_material(material_idx, &material)?;
+            }
+            self.materials[material_idx] = material;
+            return Ok(material_idx);
+        }
+        if let Some(renderer) = &mut self.renderer {
+            renderer.upload_material(&material)?;
+        }
+        let material_idx = self.materials.len();
+        self.material_map.insert(material.name.clone(), material_idx);
+        self.materials.push(material);
+        Ok(material_idx)
+    }
+    pub fn material_idx(&self, name: &str) -> Result<usize, String> {
+        self.material_map.get(name).copied().ok_or_else(|| format!("No material named {}", name))
+    }
+
+    /* loads the model's meshes and materials CPU side and hands them to the renderer if there is one.
+    Materials of the .mtl that have the name of one already added aren't loaded again */
     pub fn load_model(&mut self, model_path: &str) -> usize {
         let (models, _materials) = tobj::load_obj(model_path, &tobj::GPU_LOAD_OPTIONS).expect("Failed to load model");
         let mats = _materials.expect("Failed to read mtl when loading model materials");
 
+        /* texture paths in the .mtl are relative to the model's folder */
+        let model_folder = Path::new(model_path).parent().unwrap_or_else(|| Path::new(""));
         for mat in &mats {
-            let mat_name = (*mat).name.clone();
-            if !self.shader_map.contains_key(&mat_name) {
-                
-                let new_shader_idx = self.shaders.len();
-                
-                self.shaders.push(param_color_program(
-                    self.shader_folder_path.as_str(), 
-                    self.param_blinn_phong_shader_folder.as_str(), 
-                    (*mat).optical_density,
-                    &vec::Vec3::from((*mat).ambient),
-                    &vec::Vec3::from((*mat).diffuse),
-                    &vec::Vec3::from((*mat).specular),
-                    (*mat).dissolve,
-                    &mat::Mat4::identity(),
-                    &self.camera.view_matrix(),
-                    &self.camera.projection
-                ));
-
-                self.shader_map.insert(mat_name, new_shader_idx);
+            if !self.material_map.contains_key(&mat.name) {
+                let mut material = Material::from_mtl(mat);
+                for (file, map) in [
+                    (&mat.diffuse_texture, &mut material.diffuse_map),
+                    (&mat.specular_texture, &mut material.specular_map),
+                    (&mat.normal_texture, &mut material.bump_map),
+                ] {
+                    if file.is_empty() {
+                        continue;
+                    }
+                    let path = model_folder.join(file);
+                    match self.load_texture(&path.to_string_lossy()) {
+                        Ok(texture_idx) => *map = Some(texture_idx),
+                        Err(e) => warn!("Material {} goes without a texture: {}", mat.name, e),
+                    }
+                }
+                /* see-through texels of the diffuse map are cut out */
+                if material.diffuse_map.is_some_and(|texture_idx| self.textures[texture_idx].has_transparency()) {
+                    material.alpha_cutoff = Some(0.5);
+                }
+                self.add_material(material).expect("Failed to upload material");
             }
         }
 
@@ -969,131 +1536,273 @@ impl<T> Context<T> {
                 point_data: combine_loaded_data(&model), 
                 point_indices: model.mesh.indices.clone(), 
                 tri_count: tris,
-                shader_idx: self.shader_map[mats[mat_id].name.as_str()]
+                material_idx: self.material_map[mats[mat_id].name.as_str()],
+                bounds: Bounds::from_positions(&model.mesh.positions, 3)
             });
         }
-        // let mesh_id = self.meshes.len();
+        self.mesh_bounds.push(mesh_data_group.iter()
+            .map(|mesh| mesh.bounds)
+            .reduce(|bounds, mesh_bounds| bounds.union(&mesh_bounds))
+            .unwrap_or_else(|| Bounds::from_points(std::iter::empty())));
+        let mesh_id = self.meshes.len();
         let mesh_data = MeshDataGroup(mesh_data_group);
 
-        let mut drawable_group = vec![];
-        for mesh in mesh_data.0 {
-            /* generate a Vertex Array Object and store ref in mutable vao variable
-            and bind the VAO making it the active */
-            let (vao, vbo, ebo) = {
-                let vao = VertexArray::new().expect("Couldn't make a new VAO");
-                vao.bind();
-            
-                /* generate a Buffer Object and store req in mutable vbo variable
-                and set the given buffer as the current active Array Buffer 
-                and then initializes target's active buffer's storage with a size and initial data and a hint to its usage
-                and then initialize active Array Buffer with size of vertex array and pointer to vertex array */
-                let vbo = {
-                    let vbo = Buffer::new().expect("Couldn't make a new buffer");
-                    vbo.bind(BufferType::Array);
-                    // buffer_data(BufferType::Array, bytemuck::cast_slice(&vertices), GL_STATIC_DRAW);
-                    buffer_data(BufferType::Array, bytemuck::cast_slice(mesh.point_data.as_slice()), GL_STATIC_DRAW);
-                    vbo
-                    };
-
-                /* generate buffer to hold groups of vertexes that form triangles
-                set as the active element array buffer type
-                load in the data */
-                let ebo = {
-                    let ebo = Buffer::new().expect("Couldn't make a new buffer");
-                    ebo.bind(BufferType::ElementArray);
-                    buffer_data(BufferType::ElementArray, bytemuck::cast_slice(mesh.point_indices.as_slice()), GL_STATIC_DRAW);
-                    ebo
-                    };
-
-                unsafe {
-                    glVertexAttribPointer(
-                        0,
-                        3,
-                        GL_FLOAT,
-                        GL_TRUE,
-                        size_of::<NormalVertex>().try_into().unwrap(),
-                        size_of::<[f32; 0]>() as *const _,
-                    );
-                    glEnableVertexAttribArray(0);
-                    glVertexAttribPointer(
-                        1,
-                        3,
-                        GL_FLOAT,
-                        GL_FALSE,
-                        size_of::<NormalVertex>().try_into().unwrap(),
-                        (size_of::<f32>() * 3) as *const _,
+        if let Some(renderer) = &mut self.renderer {
+            renderer.upload_mesh(&mesh_data).expect("Failed to upload model");
+        }
+        self.meshes.push(mesh_data);
+
+        mesh_id
+    }
+}
+
+// pub fn 
+
+/* builds the LoopContext handed to behavior hooks out of the Context's fields and the frame's input */
+macro_rules! loop_context {
+    ($ctx:expr, $go:expr, $keys_held:expr, $mouse_deltas:expr, $deltasecs:expr, $game_time:expr, $model_map:expr) => {
+        LoopContext{
+            go: $go, 
+            keys_held: $keys_held, 
+            mouse_deltas: $mouse_deltas, 
+            camera: &mut $ctx.camera, 
+            deltasecs: $deltasecs, 
+            game_time: $game_time, 
+            rigid_body_set: &mut $ctx.rigid_body_set, 
+            collider_set: &mut $ctx.collider_set, 
+            floor_set: &mut $ctx.floor_set,
+            model_map: $model_map,
+            game_obj_store: &$ctx.game_obj_store,
+            next_scene: &mut $ctx.next_scene,
+            reparents: &mut $ctx.reparents,
+        }
+    };
+}
+
+/* runs the updates of one Stage for every object, see Stage for the order */
+fn run_stage(
+    ctx: &mut Context,
+    stage: Stage,
+    keys_held: &HashSet<Keycode>,
+    mouse_deltas: &(f32, f32),
+    deltasecs: f32,
+    game_time: f32,
+    model_map: &HashMap<&str, usize>,
+) -> BehaviorOutput {
+    let mut objs_to_remove = vec![];
+    let mut objs_to_add = vec![];
+    for (priority, ids) in stage_schedule(&ctx.game_obj_store, stage) {
+        for id in ids {
+            if let Some(mut go) = ctx.game_obj_store.checkout(&id) {
+                let mut loop_ctx = loop_context!(ctx, &mut go, keys_held, mouse_deltas, deltasecs, game_time, model_map);
+                let (mut to_remove, mut to_add) = apply_stage_behaviors(&mut loop_ctx, stage, priority);
+                objs_to_remove.append(&mut to_remove);
+                objs_to_add.append(&mut to_add);
+                ctx.game_obj_store.checkin(go);
+            }
+        }
+    }
+    (objs_to_remove, objs_to_add)
+}
+
+/* the rapier pipeline and the channel its collision events come through,
+kept across steps by whatever drives the simulation (main_loop or a HeadlessRun) */
+pub struct Simulation {
+    pipeline: PhysicsPipeline,
+    event_handler: ChannelEventCollector,
+    collision_recv: crossbeam::channel::Receiver<CollisionEvent>,
+}
+impl Simulation {
+    pub fn new() -> Self {
+        let (collision_send, collision_recv) = crossbeam::channel::unbounded();
+        Self {
+            pipeline: PhysicsPipeline::new(),
+            event_handler: ChannelEventCollector::new(collision_send),
+            collision_recv,
+        }
+    }
+
+    /* runs one fixed step of the world, see Stage for the order things happen in */
+    pub fn step(
+        &mut self,
+        ctx: &mut Context,
+        keys_held: &HashSet<Keycode>,
+        mouse_deltas: &(f32, f32),
+        model_map: &HashMap<&str, usize>,
+    ) {
+        let deltasecs = ctx.physics.timestep.step;
+        let game_time = ctx.physics.timestep.time();
+
+        let mut objs_to_remove = vec![];
+        let mut objs_to_add = vec![];
+
+        for id in ctx.game_obj_store.take_newly_added() {
+            if let Some(mut go) = ctx.game_obj_store.checkout(&id) {
+                let mut loop_ctx = loop_context!(ctx, &mut go, keys_held, mouse_deltas, deltasecs, game_time, model_map);
+                let (mut to_remove, mut to_add) = apply_spawn_behaviors(&mut loop_ctx);
+                objs_to_remove.append(&mut to_remove);
+                objs_to_add.append(&mut to_add);
+                ctx.game_obj_store.checkin(go);
+            }
+        }
+
+        let (mut to_remove, mut to_add) = run_stage(ctx, Stage::PrePhysics, keys_held, mouse_deltas, deltasecs, game_time, model_map);
+        objs_to_remove.append(&mut to_remove);
+        objs_to_add.append(&mut to_add);
+
+        ctx.physics.integration_parameters.dt = deltasecs;
+        let physics_hooks = ();
+
+        self.pipeline.step(
+            &ctx.physics.gravity,
+            &ctx.physics.integration_parameters,
+            &mut ctx.physics.island_manager,
+            &mut ctx.physics.broad_phase,
+            &mut ctx.physics.narrow_phase,
+            &mut ctx.rigid_body_set,
+            &mut ctx.collider_set,
+            &mut ctx.physics.impulse_joint_set,
+            &mut ctx.physics.multibody_joint_set,
+            &mut ctx.physics.ccd_solver,
+            &physics_hooks,
+            &self.event_handler,
+        );
+        ctx.game_obj_store.sync_from_physics(&ctx.rigid_body_set);
+
+        let collision_events: Vec<CollisionEvent> = self.collision_recv.try_iter().collect();
+        let collisions = ctx.physics.collisions.update(
+            collision_events,
+            &ctx.collider_set,
+            &ctx.physics.narrow_phase,
+            &ctx.game_obj_store
+        );
+        let mut collisions_by_object: BTreeMap<GameObjectID, Vec<CollisionInfo>> = BTreeMap::new();
+        for (id, info) in collisions {
+            collisions_by_object.entry(id).or_default().push(info);
+        }
+        for (id, collisions) in collisions_by_object {
+            if let Some(mut go) = ctx.game_obj_store.checkout(&id) {
+                let mut loop_ctx = loop_context!(ctx, &mut go, keys_held, mouse_deltas, deltasecs, game_time, model_map);
+                for info in &collisions {
+                    let (mut to_remove, mut to_add) = apply_collision_behaviors(&mut loop_ctx, info);
+                    objs_to_remove.append(&mut to_remove);
+                    objs_to_add.append(&mut to_add);
+                }
+                ctx.game_obj_store.checkin(go);
+            }
+        }
+
+        let (mut to_remove, mut to_add) = run_stage(ctx, Stage::PostPhysics, keys_held, mouse_deltas, deltasecs, game_time, model_map);
+        objs_to_remove.append(&mut to_remove);
+        objs_to_add.append(&mut to_add);
+
+        /* despawn hooks can remove or add more objects themselves */
+        let mut removal_queue = objs_to_remove;
+        while let Some(i) = removal_queue.pop() {
+            if let Some(mut go) = ctx.game_obj_store.checkout(&i) {
+                let (mut to_remove, mut to_add) = {
+                    let mut loop_ctx = loop_context!(ctx, &mut go, keys_held, mouse_deltas, deltasecs, game_time, model_map);
+                    apply_despawn_behaviors(&mut loop_ctx)
+                };
+                ctx.game_obj_store.checkin(go);
+                let removed = match ctx.game_obj_store.remove(&i) {
+                    Some(removed) => removed,
+                    None => continue,
+                };
+                /* despawning a parent despawns its whole subtree */
+                removal_queue.extend(removed.children.iter().copied());
+                /* removing the body lets rapier report the end of its contacts next step */
+                if let Some(rb_handle) = removed.rigid_body_handle {
+                    ctx.floor_set.remove(&rb_handle);
+                    ctx.rigid_body_set.remove(
+                        rb_handle,
+                        &mut ctx.physics.island_manager,
+                        &mut ctx.collider_set,
+                        &mut ctx.physics.impulse_joint_set,
+                        &mut ctx.physics.multibody_joint_set,
+                        true
                     );
-                    glEnableVertexAttribArray(1);
                 }
+                removal_queue.append(&mut to_remove);
+                objs_to_add.append(&mut to_add);
+            }
+        }
 
-                (vao, vbo, ebo)
-            };
-            drawable_group.push(Drawable{
-                vao, 
-                vbo, 
-                ebo, 
-                tri_count: mesh.tri_count, 
-                shader_idx: mesh.shader_idx
-            });
+        for obj in objs_to_add {
+            ctx.game_obj_store.add(obj);
         }
-        let draw_id = self.drawable_groups.len();
-        self.drawable_groups.push(DrawableGroup(drawable_group));
 
-        draw_id
+        for (child, parent) in std::mem::take(&mut ctx.reparents) {
+            if let Err(e) = ctx.game_obj_store.set_parent(&child, parent) {
+                warn!(target: LT_MAIN_LOOP, "{}", e);
+            }
+        }
+
+        ctx.physics.timestep.count_step();
+    }
+}
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-// pub fn 
+pub const SCREENSHOT_FOLDER: &str = "screenshots";
+pub const QUICKSAVE_PATH: &str = "snapshots/quicksave.ron";
 
-pub fn main_loop<T> (ctx: &mut Context<T>, model_map: &HashMap<&str, usize>) {
+pub fn main_loop(ctx: &mut Context, model_map: &HashMap<&str, usize>) {
     init_log().expect("");
     info!(target: LT_MAIN_LOOP, "main_loop function called");
     let mut _rng = rand::thread_rng();
     
+    /* headless contexts have nothing to show the loop on, they are stepped with a HeadlessRun */
+    if ctx.is_headless() {
+        warn!(target: LT_MAIN_LOOP, "main_loop needs a window, the context is headless");
+        return;
+    }
+
     /* Physics Config */
-    let gravity = vector![0.0, -9.81];
-    let mut integration_parameters = IntegrationParameters::default();
-    let mut physics_pipeline = PhysicsPipeline::new();
-    let mut island_manager = IslandManager::new();
-    let mut broad_phase = BroadPhase::new();
-    let mut narrow_phase = NarrowPhase::new();
-    let mut impulse_joint_set = ImpulseJointSet::new();
-    let mut multibody_joint_set = MultibodyJointSet::new();
-    let mut ccd_solver = CCDSolver::new();
-    let (collision_send, collision_recv) = crossbeam::channel::unbounded();
-    let event_handler = ChannelEventCollector::new(collision_send);
-    let physics_hooks = ();
+    let mut simulation = Simulation::new();
 
     /* mouse input config */
     const MOUSE_SENSITIVITY: f32 = 0.4;
-    ctx.sdl.set_relative_mouse_mode(true).unwrap();
+    if let Some(display) = &ctx.display {
+        display.sdl.set_relative_mouse_mode(true).unwrap();
+    }
 
     /* Keyboard input storage */
     let mut keys_held = HashSet::new();
+    let mut keys_pressed = HashSet::new();
+
+    /* snapshot of the freshly built scene used to reset the level */
+    let mut level_start_snapshot = save_snapshot(ctx).expect("Failed to snapshot level start");
 
     /* Time and FPS configuration */
     let mut deltatime = Duration::new(0, 0);
     let target_fps: f32 = 60.0;
     let target_frame_micros = (1000000_f32 / target_fps).ceil() as u64;
     let _target_frame_time = Duration::from_micros(target_frame_micros);
-    let start_instant = Instant::now();
     let mut update_view_lights = true;
 
     'main_loop: loop {
         let frame_start = Instant::now();
-        let deltasecs = deltatime.as_secs_f32();
-        let game_time = start_instant.elapsed().as_secs_f32();
+        let frame_secs = deltatime.as_secs_f32();
         let mut mouse_deltas = (0.0, 0.0);
+        keys_pressed.clear();
 
-        while let Some(event) = ctx.sdl.poll_events().and_then(Result::ok) {
+        while let Some(event) = ctx.display.as_ref().and_then(|display| display.sdl.poll_events().and_then(Result::ok)) {
             match event {
                 Event::Quit(_) => break 'main_loop,
                 Event::Keyboard(KeyboardEvent {
                     is_pressed,
+                    repeat,
                     key: KeyInfo {keycode, ..},
                     ..
                 }) => {
                     if is_pressed {
+                        if repeat == 0 {
+                            keys_pressed.insert(keycode);
+                        }
                         keys_held.insert(keycode);
                     } else {
                         keys_held.remove(&keycode);
@@ -1106,146 +1815,122 @@ pub fn main_loop<T> (ctx: &mut Context<T>, model_map: &HashMap<&str, usize>) {
             }
         }
 
-        integration_parameters.dt = deltasecs;
-
-        physics_pipeline.step(
-            &gravity,
-            &integration_parameters,
-            &mut island_manager,
-            &mut broad_phase,
-            &mut narrow_phase,
-            &mut ctx.rigid_body_set,
-            &mut ctx.collider_set,
-            &mut impulse_joint_set,
-            &mut multibody_joint_set,
-            &mut ccd_solver,
-            &physics_hooks,
-            &event_handler,
-        );
-
-        let mut collision_map_list: HashMap<RigidBodyHandle, Vec<RigidBodyHandle>> = HashMap::new();
-
-        while let Ok(collision_event) = collision_recv.try_recv() {
-            let (col1_rb, col2_rb) = (
-                get_rb_handle_from_collision_handle(&ctx.collider_set, &collision_event.collider1()), 
-                get_rb_handle_from_collision_handle(&ctx.collider_set, &collision_event.collider2())
-            );
-            if let Some(col1_rb) = col1_rb {
-                if let Some(col2_rb) = col2_rb {
-                    if !collision_map_list.contains_key(&col1_rb) {
-                        collision_map_list.insert(col1_rb, vec![]);
-                    }
-                    if !collision_map_list.contains_key(&col2_rb) {
-                        collision_map_list.insert(col2_rb, vec![]);
-                    }
-                    (*collision_map_list.get_mut(&col1_rb).unwrap()).push(col2_rb);
-                    (*collision_map_list.get_mut(&col2_rb).unwrap()).push(col1_rb);
-                }
+        /* F5 quicksave, F9 quickload, F6 reset the level to how it was when the loop started */
+        if keys_pressed.contains(&Keycode::F5) {
+            match write_snapshot_file(ctx, QUICKSAVE_PATH) {
+                Ok(()) => info!(target: LT_MAIN_LOOP, "saved snapshot to {}", QUICKSAVE_PATH),
+                Err(e) => warn!(target: LT_MAIN_LOOP, "{}", e),
             }
         }
-
-        let should_update_view = camera_controller(&keys_held, mouse_deltas, &mut ctx.camera, 5.0 * deltasecs);
-        // let should_update_view = true; 
-        update_view_lights = update_view_lights || should_update_view;
-        
-        /* draw vao verts */
-
-        // if update_view_lights {
-        let [v1, v2, v3] = *(ctx.camera.view_pos.as_array());
-        let [v4, v5, v6] = *(ctx.camera.light_position.as_array());
-        for shader in &ctx.shaders {
-            (*shader).set_3_float(UNI_ID[UniEnum::ViewPos as usize], v1, v2, v3);
-            (*shader).set_3_float(UNI_ID[UniEnum::LightPos as usize], v4, v5, v6);
-            (*shader).set_4_float_matrix(UNI_ID[UniEnum::View as usize], ctx.camera.view_matrix().as_ptr().cast());
+        if keys_pressed.contains(&Keycode::F9) {
+            match read_snapshot_file(ctx, QUICKSAVE_PATH) {
+                Ok(()) => info!(target: LT_MAIN_LOOP, "loaded snapshot from {}", QUICKSAVE_PATH),
+                Err(e) => warn!(target: LT_MAIN_LOOP, "{}", e),
+            }
         }
-            // update_view_lights = false;
-        // }
-
-        unsafe { glClear(GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT); }
-
-        let mut objs_to_remove = vec![];
-        let mut objs_to_add = vec![];
-
-        for obj in ctx.game_obj_store.0.values() {
-            let mut obj_bor = obj.borrow_mut();
-            let mut loop_ctx = LoopContext{
-                go: &mut obj_bor, 
-                keys_held: &keys_held, 
-                mouse_deltas: &mouse_deltas, 
-                camera: &mut ctx.camera, 
-                deltasecs, 
-                game_time, 
-                rigid_body_set: &mut ctx.rigid_body_set, 
-                collider_set: &mut ctx.collider_set, 
-                floor_set: &mut ctx.floor_set,
-                model_map,
-                game_obj_store: &ctx.game_obj_store
-            };
-            loop_ctx.go.physic_update(loop_ctx.rigid_body_set);
-
-            let mut has_rb_and_collisions = false;
-            if let Some(obj_rb_handle) = loop_ctx.go.rigid_body_handle {
-                if collision_map_list.contains_key(&obj_rb_handle) {
-                    has_rb_and_collisions = true;
-                }
+        if keys_pressed.contains(&Keycode::F6) {
+            match restore_snapshot(ctx, &level_start_snapshot) {
+                Ok(()) => info!(target: LT_MAIN_LOOP, "level reset"),
+                Err(e) => warn!(target: LT_MAIN_LOOP, "{}", e),
             }
-            if has_rb_and_collisions {
-                for rb_handle in collision_map_list.get(&loop_ctx.go.rigid_body_handle.unwrap()).unwrap() {
-                    if ctx.game_obj_store.1[rb_handle] == loop_ctx.go.id {
-                        // println!("self collide");
-                    } else {
-                        let other_go_id = ctx.game_obj_store.1[rb_handle];
-                        let other = &ctx.game_obj_store.0[&other_go_id];
-                        let (mut to_remove, mut to_add) = (ctx.handlecollisionit)(&mut loop_ctx, other);
-                        objs_to_remove.append(&mut to_remove);
-                        objs_to_add.append(&mut to_add);
-                    }
-                }
+        }
+        /* F2 cycles through the registered scenes */
+        if keys_pressed.contains(&Keycode::F2) {
+            let names = ctx.scenes.names();
+            if !names.is_empty() {
+                let current = ctx.current_scene.as_deref().and_then(|current| names.iter().position(|name| *name == current));
+                let next = current.map_or(0, |i| (i + 1) % names.len());
+                ctx.next_scene = Some(names[next].to_string());
             }
+        }
 
-            let (mut to_remove, mut to_add) = (ctx.handleit)(&mut loop_ctx);
-            objs_to_remove.append(&mut to_remove);
-            objs_to_add.append(&mut to_add);
-
-            let go_model_matrix = obj_bor.model_matrix();
-            let go_rotation_matrix = obj_bor.rotation_matrix();
-
-            if let Some( draw ) = &mut obj_bor.drawable_object {
+        let should_update_view = camera_controller(&keys_held, mouse_deltas, &mut ctx.camera, 5.0 * frame_secs);
+        // let should_update_view = true; 
+        update_view_lights = update_view_lights || should_update_view;
 
-                for drawable in &ctx.drawable_groups[draw.drawable_group_idx].0 {
+        /* the simulation runs in fixed steps however long the frame took, see FixedTimestep */
+        let steps = ctx.physics.timestep.advance(frame_secs);
+        for _ in 0..steps {
+            simulation.step(ctx, &keys_held, &mouse_deltas, model_map);
+        }
 
-                    let shader = &ctx.shaders[(*drawable).shader_idx];
-                    
-                    (*shader).use_program();
-                    (*shader).set_4_float_matrix(UNI_ID[UniEnum::Model as usize], (go_model_matrix * draw.model_matrix()).as_ptr().cast());
-                    (*shader).set_4_float_matrix(UNI_ID[UniEnum::Rotation as usize], (go_rotation_matrix * draw.rotation_matrix()).as_ptr().cast());
+        ctx.game_obj_store.update_world_transforms(ctx.physics.timestep.alpha());
 
-                    (ctx.pre_draw)(&shader, &draw);
+        render_frame(ctx);
 
-                    (*drawable).vao.bind();
-                    unsafe { glDrawElements(GL_TRIANGLES, (*drawable).tri_count as i32, GL_UNSIGNED_INT, 0 as *const _); }
-                }
+        /* F12 saves what was just drawn to the screenshots folder */
+        if keys_pressed.contains(&Keycode::F12) {
+            let path = format!("{}/screenshot-{}.png", SCREENSHOT_FOLDER, SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis()));
+            let screenshot = ctx.renderer.as_mut().ok_or_else(|| "No renderer to take a screenshot with".to_string())
+                .and_then(|renderer| renderer.read_pixels())
+                .and_then(|image| save_png(&image, &path));
+            match screenshot {
+                Ok(()) => info!(target: LT_MAIN_LOOP, "saved screenshot to {}", path),
+                Err(e) => warn!(target: LT_MAIN_LOOP, "{}", e),
             }
         }
 
-        for i in objs_to_remove {
-            ctx.game_obj_store.remove(&i);
-        }
-
-        for obj in objs_to_add {
-            ctx.game_obj_store.add(obj);
+        if let Some(scene_name) = ctx.next_scene.take() {
+            match switch_scene(ctx, model_map, &scene_name) {
+                Ok(()) => {
+                    info!(target: LT_MAIN_LOOP, "switched to scene {}", scene_name);
+                    level_start_snapshot = save_snapshot(ctx).expect("Failed to snapshot level start");
+                },
+                Err(e) => warn!(target: LT_MAIN_LOOP, "{}", e),
+            }
         }
 
         /* 2 buffers exist, draw buffer and display buffer
         draw buffer is where the next frame is being built piece by piece
         display buffer is what will be shown on the screen
         swap the draw and display buffer */
-        ctx.window.swap_window();
+        if let Some(display) = &ctx.display {
+            display.window.swap_window();
+        }
 
         // while frame_start.elapsed() < target_frame_time {}
         deltatime = frame_start.elapsed();
         if keys_held.contains(&Keycode::F) {
             println!("FPS {:?}", 1.0 / deltatime.as_secs_f32() );
+            let stats = ctx.render_stats;
+            info!(target: LT_MAIN_LOOP, "drawn {} of {} objects in {} batches and {} draws, {} culled", stats.drawn, stats.objects, stats.batches, stats.draws, stats.culled);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_timestep_counts_whole_steps() {
+        let mut timestep = FixedTimestep::new(0.25, 5);
+        assert_eq!(timestep.advance(0.1), 0);
+        assert!((timestep.alpha() - 0.4).abs() < 1e-6);
+        assert_eq!(timestep.advance(0.2), 1);
+        assert!((timestep.alpha() - 0.2).abs() < 1e-6);
+        assert_eq!(timestep.advance(0.6), 2);
+        assert!((timestep.alpha() - 0.6).abs() < 1e-5);
+    }
+
+    #[test]
+    fn fixed_timestep_drops_time_past_max_steps() {
+        let mut timestep = FixedTimestep::new(0.25, 2);
+        assert_eq!(timestep.advance(1.6), 2);
+        /* only the part of a step is kept, not the three steps that were due on top */
+        assert!((timestep.alpha() - 0.4).abs() < 1e-5);
+        assert_eq!(timestep.advance(0.2), 1);
+        assert!((timestep.alpha() - 0.2).abs() < 1e-5);
+    }
+
+    #[test]
+    fn fixed_timestep_time_counts_steps() {
+        let mut timestep = FixedTimestep::new(0.5, 5);
+        timestep.advance(10.0);
+        assert_eq!(timestep.time(), 0.0);
+        for _ in 0..3 {
+            timestep.count_step();
+        }
+        assert_eq!(timestep.time(), 1.5);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use beryllium::*;
+use crate::gllib::*;
+use crate::scenes::*;
+
+/* Running the world without a window or GPU, for integration tests, CI and batch simulations.
+Context::headless builds a context without SDL or GL where models are loaded CPU side only.
+A HeadlessRun then steps it the same way main_loop does, one fixed step at a time, with
+keyboard and mouse input played back from a ScriptedInput instead of coming from SDL.
+
+    let mut ctx = Context::headless(800, 600);
+    let model_map = ...;
+    switch_scene(&mut ctx, &model_map, "physics")?;
+    let input = ScriptedInput::new().hold(Keycode::RIGHT, 0, 60).at(30, InputEvent::Press(Keycode::SPACE));
+    let mut run = HeadlessRun::new(input);
+    run.run(&mut ctx, &model_map, 120)?; */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    Press(Keycode),
+    Release(Keycode),
+    /* mouse movement during the step, like a MouseMotion event after sensitivity */
+    MouseMotion(f32, f32),
+}
+
+/* input events keyed by the step they happen at, counted from the start of the run */
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedInput {
+    events: BTreeMap<u64, Vec<InputEvent>>,
+}
+impl ScriptedInput {
+    pub fn new() -> Self {
+        Self { events: BTreeMap::new() }
+    }
+    pub fn at(mut self, step: u64, event: InputEvent) -> Self {
+        self.events.entry(step).or_default().push(event);
+        self
+    }
+    /* keeps `key` down for `steps` steps starting at `from` */
+    pub fn hold(self, key: Keycode, from: u64, steps: u64) -> Self {
+        self.at(from, InputEvent::Press(key)).at(from + steps, InputEvent::Release(key))
+    }
+    pub fn events_at(&self, step: u64) -> &[InputEvent] {
+        self.events.get(&step).map_or(&[], |events| events.as_slice())
+    }
+    /* the step after the last event */
+    pub fn len(&self) -> u64 {
+        self.events.keys().next_back().map_or(0, |step| step + 1)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+pub struct HeadlessRun {
+    simulation: Simulation,
+    input: ScriptedInput,
+    keys_held: HashSet<Keycode>,
+    steps_run: u64,
+}
+impl HeadlessRun {
+    pub fn new(input: ScriptedInput) -> Self {
+        Self { simulation: Simulation::new(), input, keys_held: HashSet::new(), steps_run: 0 }
+    }
+
+    pub fn steps_run(&self) -> u64 {
+        self.steps_run
+    }
+    pub fn keys_held(&self) -> &HashSet<Keycode> {
+        &self.keys_held
+    }
+
+    /* applies this step's input, simulates it and switches scenes if a behavior asked to */
+    pub fn step(&mut self, ctx: &mut Context, model_map: &HashMap<&str, usize>) -> Result<(), String> {
+        let mut mouse_deltas = (0.0, 0.0);
+        for event in self.input.events_at(self.steps_run) {
+            match *event {
+                InputEvent::Press(key) => { self.keys_held.insert(key); },
+                InputEvent::Release(key) => { self.keys_held.remove(&key); },
+                InputEvent::MouseMotion(x, y) => mouse_deltas = (x, y),
+            }
+        }
+
+        self.simulation.step(ctx, &self.keys_held, &mouse_deltas, model_map);
+        ctx.game_obj_store.update_world_transforms(1.0);
+        self.steps_run += 1;
+
+        if let Some(scene_name) = ctx.next_scene.take() {
+            switch_scene(ctx, model_map, &scene_name)?;
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self, ctx: &mut Context, model_map: &HashMap<&str, usize>, steps: u64) -> Result<(), String> {
+        for _ in 0..steps {
+            self.step(ctx, model_map)?;
+        }
+        Ok(())
+    }
+}
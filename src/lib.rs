@@ -0,0 +1,32 @@
+/* The engine, split from main.rs so integration tests under tests/ can drive it headless */
+
+pub mod gllib;
+pub mod camera;
+pub mod scenes;
+pub mod behaviors;
+pub mod scene_loader;
+pub mod snapshot;
+pub mod collisions;
+pub mod ecs;
+pub mod scripting;
+pub mod headless;
+pub mod offscreen;
+pub mod render;
+pub mod software_render;
+pub mod lights;
+pub mod shadows;
+pub mod materials;
+pub mod bounds;
+pub mod render_queue;
+pub mod environment;
+pub mod post_process;
+
+/* Takes a string literal and concatenates a null byte onto the end. */
+#[macro_export]
+macro_rules! null_str {
+  ($lit:literal) => {{
+    // "type check" the input
+    const _: &str = $lit;
+    concat!($lit, "\0")
+  }};
+}
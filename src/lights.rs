@@ -0,0 +1,258 @@
+use serde::{Serialize, Deserialize};
+use ultraviolet::{mat, vec};
+use crate::ecs::*;
+use crate::shadows::*;
+
+/* Lights are a component: attach a Light to a GameObject (GameObject::light, or `light` in scene
+files) and it lights the scene from wherever the object is, turning with it. Every frame the
+renderer gathers the first MAX_LIGHTS lights in store order and places them in the world.
+A scene without any lights is lit by one plain white point light at the camera's light_position,
+which is how every scene was lit before lights were components. */
+
+/* has to match MAX_LIGHTS in param_blinn_phong_shader/fragment.GLSL */
+pub const MAX_LIGHTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LightKind {
+    /* shines in every direction from the object's position */
+    Point,
+    /* parallel rays along `direction` from infinitely far away, where the object is doesn't matter */
+    Directional,
+    /* a cone along `direction`, full strength within inner_angle of its axis and fading out
+    to nothing at outer_angle, both in degrees */
+    Spot { inner_angle: f32, outer_angle: f32 },
+}
+
+/* light falls off with the distance d as 1 / (constant + linear * d + quadratic * d^2) */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+impl Attenuation {
+    /* doesn't fall off at all */
+    pub const NONE: Self = Self { constant: 1.0, linear: 0.0, quadratic: 0.0 };
+
+    /* falls off to about 1% of its brightness at `distance` */
+    pub fn range(distance: f32) -> Self {
+        let distance = distance.max(0.001);
+        Self { constant: 1.0, linear: 4.5 / distance, quadratic: 75.0 / (distance * distance) }
+    }
+
+    pub fn at(&self, distance: f32) -> f32 {
+        1.0 / (self.constant + self.linear * distance + self.quadratic * distance * distance).max(0.0001)
+    }
+}
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+fn white() -> [f32; 3] { [1.0, 1.0, 1.0] }
+fn one() -> f32 { 1.0 }
+fn down() -> [f32; 3] { [0.0, -1.0, 0.0] }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Light {
+    pub kind: LightKind,
+    #[serde(default = "white")]
+    pub color: [f32; 3],
+    #[serde(default = "one")]
+    pub intensity: f32,
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    /* where directional and spot lights point, relative to the object's rotation */
+    #[serde(default = "down")]
+    pub direction: [f32; 3],
+    /* makes this the light that casts shadows, see shadows.rs */
+    #[serde(default)]
+    pub shadow: Option<ShadowSettings>,
+}
+impl Light {
+    pub fn point(color: [f32; 3], intensity: f32) -> Self {
+        Self { kind: LightKind::Point, color, intensity, attenuation: Attenuation::NONE, direction: down(), shadow: None }
+    }
+    pub fn directional(direction: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Self { kind: LightKind::Directional, color, intensity, attenuation: Attenuation::NONE, direction, shadow: None }
+    }
+    pub fn spot(direction: [f32; 3], inner_angle: f32, outer_angle: f32, color: [f32; 3], intensity: f32) -> Self {
+        Self { kind: LightKind::Spot { inner_angle, outer_angle }, color, intensity, attenuation: Attenuation::NONE, direction, shadow: None }
+    }
+    pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+        self.attenuation = attenuation;
+        self
+    }
+    pub fn with_shadow(mut self, settings: ShadowSettings) -> Self {
+        self.shadow = Some(settings);
+        self
+    }
+
+    /* the light as placed by an object's world transform */
+    pub fn to_world(&self, world_matrix: &mat::Mat4, world_rotation: &mat::Mat4) -> WorldLight {
+        let direction = world_rotation.transform_vec3(vec::Vec3::from(self.direction));
+        WorldLight {
+            kind: self.kind,
+            position: world_matrix.cols[3].xyz(),
+            direction: if direction.mag_sq() > 0.0 { direction.normalized() } else { -vec::Vec3::unit_y() },
+            color: vec::Vec3::from(self.color) * self.intensity,
+            attenuation: self.attenuation,
+            shadow: self.shadow,
+        }
+    }
+}
+
+/* a light in world space for one frame, color already scaled by intensity */
+#[derive(Debug, Clone, Copy)]
+pub struct WorldLight {
+    pub kind: LightKind,
+    pub position: vec::Vec3,
+    pub direction: vec::Vec3,
+    pub color: vec::Vec3,
+    pub attenuation: Attenuation,
+    pub shadow: Option<ShadowSettings>,
+}
+impl WorldLight {
+    /* unit vector from `point` towards the light and how strongly the light reaches it */
+    pub fn towards(&self, point: vec::Vec3) -> (vec::Vec3, f32) {
+        if self.kind == LightKind::Directional {
+            return (-self.direction, 1.0);
+        }
+        let to_light = self.position - point;
+        let distance = to_light.mag();
+        let light_dir = if distance > 0.0 { to_light / distance } else { vec::Vec3::unit_y() };
+        let mut strength = self.attenuation.at(distance);
+        if let LightKind::Spot { inner_angle, outer_angle } = self.kind {
+            let (cos_inner, cos_outer) = spot_cosines(inner_angle, outer_angle);
+            let theta = (-light_dir).dot(self.direction);
+            strength *= ((theta - cos_outer) / (cos_inner - cos_outer).max(0.0001)).clamp(0.0, 1.0);
+        }
+        (light_dir, strength)
+    }
+}
+
+/* cosines of the cone angles, what the shaders compare against */
+pub fn spot_cosines(inner_angle: f32, outer_angle: f32) -> (f32, f32) {
+    let outer_angle = outer_angle.max(inner_angle);
+    (inner_angle.to_radians().cos(), outer_angle.to_radians().cos())
+}
+
+/* the lights for a frame, with the world transforms from the last update_world_transforms */
+pub fn gather_lights(store: &GameObjectStore, fallback_position: vec::Vec3) -> Vec<WorldLight> {
+    let mut lights: Vec<WorldLight> = join(&store.lights, &store.transforms)
+        .take(MAX_LIGHTS)
+        .map(|(_, light, transform)| light.to_world(&transform.world_matrix, &transform.world_rotation))
+        .collect();
+    if lights.is_empty() {
+        let mut fallback = Light::point(white(), 1.0).to_world(&mat::Mat4::identity(), &mat::Mat4::identity());
+        fallback.position = fallback_position;
+        lights.push(fallback);
+    }
+    lights
+}
+
+/* the Lights uniform block in std140 layout: the count padded to 16 bytes, then per light
+position + kind, direction, color, attenuation and the spot cone cosines, each a vec4 */
+pub fn pack_lights(lights: &[WorldLight]) -> Vec<f32> {
+    let count = lights.len().min(MAX_LIGHTS);
+    /* the count is a GLSL int, so its bits go in as they are */
+    let mut data = vec![f32::from_bits(count as u32), 0.0, 0.0, 0.0];
+    for light in lights.iter().take(count) {
+        let (kind, (cos_inner, cos_outer)) = match light.kind {
+            LightKind::Point => (0.0, (0.0, 0.0)),
+            LightKind::Directional => (1.0, (0.0, 0.0)),
+            LightKind::Spot { inner_angle, outer_angle } => (2.0, spot_cosines(inner_angle, outer_angle)),
+        };
+        data.extend_from_slice(&[light.position.x, light.position.y, light.position.z, kind]);
+        data.extend_from_slice(&[light.direction.x, light.direction.y, light.direction.z, 0.0]);
+        data.extend_from_slice(&[light.color.x, light.color.y, light.color.z, 0.0]);
+        data.extend_from_slice(&[light.attenuation.constant, light.attenuation.linear, light.attenuation.quadratic, 0.0]);
+        data.extend_from_slice(&[cos_inner, cos_outer, 0.0, 0.0]);
+    }
+    data.resize(LIGHTS_BLOCK_FLOATS, 0.0);
+    data
+}
+pub const LIGHT_FLOATS: usize = 20;
+pub const LIGHTS_BLOCK_FLOATS: usize = 4 + MAX_LIGHTS * LIGHT_FLOATS;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gllib::GameObject;
+
+    fn light_at(position: vec::Vec3, light: Light) -> GameObject {
+        let mut go = GameObject::empty().add_light(light);
+        go.position = position;
+        go
+    }
+
+    #[test]
+    fn pack_lights_uses_std140_offsets() {
+        let point = Light::point([1.0, 0.5, 0.25], 2.0).with_attenuation(Attenuation { constant: 1.0, linear: 0.5, quadratic: 0.25 })
+            .to_world(&mat::Mat4::from_translation(vec::Vec3::new(1.0, 2.0, 3.0)), &mat::Mat4::identity());
+        let spot = Light::spot([0.0, 0.0, -1.0], 0.0, 60.0, [1.0, 1.0, 1.0], 1.0)
+            .to_world(&mat::Mat4::identity(), &mat::Mat4::identity());
+        let data = pack_lights(&[point, spot]);
+
+        assert_eq!(data.len(), LIGHTS_BLOCK_FLOATS);
+        assert_eq!(data[0].to_bits(), 2);
+        assert_eq!(&data[1..4], &[0.0, 0.0, 0.0]);
+
+        let first = &data[4..4 + LIGHT_FLOATS];
+        assert_eq!(&first[0..4], &[1.0, 2.0, 3.0, 0.0]);
+        assert_eq!(&first[4..8], &[0.0, -1.0, 0.0, 0.0]);
+        assert_eq!(&first[8..12], &[2.0, 1.0, 0.5, 0.0]);
+        assert_eq!(&first[12..16], &[1.0, 0.5, 0.25, 0.0]);
+        assert_eq!(&first[16..20], &[0.0, 0.0, 0.0, 0.0]);
+
+        let second = &data[4 + LIGHT_FLOATS..4 + 2 * LIGHT_FLOATS];
+        assert_eq!(second[3], 2.0);
+        assert_eq!(&second[4..8], &[0.0, 0.0, -1.0, 0.0]);
+        assert!((second[16] - 1.0).abs() < 1e-6);
+        assert!((second[17] - 0.5).abs() < 1e-6);
+
+        assert!(data[4 + 2 * LIGHT_FLOATS..].iter().all(|f| *f == 0.0));
+    }
+
+    #[test]
+    fn gather_and_pack_stop_at_max_lights() {
+        let mut store = GameObjectStore::new();
+        for i in 0..MAX_LIGHTS + 3 {
+            store.add(light_at(vec::Vec3::new(i as f32, 0.0, 0.0), Light::point([1.0, 1.0, 1.0], 1.0)));
+        }
+        store.update_world_transforms(1.0);
+        let lights = gather_lights(&store, vec::Vec3::zero());
+        assert_eq!(lights.len(), MAX_LIGHTS);
+        assert_eq!(lights[MAX_LIGHTS - 1].position.x, (MAX_LIGHTS - 1) as f32);
+
+        let too_many = vec![lights[0]; MAX_LIGHTS + 2];
+        let data = pack_lights(&too_many);
+        assert_eq!(data.len(), LIGHTS_BLOCK_FLOATS);
+        assert_eq!(data[0].to_bits(), MAX_LIGHTS as u32);
+    }
+
+    #[test]
+    fn scene_without_lights_gets_a_white_point_light() {
+        let mut store = GameObjectStore::new();
+        store.add(GameObject::empty());
+        store.update_world_transforms(1.0);
+        let lights = gather_lights(&store, vec::Vec3::new(4.0, 5.0, 6.0));
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].kind, LightKind::Point);
+        assert_eq!(lights[0].position, vec::Vec3::new(4.0, 5.0, 6.0));
+        assert_eq!(lights[0].color, vec::Vec3::one());
+        assert_eq!(lights[0].attenuation, Attenuation::NONE);
+    }
+
+    #[test]
+    fn lights_follow_their_object() {
+        let mut store = GameObjectStore::new();
+        store.add(light_at(vec::Vec3::new(0.0, 3.0, 0.0), Light::directional([0.0, -1.0, 0.0], [1.0, 1.0, 1.0], 0.5)));
+        store.update_world_transforms(1.0);
+        let lights = gather_lights(&store, vec::Vec3::zero());
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].position, vec::Vec3::new(0.0, 3.0, 0.0));
+        assert_eq!(lights[0].color, vec::Vec3::broadcast(0.5));
+    }
+}
@@ -1,33 +1,49 @@
-mod gllib;
-mod camera;
-mod scenes;
-mod behaviors;
-
 use std::collections::HashMap;
 
-use crate::behaviors::apply_behaviors;
-use crate::behaviors::apply_collision_behaviors;
-use crate::behaviors::BehaviorDataContainerEnum;
-use crate::gllib::*;
-use crate::scenes::*;
-
-/* Takes a string literal and concatenates a null byte onto the end. */
-#[macro_export]
-macro_rules! null_str {
-  ($lit:literal) => {{
-    // "type check" the input
-    const _: &str = $lit;
-    concat!($lit, "\0")
-  }};
-}
+use rustproject::gllib::*;
+use rustproject::scenes::*;
+use rustproject::headless::*;
 
-fn main() {
-    
-    const WINDOW_WIDTH: u32 = 800;
-    const WINDOW_HEIGHT: u32 = 600;
+pub const SCENE_ENV_VAR: &str = "RUSTGRAPHICS_SCENE";
+pub const DEFAULT_SCENE: &str = "physics";
 
-    let mut ctx: Context<BehaviorDataContainerEnum> = Context::new(WINDOW_WIDTH, WINDOW_HEIGHT).expect("creating window failed probably");
+/* start scene comes from `--scene <name>` (or `--scene=<name>`), then the RUSTGRAPHICS_SCENE env var, then the default */
+fn start_scene_name() -> String {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--scene" {
+            if let Some(name) = args.next() {
+                return name;
+            }
+        } else if let Some(name) = arg.strip_prefix("--scene=") {
+            return name.to_string();
+        }
+    }
+    std::env::var(SCENE_ENV_VAR).unwrap_or_else(|_| DEFAULT_SCENE.to_string())
+}
 
+/* `--headless <steps>` runs the start scene for that many fixed steps without a window */
+fn headless_steps() -> Result<Option<u64>, String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let steps = if arg == "--headless" {
+            args.next()
+        } else if let Some(steps) = arg.strip_prefix("--headless=") {
+            Some(steps.to_string())
+        } else {
+            continue;
+        };
+        return match steps {
+            Some(steps) => steps.parse()
+                .map(Some)
+                .map_err(|e| format!("--headless expects a number of steps, got '{}': {}", steps, e)),
+            None => Err("--headless expects a number of steps".to_string()),
+        };
+    }
+    Ok(None)
+}
+
+fn load_models(ctx: &mut Context) -> HashMap<&'static str, usize> {
     let mut model_map: HashMap<&str, usize> = HashMap::new();
 
     model_map.insert("cone", ctx.load_model("src/models/cone.obj"));
@@ -35,24 +51,49 @@ fn main() {
     model_map.insert("cone_ring", ctx.load_model("src/models/cone_ring.obj"));
     model_map.insert("plane", ctx.load_model("src/models/plane.obj"));
     model_map.insert("ball", ctx.load_model("src/models/ball.obj"));
+    model_map.insert("crate", ctx.load_model("src/models/crate.obj"));
 
-    // let scene_name = "";
-    // let scene_name = "waves";
-    let scene_name = "physics";
-
-    ctx.pre_draw = {
-        match scene_name {
-            "waves" => {
-                make_scene_waves(&mut ctx, &model_map)
-            },
-            "physics" => {
-                make_scene_physics(&mut ctx, &model_map)
-            },
-            _ => make_scene_empty(&mut ctx, &model_map)
+    model_map
+}
+
+fn main() {
+    if std::env::args().any(|arg| arg == "--list-scenes") {
+        for name in default_scene_registry().names() {
+            println!("{}", name);
         }
+        return;
+    }
+    
+    const WINDOW_WIDTH: u32 = 800;
+    const WINDOW_HEIGHT: u32 = 600;
+
+    let headless_steps = match headless_steps() {
+        Ok(steps) => steps,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
     };
-    ctx.handleit = Box::new(apply_behaviors);
-    ctx.handlecollisionit = Box::new(apply_collision_behaviors);
+    if let Some(steps) = headless_steps {
+        let mut ctx = Context::headless(WINDOW_WIDTH, WINDOW_HEIGHT);
+        let model_map = load_models(&mut ctx);
+        ctx.scenes = default_scene_registry();
+        let scene_name = start_scene_name();
+        switch_scene(&mut ctx, &model_map, &scene_name).expect("Failed to load start scene");
+        let mut run = HeadlessRun::new(ScriptedInput::new());
+        run.run(&mut ctx, &model_map, steps).expect("Headless run failed");
+        println!("ran {} steps of {}, {} objects left", run.steps_run(), scene_name, ctx.game_obj_store.len());
+        return;
+    }
+
+    let mut ctx: Context = Context::new(WINDOW_WIDTH, WINDOW_HEIGHT).expect("creating window failed probably");
+
+    let model_map = load_models(&mut ctx);
+
+    ctx.scenes = default_scene_registry();
+
+    let scene_name = start_scene_name();
+    switch_scene(&mut ctx, &model_map, &scene_name).expect("Failed to load start scene");
 
     main_loop(&mut ctx, &model_map);
 
@@ -0,0 +1,263 @@
+use serde::{Serialize, Deserialize};
+use ultraviolet::vec;
+use crate::render::*;
+use crate::render_queue::*;
+
+/* A Material is a shader, the parameter block it draws with and the textures it samples.
+Materials come from the .mtl files of loaded models, from Context::add_material in code,
+or from the `materials` of a scene file. They are looked up by name and referred to by
+their index into Context::materials. Renderers compile each shader (with the defines of
+the maps a material has) once and share it between every material that uses it, the
+parameters are set per draw, so objects can swap or tweak their material with a
+MaterialOverride without any shaders being made. */
+
+/* folder under src/shaders every material is drawn with unless it names another.
+Other shaders get the same uniforms and vertex attributes, see param_blinn_phong_shader */
+pub const DEFAULT_SHADER: &str = "param_blinn_phong_shader";
+
+/* the uniforms a material sets, named after the .mtl statements they come from */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MaterialParams {
+    /* Ka, Kd and Ks */
+    pub ambient: vec::Vec3,
+    pub diffuse: vec::Vec3,
+    pub specular: vec::Vec3,
+    /* Ns, the specular exponent */
+    pub shininess: f32,
+    /* Ni and d */
+    pub optical_density: f32,
+    pub dissolve: f32,
+    /* how much of the scene's environment it mirrors, 0 none to 1 a perfect mirror.
+    Not part of .mtl files, see environment.rs */
+    #[serde(default)]
+    pub reflectivity: f32,
+}
+impl Default for MaterialParams {
+    fn default() -> Self {
+        Self {
+            ambient: vec::Vec3::one(),
+            diffuse: vec::Vec3::one(),
+            specular: vec::Vec3::broadcast(0.5),
+            shininess: 32.0,
+            optical_density: 1.0,
+            dissolve: 1.0,
+            reflectivity: 0.0,
+        }
+    }
+}
+
+/* how a Transparent pass material is combined with what is already drawn behind it */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BlendMode {
+    /* mixed in by dissolve, for glass, water and fading out */
+    #[default]
+    Alpha,
+    /* added on top, scaled by dissolve, for glows and fire. Always drawn in the Transparent pass */
+    Additive,
+}
+
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name: String,
+    /* folder of the shader under src/shaders */
+    pub shader: String,
+    pub params: MaterialParams,
+    /* indices into Context::textures of map_Kd, map_Ks and map_Bump. The diffuse map
+    multiplies Ka and Kd, the specular map Ks, the bump map is a tangent space normal map */
+    pub diffuse_map: Option<usize>,
+    pub specular_map: Option<usize>,
+    pub bump_map: Option<usize>,
+    /* fragments where the diffuse map's alpha is below this are left out, for leaves, fences and
+    the like. Set by Context::load_model for diffuse maps with transparent texels */
+    pub alpha_cutoff: Option<f32>,
+    /* only used in the Transparent pass */
+    pub blend: BlendMode,
+    /* whether it hides what is drawn after it in the Transparent pass. Off by default so
+    transparent objects behind each other all show, on for mostly opaque ones that look wrong
+    when their own far side shows through */
+    pub depth_write: bool,
+}
+impl Material {
+    /* drawn with the default shader, without any maps */
+    pub fn new(name: &str, params: MaterialParams) -> Self {
+        Self {
+            name: name.to_string(),
+            shader: DEFAULT_SHADER.to_string(),
+            params,
+            diffuse_map: None,
+            specular_map: None,
+            bump_map: None,
+            alpha_cutoff: None,
+            blend: BlendMode::Alpha,
+            depth_write: false,
+        }
+    }
+    /* without the maps, their files are loaded by Context::load_model */
+    pub fn from_mtl(mat: &tobj::Material) -> Self {
+        Self::new(&mat.name, MaterialParams {
+            ambient: vec::Vec3::from(mat.ambient),
+            diffuse: vec::Vec3::from(mat.diffuse),
+            specular: vec::Vec3::from(mat.specular),
+            shininess: mat.shininess,
+            optical_density: mat.optical_density,
+            dissolve: mat.dissolve,
+            reflectivity: 0.0,
+        })
+    }
+    pub fn with_shader(mut self, shader: &str) -> Self {
+        self.shader = shader.to_string();
+        self
+    }
+    pub fn with_alpha_cutoff(mut self, alpha_cutoff: f32) -> Self {
+        self.alpha_cutoff = Some(alpha_cutoff);
+        self
+    }
+    pub fn with_blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+    pub fn with_depth_write(mut self, depth_write: bool) -> Self {
+        self.depth_write = depth_write;
+        self
+    }
+    pub fn with_map(mut self, slot: MapSlot, texture_idx: usize) -> Self {
+        match slot {
+            MapSlot::Diffuse => self.diffuse_map = Some(texture_idx),
+            MapSlot::Specular => self.specular_map = Some(texture_idx),
+            MapSlot::Bump => self.bump_map = Some(texture_idx),
+        }
+        self
+    }
+
+    /* the pass it's drawn in with these parameters, which may have an object's overrides applied */
+    pub fn pass(&self, params: &MaterialParams) -> RenderPass {
+        if params.dissolve < 1.0 || self.blend == BlendMode::Additive {
+            RenderPass::Transparent
+        } else if self.alpha_cutoff.is_some() && self.diffuse_map.is_some() {
+            RenderPass::AlphaTested
+        } else {
+            RenderPass::Opaque
+        }
+    }
+
+    /* the map slots in order, with the texture unit and shader define each one gets */
+    pub fn maps(&self) -> [(Option<usize>, MapSlot); 3] {
+        [
+            (self.diffuse_map, MapSlot::Diffuse),
+            (self.specular_map, MapSlot::Specular),
+            (self.bump_map, MapSlot::Bump),
+        ]
+    }
+}
+
+/* changes to the materials of one DrawableObject, leaving the model's own materials as they are.
+`material` draws every mesh of the model with that material instead of its own,
+the rest replace single parameters of whichever material the mesh ends up with.
+`opacity` scales dissolve instead of replacing it, so fading out an object keeps its
+glass as much clearer than the rest as it was, see FadeOut in behaviors.rs */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialOverride {
+    pub material: Option<usize>,
+    pub ambient: Option<vec::Vec3>,
+    pub diffuse: Option<vec::Vec3>,
+    pub specular: Option<vec::Vec3>,
+    pub shininess: Option<f32>,
+    pub dissolve: Option<f32>,
+    pub opacity: Option<f32>,
+}
+impl MaterialOverride {
+    pub fn material(material_idx: usize) -> Self {
+        Self { material: Some(material_idx), ..Self::default() }
+    }
+    /* sets Ka and Kd, which is what tints an object */
+    pub fn color(color: vec::Vec3) -> Self {
+        Self { ambient: Some(color), diffuse: Some(color), ..Self::default() }
+    }
+    pub fn with_specular(mut self, specular: vec::Vec3) -> Self {
+        self.specular = Some(specular);
+        self
+    }
+    pub fn with_shininess(mut self, shininess: f32) -> Self {
+        self.shininess = Some(shininess);
+        self
+    }
+    pub fn with_dissolve(mut self, dissolve: f32) -> Self {
+        self.dissolve = Some(dissolve);
+        self
+    }
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = Some(opacity);
+        self
+    }
+
+    /* the material a mesh is drawn with, out of `material_count` materials */
+    pub fn material_for(&self, mesh_material_idx: usize, material_count: usize) -> usize {
+        self.material.filter(|material_idx| *material_idx < material_count).unwrap_or(mesh_material_idx)
+    }
+    pub fn apply(&self, params: &MaterialParams) -> MaterialParams {
+        MaterialParams {
+            ambient: self.ambient.unwrap_or(params.ambient),
+            diffuse: self.diffuse.unwrap_or(params.diffuse),
+            specular: self.specular.unwrap_or(params.specular),
+            shininess: self.shininess.unwrap_or(params.shininess),
+            optical_density: params.optical_density,
+            dissolve: self.dissolve.unwrap_or(params.dissolve) * self.opacity.unwrap_or(1.0).clamp(0.0, 1.0),
+            reflectivity: params.reflectivity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glass() -> MaterialParams {
+        MaterialParams { dissolve: 0.5, ..MaterialParams::default() }
+    }
+
+    #[test]
+    fn override_replaces_only_what_it_sets() {
+        let red = vec::Vec3::new(1.0, 0.0, 0.0);
+        let params = MaterialOverride::color(red).with_shininess(8.0).apply(&glass());
+        assert_eq!(params.ambient, red);
+        assert_eq!(params.diffuse, red);
+        assert_eq!(params.shininess, 8.0);
+        assert_eq!(params.specular, glass().specular);
+        assert_eq!(params.dissolve, 0.5);
+
+        assert_eq!(MaterialOverride::default().apply(&glass()), glass());
+    }
+
+    #[test]
+    fn opacity_scales_dissolve() {
+        assert_eq!(MaterialOverride::default().with_opacity(0.5).apply(&glass()).dissolve, 0.25);
+        assert_eq!(MaterialOverride::default().with_dissolve(0.8).with_opacity(0.5).apply(&glass()).dissolve, 0.4);
+        /* clamped, it can only fade out */
+        assert_eq!(MaterialOverride::default().with_opacity(2.0).apply(&glass()).dissolve, 0.5);
+        assert_eq!(MaterialOverride::default().with_opacity(-1.0).apply(&glass()).dissolve, 0.0);
+    }
+
+    #[test]
+    fn override_material_must_exist() {
+        assert_eq!(MaterialOverride::default().material_for(1, 3), 1);
+        assert_eq!(MaterialOverride::material(2).material_for(1, 3), 2);
+        assert_eq!(MaterialOverride::material(3).material_for(1, 3), 1);
+    }
+
+    #[test]
+    fn pass_follows_blend_dissolve_and_cutoff() {
+        let opaque = MaterialParams::default();
+        let plain = Material::new("plain", opaque);
+        assert_eq!(plain.pass(&opaque), RenderPass::Opaque);
+        assert_eq!(plain.pass(&glass()), RenderPass::Transparent);
+        assert_eq!(plain.clone().with_blend(BlendMode::Additive).pass(&opaque), RenderPass::Transparent);
+
+        /* the cutoff tests the diffuse map's alpha, without one there is nothing to cut */
+        let cutout = plain.clone().with_alpha_cutoff(0.5);
+        assert_eq!(cutout.pass(&opaque), RenderPass::Opaque);
+        let cutout = cutout.with_map(MapSlot::Diffuse, 0);
+        assert_eq!(cutout.pass(&opaque), RenderPass::AlphaTested);
+        assert_eq!(cutout.pass(&glass()), RenderPass::Transparent);
+    }
+}
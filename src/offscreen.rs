@@ -0,0 +1,327 @@
+use std::fs;
+use std::path::Path;
+use image::RgbaImage;
+use crate::gllib::*;
+use crate::render::*;
+
+/* Rendering without a window: frames are drawn into an image target of the renderer and read back.
+Windowed contexts can do it as well (the screenshot hotkey). For golden-image tests and CI,
+Context::offscreen gets GL from a surfaceless EGL context instead of SDL, which works with
+Mesa's llvmpipe and no display at all (EGL_PLATFORM=surfaceless). That part needs libEGL and
+is only built on linux with the `egl` feature, elsewhere Context::offscreen returns an error.
+Context::software renders on the CPU instead and works everywhere. */
+
+pub const UPDATE_GOLDEN_ENV_VAR: &str = "RUSTGRAPHICS_UPDATE_GOLDEN";
+
+/* draws the current state of the world at width x height and reads it back */
+pub fn render_to_image(ctx: &mut Context, width: u32, height: u32) -> Result<RgbaImage, String> {
+    ctx.renderer.as_mut()
+        .ok_or_else(|| "Can't render, the context has no renderer".to_string())?
+        .set_target(RenderTarget::Image(width, height))?;
+    render_frame(ctx);
+    let renderer = ctx.renderer.as_mut().ok_or_else(|| "Renderer went away while rendering".to_string())?;
+    let image = renderer.read_pixels();
+    renderer.set_target(RenderTarget::Screen)?;
+    image
+}
+
+pub fn save_png(image: &RgbaImage, path: &str) -> Result<(), String> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Could not create folder for {}: {}", path, e))?;
+    }
+    image.save_with_format(path, image::ImageFormat::Png)
+        .map_err(|e| format!("Could not write image {}: {}", path, e))
+}
+
+pub fn load_png(path: &str) -> Result<RgbaImage, String> {
+    image::open(path)
+        .map(|image| image.to_rgba8())
+        .map_err(|e| format!("Could not read image {}: {}", path, e))
+}
+
+/* how far apart two images of the same size are */
+#[derive(Debug, Clone, Copy)]
+pub struct ImageDiff {
+    /* pixels where some channel differs by more than the channel tolerance */
+    pub differing_pixels: usize,
+    pub total_pixels: usize,
+    /* largest difference of any channel of any pixel */
+    pub max_channel_diff: u8,
+}
+impl ImageDiff {
+    pub fn differing_fraction(&self) -> f32 {
+        if self.total_pixels == 0 {
+            0.0
+        } else {
+            self.differing_pixels as f32 / self.total_pixels as f32
+        }
+    }
+}
+
+/* software and hardware GL rasterize slightly differently, so goldens are compared with some slack:
+a pixel only counts as different when a channel is off by more than `channel`, and images
+still match while at most `max_differing_fraction` of their pixels are different */
+#[derive(Debug, Clone, Copy)]
+pub struct DiffTolerance {
+    pub channel: u8,
+    pub max_differing_fraction: f32,
+}
+impl Default for DiffTolerance {
+    fn default() -> Self {
+        Self { channel: 2, max_differing_fraction: 0.001 }
+    }
+}
+
+pub fn diff_images(expected: &RgbaImage, actual: &RgbaImage, channel_tolerance: u8) -> Result<ImageDiff, String> {
+    if expected.dimensions() != actual.dimensions() {
+        return Err(format!("Image sizes differ, expected {:?} but got {:?}", expected.dimensions(), actual.dimensions()));
+    }
+    let mut diff = ImageDiff { differing_pixels: 0, total_pixels: (expected.width() * expected.height()) as usize, max_channel_diff: 0 };
+    for (expected, actual) in expected.pixels().zip(actual.pixels()) {
+        let channel_diff = expected.0.iter().zip(actual.0.iter())
+            .map(|(e, a)| e.abs_diff(*a))
+            .max()
+            .unwrap_or(0);
+        diff.max_channel_diff = diff.max_channel_diff.max(channel_diff);
+        if channel_diff > channel_tolerance {
+            diff.differing_pixels += 1;
+        }
+    }
+    Ok(diff)
+}
+
+/* compares the image with the golden at `path`. While RUSTGRAPHICS_UPDATE_GOLDEN is set the
+golden is written from the image instead, which is also how new goldens are made: a missing
+golden is an error otherwise. On a mismatch the image is written next to the golden as
+<name>.actual.png to look at */
+pub fn check_golden(actual: &RgbaImage, path: &str, tolerance: DiffTolerance) -> Result<ImageDiff, String> {
+    compare_golden(actual, path, tolerance, std::env::var_os(UPDATE_GOLDEN_ENV_VAR).is_some())
+}
+
+fn compare_golden(actual: &RgbaImage, path: &str, tolerance: DiffTolerance, update: bool) -> Result<ImageDiff, String> {
+    if update {
+        save_png(actual, path)?;
+        return diff_images(actual, actual, tolerance.channel);
+    }
+    if !Path::new(path).exists() {
+        return Err(format!("Golden {} does not exist, run with {} set to write it", path, UPDATE_GOLDEN_ENV_VAR));
+    }
+    let expected = load_png(path)?;
+    let diff = diff_images(&expected, actual, tolerance.channel);
+    let matches = diff.as_ref().is_ok_and(|diff| diff.differing_fraction() <= tolerance.max_differing_fraction);
+    if matches {
+        return diff;
+    }
+    let actual_path = Path::new(path).with_extension("actual.png");
+    save_png(actual, &actual_path.to_string_lossy())?;
+    let diff = diff?;
+    Err(format!(
+        "{} differs from its golden: {} of {} pixels off, up to {} per channel, got {}",
+        path, diff.differing_pixels, diff.total_pixels, diff.max_channel_diff, actual_path.display()
+    ))
+}
+
+/* a GL 3.3 core context made current without any surface, through EGL */
+pub struct SurfacelessGl {
+    #[cfg(all(target_os = "linux", feature = "egl"))]
+    display: egl::EGLDisplay,
+    #[cfg(all(target_os = "linux", feature = "egl"))]
+    context: egl::EGLContext,
+}
+
+#[cfg(all(target_os = "linux", feature = "egl"))]
+impl SurfacelessGl {
+    pub fn new() -> Result<Self, String> {
+        use egl::*;
+        unsafe {
+            /* prefer the surfaceless platform so no X or wayland server is needed */
+            let get_platform_display = eglGetProcAddress(c"eglGetPlatformDisplayEXT".as_ptr());
+            let display = if get_platform_display.is_null() {
+                eglGetDisplay(EGL_DEFAULT_DISPLAY)
+            } else {
+                let get_platform_display: GetPlatformDisplayExt = std::mem::transmute(get_platform_display);
+                get_platform_display(EGL_PLATFORM_SURFACELESS_MESA, EGL_DEFAULT_DISPLAY, std::ptr::null())
+            };
+            if display.is_null() {
+                return Err(format!("Could not get an EGL display, error {:#x}", eglGetError()));
+            }
+            let (mut major, mut minor) = (0, 0);
+            if eglInitialize(display, &mut major, &mut minor) == EGL_FALSE {
+                return Err(format!("Could not initialize EGL, error {:#x}", eglGetError()));
+            }
+            if eglBindAPI(EGL_OPENGL_API) == EGL_FALSE {
+                eglTerminate(display);
+                return Err(format!("EGL has no desktop OpenGL, error {:#x}", eglGetError()));
+            }
+
+            let config_attribs = [EGL_RENDERABLE_TYPE, EGL_OPENGL_BIT, EGL_NONE];
+            let mut config = std::ptr::null_mut();
+            let mut config_count = 0;
+            if eglChooseConfig(display, config_attribs.as_ptr(), &mut config, 1, &mut config_count) == EGL_FALSE || config_count == 0 {
+                eglTerminate(display);
+                return Err(format!("No EGL config for OpenGL, error {:#x}", eglGetError()));
+            }
+
+            let context_attribs = [
+                EGL_CONTEXT_MAJOR_VERSION, 3,
+                EGL_CONTEXT_MINOR_VERSION, 3,
+                EGL_CONTEXT_OPENGL_PROFILE_MASK, EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT,
+                EGL_NONE,
+            ];
+            let context = eglCreateContext(display, config, EGL_NO_CONTEXT, context_attribs.as_ptr());
+            if context.is_null() {
+                eglTerminate(display);
+                return Err(format!("Could not create an OpenGL 3.3 context, error {:#x}", eglGetError()));
+            }
+            if eglMakeCurrent(display, EGL_NO_SURFACE, EGL_NO_SURFACE, context) == EGL_FALSE {
+                eglDestroyContext(display, context);
+                eglTerminate(display);
+                return Err(format!("Could not make the context current without a surface, error {:#x}", eglGetError()));
+            }
+            Ok(Self { display, context })
+        }
+    }
+
+    /* `name` is a null terminated function name, as the GL loader hands them over */
+    pub(crate) fn get_proc_address(&self, name: *const std::os::raw::c_char) -> *const std::os::raw::c_void {
+        unsafe { egl::eglGetProcAddress(name) }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "egl"))]
+impl Drop for SurfacelessGl {
+    fn drop(&mut self) {
+        unsafe {
+            egl::eglMakeCurrent(self.display, egl::EGL_NO_SURFACE, egl::EGL_NO_SURFACE, egl::EGL_NO_CONTEXT);
+            egl::eglDestroyContext(self.display, self.context);
+            egl::eglTerminate(self.display);
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "egl")))]
+impl SurfacelessGl {
+    pub fn new() -> Result<Self, String> {
+        Err("Surfaceless GL needs linux and the egl feature".to_string())
+    }
+
+    pub(crate) fn get_proc_address(&self, _name: *const std::os::raw::c_char) -> *const std::os::raw::c_void {
+        std::ptr::null()
+    }
+}
+
+/* the few EGL 1.5 entry points SurfacelessGl needs */
+#[cfg(all(target_os = "linux", feature = "egl"))]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+mod egl {
+    use std::os::raw::{c_char, c_void};
+
+    pub type EGLDisplay = *mut c_void;
+    pub type EGLConfig = *mut c_void;
+    pub type EGLContext = *mut c_void;
+    pub type EGLSurface = *mut c_void;
+    pub type EGLint = i32;
+    pub type EGLenum = u32;
+    pub type EGLBoolean = u32;
+
+    pub const EGL_FALSE: EGLBoolean = 0;
+    pub const EGL_NONE: EGLint = 0x3038;
+    pub const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+    pub const EGL_OPENGL_BIT: EGLint = 0x0008;
+    pub const EGL_OPENGL_API: EGLenum = 0x30A2;
+    pub const EGL_CONTEXT_MAJOR_VERSION: EGLint = 0x3098;
+    pub const EGL_CONTEXT_MINOR_VERSION: EGLint = 0x30FB;
+    pub const EGL_CONTEXT_OPENGL_PROFILE_MASK: EGLint = 0x30FD;
+    pub const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT: EGLint = 0x0001;
+    pub const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31DD;
+    pub const EGL_DEFAULT_DISPLAY: *mut c_void = std::ptr::null_mut();
+    pub const EGL_NO_CONTEXT: EGLContext = std::ptr::null_mut();
+    pub const EGL_NO_SURFACE: EGLSurface = std::ptr::null_mut();
+
+    pub type GetPlatformDisplayExt = unsafe extern "C" fn(EGLenum, *mut c_void, *const EGLint) -> EGLDisplay;
+
+    #[link(name = "EGL")]
+    extern "C" {
+        pub fn eglGetDisplay(display_id: *mut c_void) -> EGLDisplay;
+        pub fn eglGetProcAddress(procname: *const c_char) -> *const c_void;
+        pub fn eglGetError() -> EGLint;
+        pub fn eglInitialize(display: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> EGLBoolean;
+        pub fn eglTerminate(display: EGLDisplay) -> EGLBoolean;
+        pub fn eglBindAPI(api: EGLenum) -> EGLBoolean;
+        pub fn eglChooseConfig(display: EGLDisplay, attribs: *const EGLint, configs: *mut EGLConfig, config_size: EGLint, config_count: *mut EGLint) -> EGLBoolean;
+        pub fn eglCreateContext(display: EGLDisplay, config: EGLConfig, share_context: EGLContext, attribs: *const EGLint) -> EGLContext;
+        pub fn eglDestroyContext(display: EGLDisplay, context: EGLContext) -> EGLBoolean;
+        pub fn eglMakeCurrent(display: EGLDisplay, draw: EGLSurface, read: EGLSurface, context: EGLContext) -> EGLBoolean;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn gray(width: u32, height: u32, value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba([value, value, value, 255]))
+    }
+
+    /* a folder of its own under the system temp dir for each test */
+    fn temp_path(test: &str, file: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rustgraphics-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(file).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn diff_counts_pixels_past_the_channel_tolerance() {
+        let expected = gray(4, 4, 100);
+        let mut actual = expected.clone();
+        actual.put_pixel(0, 0, Rgba([102, 100, 100, 255]));
+        actual.put_pixel(1, 0, Rgba([100, 97, 100, 255]));
+        actual.put_pixel(2, 0, Rgba([100, 100, 100, 200]));
+
+        let diff = diff_images(&expected, &actual, 2).unwrap();
+        assert_eq!(diff.total_pixels, 16);
+        assert_eq!(diff.differing_pixels, 2);
+        assert_eq!(diff.max_channel_diff, 55);
+        assert_eq!(diff.differing_fraction(), 2.0 / 16.0);
+
+        let diff = diff_images(&expected, &actual, 55).unwrap();
+        assert_eq!(diff.differing_pixels, 0);
+    }
+
+    #[test]
+    fn diff_rejects_different_sizes() {
+        assert!(diff_images(&gray(4, 4, 0), &gray(4, 3, 0), 255).is_err());
+    }
+
+    #[test]
+    fn golden_matches_within_tolerance() {
+        let path = temp_path("golden-match", "scene.png");
+        let golden = gray(10, 10, 50);
+        save_png(&golden, &path).unwrap();
+
+        /* one pixel in a hundred off by a lot, the rest within the channel tolerance */
+        let mut actual = gray(10, 10, 52);
+        actual.put_pixel(3, 3, Rgba([0, 0, 0, 255]));
+        let tolerance = DiffTolerance { channel: 2, max_differing_fraction: 0.01 };
+        let diff = compare_golden(&actual, &path, tolerance, false).unwrap();
+        assert_eq!(diff.differing_pixels, 1);
+
+        actual.put_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        assert!(compare_golden(&actual, &path, tolerance, false).is_err());
+        let written = load_png(&Path::new(&path).with_extension("actual.png").to_string_lossy()).unwrap();
+        assert_eq!(written, actual);
+    }
+
+    #[test]
+    fn missing_golden_is_an_error_unless_updating() {
+        let path = temp_path("golden-missing", "scene.png");
+        let image = gray(2, 2, 10);
+        assert!(compare_golden(&image, &path, DiffTolerance::default(), false).is_err());
+        assert!(!Path::new(&path).exists());
+
+        compare_golden(&image, &path, DiffTolerance::default(), true).unwrap();
+        assert_eq!(load_png(&path).unwrap(), image);
+        compare_golden(&image, &path, DiffTolerance::default(), false).unwrap();
+    }
+}
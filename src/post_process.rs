@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use ogl33::*;
+use log::warn;
+use serde::{Serialize, Deserialize};
+use crate::gllib::*;
+use crate::render::*;
+
+/* Post-processing: with passes in Context::post_process (or `post_process` in a scene file) the
+scene is drawn into an offscreen half float target instead of straight into the window, then every
+pass draws a fullscreen triangle that reads the last one's result, the final pass into the real
+target. Passes run in the order given. The usual one is what standard_chain returns: bloom while
+colors still go above 1, tone mapping them back to 0 to 1, FXAA on what the screen will show,
+then gamma. Without passes nothing changes and frames are drawn straight into the target.
+Games add their own passes with PostPass::custom and a GLSL fragment shader, see Custom.
+Only GlBackend does post-processing, the software backend ignores it. */
+
+fn default_threshold() -> f32 { 1.0 }
+fn default_bloom_intensity() -> f32 { 0.5 }
+fn default_blur_passes() -> u32 { 4 }
+fn default_exposure() -> f32 { 1.0 }
+fn default_gamma() -> f32 { 2.2 }
+fn default_vignette_strength() -> f32 { 0.4 }
+fn default_vignette_radius() -> f32 { 0.6 }
+fn default_grade_strength() -> f32 { 1.0 }
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ToneMapOperator {
+    /* c / (1 + c), soft and a little flat */
+    #[default]
+    Reinhard,
+    /* an approximation of the ACES filmic curve, more contrast */
+    Aces,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PostPass {
+    /* blurs what is brighter than threshold and adds it back on top, so lights glow.
+    blur_passes is how many times the half size copy is blurred, more spreads it further */
+    Bloom {
+        #[serde(default = "default_threshold")]
+        threshold: f32,
+        #[serde(default = "default_bloom_intensity")]
+        intensity: f32,
+        #[serde(default = "default_blur_passes")]
+        blur_passes: u32,
+    },
+    /* brings colors from 0 to anything back to 0 to 1, exposure scales them first */
+    ToneMap {
+        #[serde(default)]
+        operator: ToneMapOperator,
+        #[serde(default = "default_exposure")]
+        exposure: f32,
+    },
+    /* from linear colors to what the screen expects */
+    Gamma {
+        #[serde(default = "default_gamma")]
+        gamma: f32,
+    },
+    /* smooths jagged edges by blurring along them */
+    Fxaa,
+    /* darkens towards the corners, from `radius` (0 the center, 1 the corners) out */
+    Vignette {
+        #[serde(default = "default_vignette_strength")]
+        strength: f32,
+        #[serde(default = "default_vignette_radius")]
+        radius: f32,
+    },
+    /* looks every color up in a LUT image, a strip of N slices of N x N for N^2 x N pixels
+    (256 x 16, 1024 x 32), red increasing to the right, green downwards and blue by slice.
+    strength mixes between the original and the graded color */
+    ColorGrade {
+        lut: String,
+        #[serde(default = "default_grade_strength")]
+        strength: f32,
+    },
+    /* a fragment shader file of the game's own, relative to the working directory. It gets
+    `in vec2 TexCoord`, the last pass's result as `uniform sampler2D scene`, the size of one of
+    its texels as `uniform vec2 texel_size`, and a float uniform for each of params */
+    Custom {
+        fragment: String,
+        #[serde(default)]
+        params: BTreeMap<String, f32>,
+    },
+}
+impl PostPass {
+    pub fn custom(fragment: &str) -> Self {
+        Self::Custom { fragment: fragment.to_string(), params: BTreeMap::new() }
+    }
+    /* sets a uniform of a Custom pass, does nothing to the others */
+    pub fn with_param(mut self, name: &str, value: f32) -> Self {
+        if let Self::Custom { params, .. } = &mut self {
+            params.insert(name.to_string(), value);
+        }
+        self
+    }
+}
+
+/* bloom, ACES tone mapping, FXAA and gamma 2.2 */
+pub fn standard_chain() -> Vec<PostPass> {
+    vec![
+        PostPass::Bloom { threshold: default_threshold(), intensity: default_bloom_intensity(), blur_passes: default_blur_passes() },
+        PostPass::ToneMap { operator: ToneMapOperator::Aces, exposure: default_exposure() },
+        PostPass::Fxaa,
+        PostPass::Gamma { gamma: default_gamma() },
+    ]
+}
+
+/* the built in passes' fragment shaders are files in this folder under the shader folder,
+along with the vertex shader every pass shares */
+const POST_SHADER_FOLDER: &str = "post_process";
+
+/* texture units the passes' second input is bound at, the first is always at 0 */
+const SECOND_INPUT_UNIT: GLenum = 1;
+
+/* the targets and shaders GlBackend post-processes with */
+pub struct GlPostProcess {
+    shader_folder_path: String,
+    /* keyed by fragment shader path, compiled the first time a pass needs them */
+    programs: HashMap<String, ShaderProgram>,
+    /* LUTs keyed by path, with their N */
+    luts: HashMap<String, (Texture, u32)>,
+    /* shaders and LUTs that couldn't be loaded, their passes are skipped without warning again */
+    failed: HashSet<String>,
+    /* the scene is drawn into the first, which has depth, then passes go back and forth between them */
+    targets: Vec<RenderTexture>,
+    /* half size, bloom's bright parts and their blur */
+    bloom_targets: Vec<RenderTexture>,
+    /* a size the targets couldn't be made at, not tried again every frame */
+    failed_size: Option<(u32, u32)>,
+    /* the fullscreen triangle comes from gl_VertexID, but GL still wants a vertex array bound */
+    empty_vao: VertexArray,
+}
+impl GlPostProcess {
+    pub fn new(shader_folder_path: &str) -> Result<Self, String> {
+        Ok(Self {
+            shader_folder_path: shader_folder_path.to_string(),
+            programs: HashMap::new(),
+            luts: HashMap::new(),
+            failed: HashSet::new(),
+            targets: vec![],
+            bloom_targets: vec![],
+            failed_size: None,
+            empty_vao: VertexArray::new().ok_or_else(|| "Couldn't make a new vertex array".to_string())?,
+        })
+    }
+
+    /* binds the target the scene is drawn into, made at this size if it isn't already.
+    false when it can't be made, the frame is then drawn without post-processing */
+    pub fn begin(&mut self, (width, height): (u32, u32)) -> bool {
+        if self.failed_size == Some((width, height)) {
+            return false;
+        }
+        if self.targets.first().map(|target| (target.width, target.height)) != Some((width, height)) {
+            if let Err(e) = self.make_targets(width, height) {
+                warn!("No post-processing: {}", e);
+                self.failed_size = Some((width, height));
+                return false;
+            }
+        }
+        self.targets[0].bind();
+        true
+    }
+
+    fn make_targets(&mut self, width: u32, height: u32) -> Result<(), String> {
+        for target in self.targets.drain(..).chain(self.bloom_targets.drain(..)) {
+            target.delete();
+        }
+        let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+        self.targets = vec![RenderTexture::new(width, height, true)?, RenderTexture::new(width, height, false)?];
+        self.bloom_targets = vec![RenderTexture::new(half_width, half_height, false)?, RenderTexture::new(half_width, half_height, false)?];
+        Ok(())
+    }
+
+    fn builtin(&self, name: &str) -> String {
+        format!("{}/{}/{}.GLSL", self.shader_folder_path, POST_SHADER_FOLDER, name)
+    }
+
+    /* the fragment shaders a pass draws with, its last one draws the pass's result */
+    fn fragments_of(&self, pass: &PostPass) -> Vec<String> {
+        match pass {
+            PostPass::Bloom { .. } => vec![self.builtin("bloom_extract"), self.builtin("blur"), self.builtin("bloom_combine")],
+            PostPass::ToneMap { .. } => vec![self.builtin("tonemap")],
+            PostPass::Gamma { .. } => vec![self.builtin("gamma")],
+            PostPass::Fxaa => vec![self.builtin("fxaa")],
+            PostPass::Vignette { .. } => vec![self.builtin("vignette")],
+            PostPass::ColorGrade { .. } => vec![self.builtin("color_grade")],
+            PostPass::Custom { fragment, .. } => vec![fragment.clone()],
+        }
+    }
+
+    /* compiles what the pass needs, false when something of it can't be loaded */
+    fn load(&mut self, pass: &PostPass) -> bool {
+        let mut loaded = true;
+        for fragment in self.fragments_of(pass) {
+            loaded &= self.load_program(&fragment);
+        }
+        if let PostPass::ColorGrade { lut, .. } = pass {
+            loaded &= self.load_lut(lut);
+        }
+        loaded
+    }
+
+    fn load_program(&mut self, fragment: &str) -> bool {
+        if self.programs.contains_key(fragment) {
+            return true;
+        }
+        if self.failed.contains(fragment) {
+            return false;
+        }
+        match ShaderProgram::from_files(&self.builtin("vertex"), fragment) {
+            Ok(program) => {
+                program.set_int_bool(UNI_ID[UniEnum::Scene as usize], 0);
+                program.set_int_bool(UNI_ID[UniEnum::Bloom as usize], SECOND_INPUT_UNIT as GLint);
+                program.set_int_bool(UNI_ID[UniEnum::Lut as usize], SECOND_INPUT_UNIT as GLint);
+                self.programs.insert(fragment.to_string(), program);
+                true
+            },
+            Err(e) => {
+                warn!("Skipping post-processing pass {}: {}", fragment, e);
+                self.failed.insert(fragment.to_string());
+                false
+            },
+        }
+    }
+
+    fn load_lut(&mut self, path: &str) -> bool {
+        if self.luts.contains_key(path) {
+            return true;
+        }
+        if self.failed.contains(path) {
+            return false;
+        }
+        let lut = TextureData::from_file(path).and_then(|texture| {
+            let (width, height) = (texture.image.width(), texture.image.height());
+            if height == 0 || width != height * height {
+                return Err(format!("LUT {} is {}x{}, it has to be N^2 x N", path, width, height));
+            }
+            /* not flipped like other textures, the first row is green 0 */
+            let gl_texture = Texture::new(GL_TEXTURE0).ok_or_else(|| "Couldn't make a new texture".to_string())?;
+            gl_texture.bind_and_set_params();
+            gl_texture.bind_and_set_data(height as i32, width as i32, texture.image.as_raw(), true);
+            Ok((gl_texture, height))
+        });
+        match lut {
+            Ok(lut) => {
+                self.luts.insert(path.to_string(), lut);
+                true
+            },
+            Err(e) => {
+                warn!("Skipping color grading: {}", e);
+                self.failed.insert(path.to_string());
+                false
+            },
+        }
+    }
+
+    /* runs the passes over the scene drawn since begin, the last one drawing into whatever
+    bind_output binds. Passes that can't be loaded are left out */
+    pub fn run(&mut self, passes: &[PostPass], bind_output: &dyn Fn()) {
+        let passes: Vec<&PostPass> = passes.iter().filter(|pass| self.load(pass)).collect();
+        let copy = self.builtin("copy");
+        if passes.is_empty() && !self.load_program(&copy) {
+            return;
+        }
+        unsafe { glDisable(GL_DEPTH_TEST); }
+        self.empty_vao.bind();
+
+        if passes.is_empty() {
+            self.draw(&copy, &self.targets[0], None, bind_output, |_| {});
+        }
+        let mut current = 0;
+        for (i, pass) in passes.iter().enumerate() {
+            let output = if i + 1 == passes.len() { None } else { Some(&self.targets[1 - current]) };
+            let input = &self.targets[current];
+            match pass {
+                PostPass::Bloom { threshold, intensity, blur_passes } => {
+                    self.bloom(input, *threshold, *blur_passes);
+                    self.bloom_targets[0].color.bind_to_unit(GL_TEXTURE0 + SECOND_INPUT_UNIT);
+                    self.draw(&self.builtin("bloom_combine"), input, output, bind_output, |program| {
+                        program.set_1_float(UNI_ID[UniEnum::Intensity as usize], *intensity);
+                    });
+                },
+                PostPass::ToneMap { operator, exposure } => {
+                    self.draw(&self.builtin("tonemap"), input, output, bind_output, |program| {
+                        program.set_int_bool(UNI_ID[UniEnum::TonemapOperator as usize], *operator as GLint);
+                        program.set_1_float(UNI_ID[UniEnum::Exposure as usize], *exposure);
+                    });
+                },
+                PostPass::Gamma { gamma } => {
+                    self.draw(&self.builtin("gamma"), input, output, bind_output, |program| {
+                        program.set_1_float(UNI_ID[UniEnum::Gamma as usize], *gamma);
+                    });
+                },
+                PostPass::Fxaa => {
+                    self.draw(&self.builtin("fxaa"), input, output, bind_output, |_| {});
+                },
+                PostPass::Vignette { strength, radius } => {
+                    self.draw(&self.builtin("vignette"), input, output, bind_output, |program| {
+                        program.set_1_float(UNI_ID[UniEnum::Strength as usize], *strength);
+                        program.set_1_float(UNI_ID[UniEnum::Radius as usize], *radius);
+                    });
+                },
+                PostPass::ColorGrade { lut, strength } => {
+                    let (lut_texture, lut_size) = &self.luts[lut];
+                    lut_texture.bind_to_unit(GL_TEXTURE0 + SECOND_INPUT_UNIT);
+                    self.draw(&self.builtin("color_grade"), input, output, bind_output, |program| {
+                        program.set_1_float(UNI_ID[UniEnum::LutSize as usize], *lut_size as f32);
+                        program.set_1_float(UNI_ID[UniEnum::Strength as usize], *strength);
+                    });
+                },
+                PostPass::Custom { fragment, params } => {
+                    self.draw(fragment, input, output, bind_output, |program| {
+                        for (name, value) in params {
+                            program.set_1_float(&format!("{}\0", name), *value);
+                        }
+                    });
+                },
+            }
+            current = 1 - current;
+        }
+
+        VertexArray::clear_binding();
+        unsafe { glEnable(GL_DEPTH_TEST); }
+    }
+
+    /* leaves the blurred bright parts of input in bloom_targets[0] */
+    fn bloom(&self, input: &RenderTexture, threshold: f32, blur_passes: u32) {
+        let (bright, blurred) = (&self.bloom_targets[0], &self.bloom_targets[1]);
+        self.draw(&self.builtin("bloom_extract"), input, Some(bright), &|| {}, |program| {
+            program.set_1_float(UNI_ID[UniEnum::Threshold as usize], threshold);
+        });
+        /* a separable gaussian, across then down */
+        let blur = self.builtin("blur");
+        for _ in 0..blur_passes {
+            self.draw(&blur, bright, Some(blurred), &|| {}, |program| {
+                program.set_2_float(UNI_ID[UniEnum::Direction as usize], 1.0, 0.0);
+            });
+            self.draw(&blur, blurred, Some(bright), &|| {}, |program| {
+                program.set_2_float(UNI_ID[UniEnum::Direction as usize], 0.0, 1.0);
+            });
+        }
+    }
+
+    /* one fullscreen triangle reading input into output, or into what bind_output binds for None */
+    fn draw(
+        &self,
+        fragment: &str,
+        input: &RenderTexture,
+        output: Option<&RenderTexture>,
+        bind_output: &dyn Fn(),
+        set_uniforms: impl Fn(&ShaderProgram),
+    ) {
+        let program = match self.programs.get(fragment) {
+            Some(program) => program,
+            None => return,
+        };
+        match output {
+            Some(output) => output.bind(),
+            None => bind_output(),
+        }
+        input.color.bind_to_unit(GL_TEXTURE0);
+        program.set_2_float(UNI_ID[UniEnum::TexelSize as usize], 1.0 / input.width as f32, 1.0 / input.height as f32);
+        set_uniforms(program);
+        program.use_program();
+        unsafe { glDrawArrays(GL_TRIANGLES, 0, 3); }
+    }
+
+    pub fn delete(&mut self) {
+        for target in self.targets.drain(..).chain(self.bloom_targets.drain(..)) {
+            target.delete();
+        }
+        for (_, (lut, _)) in self.luts.drain() {
+            lut.delete();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene_loader::SceneDesc;
+
+    #[test]
+    fn scene_files_leave_out_fields_that_take_their_defaults() {
+        let scene: SceneDesc = ron::from_str(
+            "(post_process: [Bloom(), ToneMap(operator: Aces), Fxaa, Gamma()])"
+        ).unwrap();
+        assert_eq!(scene.post_process, standard_chain());
+
+        let scene: SceneDesc = ron::from_str(r#"(post_process: [
+            Bloom(threshold: 2.0),
+            Vignette(radius: 0.3),
+            ColorGrade(lut: "luts/warm.png"),
+            Custom(fragment: "shaders/wave.GLSL", params: {"speed": 2.0}),
+        ])"#).unwrap();
+        assert_eq!(scene.post_process, vec![
+            PostPass::Bloom { threshold: 2.0, intensity: default_bloom_intensity(), blur_passes: default_blur_passes() },
+            PostPass::Vignette { strength: default_vignette_strength(), radius: 0.3 },
+            PostPass::ColorGrade { lut: "luts/warm.png".to_string(), strength: default_grade_strength() },
+            PostPass::custom("shaders/wave.GLSL").with_param("speed", 2.0),
+        ]);
+
+        let scene: SceneDesc = ron::from_str("()").unwrap();
+        assert!(scene.post_process.is_empty());
+    }
+
+    #[test]
+    fn passes_round_trip_through_ron() {
+        let mut passes = standard_chain();
+        passes.push(PostPass::custom("shaders/wave.GLSL").with_param("speed", 2.0));
+        let text = ron::to_string(&passes).unwrap();
+        assert_eq!(ron::from_str::<Vec<PostPass>>(&text).unwrap(), passes);
+    }
+
+    #[test]
+    fn with_param_only_changes_custom_passes() {
+        for pass in standard_chain() {
+            assert_eq!(pass.clone().with_param("speed", 2.0), pass);
+        }
+        let pass = PostPass::custom("wave.GLSL").with_param("speed", 2.0).with_param("speed", 3.0).with_param("scale", 1.0);
+        match pass {
+            PostPass::Custom { fragment, params } => {
+                assert_eq!(fragment, "wave.GLSL");
+                assert_eq!(params, BTreeMap::from([("scale".to_string(), 1.0), ("speed".to_string(), 3.0)]));
+            },
+            pass => panic!("{:?} is not Custom", pass),
+        }
+    }
+
+    #[test]
+    fn standard_chain_tone_maps_before_gamma() {
+        let chain = standard_chain();
+        assert!(matches!(chain[0], PostPass::Bloom { .. }));
+        assert_eq!(chain[1], PostPass::ToneMap { operator: ToneMapOperator::Aces, exposure: 1.0 });
+        assert_eq!(chain[2], PostPass::Fxaa);
+        assert_eq!(chain[3], PostPass::Gamma { gamma: 2.2 });
+    }
+}
@@ -0,0 +1,901 @@
+use std::str::FromStr;
+use std::collections::{BTreeMap, HashMap};
+use ogl33::*;
+use log::warn;
+use image::RgbaImage;
+use ultraviolet::{mat, vec};
+use core::{
+    convert::TryInto,
+    mem::size_of
+};
+use crate::gllib::*;
+use crate::ecs::*;
+use crate::lights::*;
+use crate::shadows::*;
+use crate::materials::*;
+use crate::bounds::*;
+use crate::render_queue::*;
+use crate::environment::*;
+use crate::post_process::*;
+
+/* Everything that draws goes through a RenderBackend. The Context keeps the CPU side of
+models (meshes and their materials, see materials.rs) and hands each one to its backend once when loaded,
+after that draws refer to them by index. Every frame render_frame has the backend render
+the shadow map when a light casts shadows, gives it the camera and the scene's lights,
+then the DrawCall of every object with a model that is in view (see bounds.rs), in batches
+of objects that only differ in their transform and color, which GlBackend draws as one
+instanced draw per mesh, in the order of the frame's RenderQueue (see render_queue.rs).
+
+GlBackend is the OpenGL renderer the window uses. SoftwareBackend (see software_render.rs)
+rasterizes on the CPU with the same shading, so frames can be rendered and compared on
+machines without a GPU or GL driver. */
+
+/* an image a material samples, top row first like the file it came from */
+pub struct TextureData {
+    pub path: String,
+    pub image: RgbaImage,
+}
+impl TextureData {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("Could not read texture {}: {}", path, e))?
+            .to_rgba8();
+        Ok(Self { path: path.to_string(), image })
+    }
+    pub fn has_transparency(&self) -> bool {
+        self.image.pixels().any(|pixel| pixel.0[3] < 255)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapSlot {
+    Diffuse,
+    Specular,
+    Bump,
+}
+impl MapSlot {
+    /* turns on the map's code in param_blinn_phong_shader */
+    pub fn define(&self) -> &'static str {
+        match self {
+            MapSlot::Diffuse => "DIFFUSE_MAP",
+            MapSlot::Specular => "SPECULAR_MAP",
+            MapSlot::Bump => "BUMP_MAP",
+        }
+    }
+    pub fn uniform(&self) -> &'static str {
+        match self {
+            MapSlot::Diffuse => UNI_ID[UniEnum::DiffuseMap as usize],
+            MapSlot::Specular => UNI_ID[UniEnum::SpecularMap as usize],
+            MapSlot::Bump => UNI_ID[UniEnum::BumpMap as usize],
+        }
+    }
+    pub fn texture_unit(&self) -> GLenum {
+        match self {
+            MapSlot::Diffuse => 0,
+            MapSlot::Specular => 1,
+            MapSlot::Bump => 2,
+        }
+    }
+}
+
+/* what every draw of a frame shares */
+#[derive(Debug, Clone)]
+pub struct FrameParams {
+    pub view: mat::Mat4,
+    pub projection: mat::Mat4,
+    pub view_pos: vec::Vec3,
+    /* at most MAX_LIGHTS, see gather_lights */
+    pub lights: Vec<WorldLight>,
+    pub shadow: Option<ShadowParams>,
+    pub clear_color: [f32; 4],
+    pub environment: Option<Environment>,
+    /* see post_process.rs, empty to draw straight into the target */
+    pub post_process: Vec<PostPass>,
+}
+impl FrameParams {
+    pub fn new(camera: &CameraParams, lights: Vec<WorldLight>, clear_color: [f32; 4], environment: Option<Environment>) -> Self {
+        Self {
+            view: camera.view_matrix(),
+            projection: camera.projection,
+            view_pos: camera.view_pos,
+            shadow: ShadowParams::for_frame(&lights, camera),
+            lights,
+            clear_color,
+            environment,
+            post_process: vec![],
+        }
+    }
+}
+
+/* one object's model, drawn with its world transform */
+#[derive(Clone)]
+pub struct DrawCall<'a> {
+    pub mesh: usize,
+    /* world matrix of the object times the model's own matrix, and the same for rotation */
+    pub model: mat::Mat4,
+    pub rotation: mat::Mat4,
+    pub object: &'a DrawableObject,
+    /* the model's bounds in the world, None when the mesh has none */
+    pub bounds: Option<Bounds>,
+}
+impl DrawCall<'_> {
+    pub fn visible_in(&self, frustum: &Frustum) -> bool {
+        self.bounds.is_none_or(|bounds| frustum.intersects(&bounds))
+    }
+}
+
+/* what every instance of a batch gets from the instance buffer: model and rotation matrices,
+then the ambient and diffuse colors of a MaterialOverride, w is 1 where there is one */
+pub type InstanceData = [f32; 16 + 16 + 4 + 4];
+
+pub fn instance_data(call: &DrawCall) -> InstanceData {
+    let material_override = &call.object.material_override;
+    let color = |color: Option<vec::Vec3>| match color {
+        Some(color) => [color.x, color.y, color.z, 1.0],
+        None => [0.0; 4],
+    };
+    let mut data = [0.0; 40];
+    data[0..16].copy_from_slice(call.model.as_slice());
+    data[16..32].copy_from_slice(call.rotation.as_slice());
+    data[32..36].copy_from_slice(&color(material_override.ambient));
+    data[36..40].copy_from_slice(&color(material_override.diffuse));
+    data
+}
+
+/* what calls need to share to be drawn together, everything but the transform and color */
+#[derive(PartialEq, Eq, Hash)]
+struct BatchKey {
+    mesh: usize,
+    material: Option<usize>,
+    specular: Option<[u32; 3]>,
+    shininess: Option<u32>,
+    dissolve: Option<u32>,
+    opacity: Option<u32>,
+    receive_shadows: bool,
+}
+impl BatchKey {
+    fn of(call: &DrawCall) -> Self {
+        let material_override = &call.object.material_override;
+        Self {
+            mesh: call.mesh,
+            material: material_override.material,
+            specular: material_override.specular.map(|specular| [specular.x.to_bits(), specular.y.to_bits(), specular.z.to_bits()]),
+            shininess: material_override.shininess.map(f32::to_bits),
+            dissolve: material_override.dissolve.map(f32::to_bits),
+            opacity: material_override.opacity.map(f32::to_bits),
+            receive_shadows: call.object.receive_shadows,
+        }
+    }
+}
+
+/* groups the calls into batches, in the order each batch's first call comes in */
+pub fn batch_calls<'a>(calls: &[DrawCall<'a>]) -> Vec<Vec<DrawCall<'a>>> {
+    let mut batch_map: HashMap<BatchKey, usize> = HashMap::new();
+    let mut batches: Vec<Vec<DrawCall>> = vec![];
+    for call in calls {
+        let batch_idx = *batch_map.entry(BatchKey::of(call)).or_insert_with(|| {
+            batches.push(vec![]);
+            batches.len() - 1
+        });
+        batches[batch_idx].push(call.clone());
+    }
+    batches
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderTarget {
+    /* the window, or for backends without one an image the size of the viewport */
+    Screen,
+    /* an offscreen image of this width and height */
+    Image(u32, u32),
+}
+
+pub trait RenderBackend {
+    fn name(&self) -> &'static str;
+
+    /* textures, materials and meshes get indices in the order they are uploaded,
+    material maps refer to texture indices and mesh materials to material indices */
+    fn upload_texture(&mut self, texture: &TextureData) -> Result<(), String>;
+    fn upload_material(&mut self, material: &Material) -> Result<(), String>;
+    /* redefines an uploaded material, every mesh using it is drawn with the new one */
+    fn replace_material(&mut self, material_idx: usize, material: &Material) -> Result<(), String>;
+    fn upload_mesh(&mut self, mesh: &MeshDataGroup) -> Result<(), String>;
+    /* cubemaps get indices in upload order too, FrameParams::environment refers to them */
+    fn upload_cubemap(&mut self, cubemap: &CubemapData) -> Result<(), String>;
+
+    /* where the following frames are drawn */
+    fn set_target(&mut self, target: RenderTarget) -> Result<(), String>;
+
+    /* draws the depth of the casters into the shadow map the next frame is shaded with,
+    called before begin_frame on frames that have a shadow light */
+    fn render_shadow_map(&mut self, _shadow: &ShadowParams, _casters: &[DrawCall]) {}
+
+    /* clears the target and sets up the camera and lights */
+    fn begin_frame(&mut self, frame: &FrameParams);
+    /* the shader program the material is drawn with, which the render queue sorts by */
+    fn program_of(&self, _material_idx: usize) -> usize { 0 }
+    /* draws one mesh of every call in the batch, which share everything but transform and color,
+    see RenderQueue. The scene's pre_draw runs right before the draw on backends that have shaders,
+    render_frame gives every object a batch of its own while there is one */
+    fn draw_item(&mut self, item: &DrawItem, batch: &[DrawCall], pre_draw: Option<&PreDraw>);
+    /* fills what the opaque passes left of the background with the frame's environment,
+    called between them and the transparent pass */
+    fn draw_sky(&mut self);
+    fn end_frame(&mut self) {}
+
+    /* what the last frame left in the current target, top row first */
+    fn read_pixels(&mut self) -> Result<RgbaImage, String>;
+}
+
+/* draws every object that has a model and is in view, with the world transforms
+from the last update_world_transforms */
+pub fn render_frame(ctx: &mut Context) {
+    let renderer = match &mut ctx.renderer {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let lights = gather_lights(&ctx.game_obj_store, ctx.camera.light_position);
+    let mut frame = FrameParams::new(&ctx.camera, lights, ctx.clear_color, ctx.environment);
+    frame.post_process = ctx.post_process.clone();
+    let calls: Vec<DrawCall> = join(&ctx.game_obj_store.drawables, &ctx.game_obj_store.transforms)
+        .map(|(_, draw, transform)| {
+            let model = transform.world_matrix * draw.model_matrix();
+            DrawCall {
+                mesh: draw.drawable_group_idx,
+                model,
+                rotation: transform.world_rotation * draw.rotation_matrix(),
+                object: draw,
+                bounds: ctx.mesh_bounds.get(draw.drawable_group_idx).map(|bounds| bounds.transformed(&model)),
+            }
+        })
+        .collect();
+    let mut stats = RenderStats { objects: calls.len(), ..RenderStats::default() };
+
+    /* casters out of view can still throw shadows into it, so they are culled by what the light sees */
+    if let Some(shadow) = &frame.shadow {
+        let light_frustum = Frustum::from_view_projection(&shadow.light_space);
+        let casters: Vec<DrawCall> = calls.iter()
+            .filter(|call| call.object.cast_shadows && call.visible_in(&light_frustum))
+            .cloned()
+            .collect();
+        stats.shadow_casters_drawn = casters.len();
+        stats.shadow_casters_culled = calls.iter().filter(|call| call.object.cast_shadows).count() - casters.len();
+        renderer.render_shadow_map(shadow, &casters);
+    }
+
+    let frustum = Frustum::from_view_projection(&(frame.projection * frame.view));
+    let visible: Vec<DrawCall> = calls.into_iter().filter(|call| call.visible_in(&frustum)).collect();
+    /* the hook is set up for one object at a time */
+    let batches = match ctx.pre_draw {
+        Some(_) => visible.iter().map(|call| vec![call.clone()]).collect(),
+        None => batch_calls(&visible),
+    };
+    stats.drawn = visible.len();
+    stats.culled = stats.objects - stats.drawn;
+    stats.batches = batches.len();
+
+    let mut queue = RenderQueue::new();
+    for batch in batches {
+        queue.push_batch(batch, &ctx.meshes, &ctx.materials, renderer.as_ref(), (ctx.camera.view_pos, ctx.camera.look_dir()));
+    }
+    queue.sort();
+    stats.draws = queue.items.len();
+
+    renderer.begin_frame(&frame);
+    let transparent_start = queue.items.partition_point(|item| item.pass != RenderPass::Transparent);
+    for item in &queue.items[..transparent_start] {
+        renderer.draw_item(item, &queue.batches[item.batch], ctx.pre_draw.as_ref());
+    }
+    renderer.draw_sky();
+    for item in &queue.items[transparent_start..] {
+        renderer.draw_item(item, &queue.batches[item.batch], ctx.pre_draw.as_ref());
+    }
+    renderer.end_frame();
+    ctx.render_stats = stats;
+}
+
+/* a material as GlBackend draws it */
+struct GlMaterial {
+    /* index into GlBackend::programs */
+    program: usize,
+    params: MaterialParams,
+    /* (texture index, unit) for every map the material has */
+    maps: Vec<(usize, GLenum)>,
+    alpha_cutoff: Option<f32>,
+    blend: BlendMode,
+    depth_write: bool,
+}
+
+fn set_material_params(shader: &ShaderProgram, params: &MaterialParams) {
+    let [a1, a2, a3] = *params.ambient.as_array();
+    let [d1, d2, d3] = *params.diffuse.as_array();
+    let [s1, s2, s3] = *params.specular.as_array();
+    shader.set_3_float(UNI_ID[UniEnum::AmbientColor as usize], a1, a2, a3);
+    shader.set_3_float(UNI_ID[UniEnum::DiffuseColor as usize], d1, d2, d3);
+    shader.set_3_float(UNI_ID[UniEnum::SpecularColor as usize], s1, s2, s3);
+    shader.set_1_float(UNI_ID[UniEnum::Shininess as usize], params.shininess);
+    shader.set_1_float(UNI_ID[UniEnum::OpticalDensity as usize], params.optical_density);
+    shader.set_1_float(UNI_ID[UniEnum::Dissolve as usize], params.dissolve);
+    shader.set_1_float(UNI_ID[UniEnum::Reflectivity as usize], params.reflectivity);
+}
+
+/* binding point of the Lights uniform block */
+const LIGHTS_BINDING: GLuint = 0;
+/* texture unit the shadow map is sampled from, kept clear of the units materials use */
+const SHADOW_MAP_UNIT: GLenum = 7;
+/* and the environment cubemap, a sampler type of its own needs a unit of its own */
+const ENVIRONMENT_MAP_UNIT: GLenum = 6;
+
+/* corners of the skybox cube and its sides as two triangles each */
+const SKY_CUBE: [[f32; 3]; 8] = [
+    [-1.0, -1.0, -1.0], [1.0, -1.0, -1.0], [1.0, 1.0, -1.0], [-1.0, 1.0, -1.0],
+    [-1.0, -1.0, 1.0], [1.0, -1.0, 1.0], [1.0, 1.0, 1.0], [-1.0, 1.0, 1.0],
+];
+const SKY_CUBE_INDICES: [u32; 36] = [
+    0, 2, 1, 0, 3, 2,
+    4, 5, 6, 4, 6, 7,
+    0, 1, 5, 0, 5, 4,
+    3, 6, 2, 3, 7, 6,
+    0, 4, 7, 0, 7, 3,
+    1, 2, 6, 1, 6, 5,
+];
+
+/* One shader program per shader folder and set of defines, shared by the materials using it,
+one VAO per mesh. GL has to be loaded before it is created. Material parameters are set
+before each draw, the lights live in one uniform buffer all programs read, written once per frame.
+Every draw is instanced, the InstanceData of a batch is written to one instance buffer
+every mesh's VAO reads from, so a batch is a single draw per mesh however many objects it has */
+pub struct GlBackend {
+    pub shader_folder_path: String,
+    pub shadow_depth_shader_folder: String,
+    pub textures: Vec<Texture>,
+    pub programs: Vec<ShaderProgram>,
+    /* keyed by shader folder and defines */
+    program_map: HashMap<(String, Vec<&'static str>), usize>,
+    materials: Vec<GlMaterial>,
+    pub drawable_groups: Vec<DrawableGroup>,
+    instance_buffer: Buffer,
+    /* the RenderQueue batch whose instances are in instance_buffer */
+    uploaded_batch: Option<usize>,
+    /* what set_blend_state was last called with */
+    blend_state: (Option<BlendMode>, bool),
+    lights_ubo: Buffer,
+    shadow_program: ShaderProgram,
+    pub cubemaps: Vec<Cubemap>,
+    sky_program: ShaderProgram,
+    /* vao, vbo and ebo of the skybox cube */
+    sky_cube: (VertexArray, Buffer, Buffer),
+    /* of the frame being drawn */
+    environment: Option<Environment>,
+    post_process: GlPostProcess,
+    /* the frame's passes, run at end_frame when post_process could begin */
+    post_passes: Vec<PostPass>,
+    /* made on the first frame with a shadow light, remade when the resolution changes */
+    shadow_map: Option<ShadowMap>,
+    /* a resolution the shadow map couldn't be made at, not tried again every frame */
+    failed_shadow_resolution: Option<u32>,
+    screen_size: (u32, u32),
+    target_size: (u32, u32),
+    /* bound while rendering to an image */
+    framebuffer: Option<Framebuffer>,
+}
+impl GlBackend {
+    pub fn new(screen_width: u32, screen_height: u32) -> Result<Self, String> {
+        let def_shader_folder_path = String::from_str("src/shaders").expect("string failed");
+        let def_shadow_depth_shader_folder = String::from_str("shadow_depth_shader").expect("string failed");
+        unsafe { glViewport(0, 0, screen_width as GLsizei, screen_height as GLsizei); }
+
+        let instance_buffer = Buffer::new().ok_or_else(|| "Couldn't make the instance buffer".to_string())?;
+        let lights_ubo = Buffer::new().ok_or_else(|| "Couldn't make the lights buffer".to_string())?;
+        lights_ubo.bind(BufferType::Uniform);
+        buffer_data(BufferType::Uniform, bytemuck::cast_slice(pack_lights(&[]).as_slice()), GL_DYNAMIC_DRAW);
+        lights_ubo.bind_base(BufferType::Uniform, LIGHTS_BINDING);
+        Buffer::unbind(BufferType::Uniform);
+
+        let shadow_program = ShaderProgram::from_files(
+            &format!("{}/{}/{}", def_shader_folder_path, def_shadow_depth_shader_folder, "vertex.GLSL"),
+            &format!("{}/{}/{}", def_shader_folder_path, def_shadow_depth_shader_folder, "fragment.GLSL"),
+        )?;
+        let sky_program = ShaderProgram::from_files(
+            &format!("{}/{}/{}", def_shader_folder_path, "skybox_shader", "vertex.GLSL"),
+            &format!("{}/{}/{}", def_shader_folder_path, "skybox_shader", "fragment.GLSL"),
+        )?;
+        sky_program.set_int_bool(UNI_ID[UniEnum::EnvironmentMap as usize], ENVIRONMENT_MAP_UNIT as GLint);
+        let sky_cube = {
+            let vao = VertexArray::new().ok_or_else(|| "Couldn't make a new vertex array".to_string())?;
+            vao.bind();
+            let vbo = Buffer::new().ok_or_else(|| "Couldn't make a new buffer".to_string())?;
+            vbo.bind(BufferType::Array);
+            buffer_data(BufferType::Array, bytemuck::cast_slice(SKY_CUBE.as_slice()), GL_STATIC_DRAW);
+            let ebo = Buffer::new().ok_or_else(|| "Couldn't make a new buffer".to_string())?;
+            ebo.bind(BufferType::ElementArray);
+            buffer_data(BufferType::ElementArray, bytemuck::cast_slice(SKY_CUBE_INDICES.as_slice()), GL_STATIC_DRAW);
+            unsafe {
+                glVertexAttribPointer(0, 3, GL_FLOAT, GL_FALSE, size_of::<[f32; 3]>().try_into().unwrap(), std::ptr::null());
+                glEnableVertexAttribArray(0);
+            }
+            VertexArray::clear_binding();
+            (vao, vbo, ebo)
+        };
+        let post_process = GlPostProcess::new(&def_shader_folder_path)?;
+
+        Ok(Self {
+            shader_folder_path: def_shader_folder_path,
+            shadow_depth_shader_folder: def_shadow_depth_shader_folder,
+            textures: vec![],
+            programs: vec![],
+            program_map: HashMap::new(),
+            materials: vec![],
+            drawable_groups: vec![],
+            instance_buffer,
+            uploaded_batch: None,
+            blend_state: (None, true),
+            lights_ubo,
+            shadow_program,
+            cubemaps: vec![],
+            sky_program,
+            sky_cube,
+            environment: None,
+            post_process,
+            post_passes: vec![],
+            shadow_map: None,
+            failed_shadow_resolution: None,
+            screen_size: (screen_width, screen_height),
+            target_size: (screen_width, screen_height),
+            framebuffer: None,
+        })
+    }
+
+    /* the program for the shader with these maps turned on, compiled the first time it's asked for */
+    fn program_for(&mut self, shader: &str, maps: &[MapSlot], alpha_test: bool) -> Result<usize, String> {
+        let mut defines: Vec<&'static str> = maps.iter().map(|slot| slot.define()).collect();
+        if alpha_test {
+            defines.push("ALPHA_TEST");
+        }
+        let key = (shader.to_string(), defines);
+        if let Some(program_idx) = self.program_map.get(&key) {
+            return Ok(*program_idx);
+        }
+        let program = ShaderProgram::from_files_with_defines(
+            &format!("{}/{}/{}", self.shader_folder_path, shader, "vertex.GLSL"),
+            &format!("{}/{}/{}", self.shader_folder_path, shader, "fragment.GLSL"),
+            &key.1,
+        )?;
+        /* view, projection and the shadow uniforms are set at the start of every frame */
+        program.bind_uniform_block("Lights\0", LIGHTS_BINDING);
+        program.set_int_bool(UNI_ID[UniEnum::ShadowMap as usize], SHADOW_MAP_UNIT as GLint);
+        program.set_int_bool(UNI_ID[UniEnum::EnvironmentMap as usize], ENVIRONMENT_MAP_UNIT as GLint);
+        program.set_int_bool(UNI_ID[UniEnum::ShadowLight as usize], -1);
+        for slot in maps {
+            program.set_int_bool(slot.uniform(), slot.texture_unit() as GLint);
+        }
+        let program_idx = self.programs.len();
+        self.programs.push(program);
+        self.program_map.insert(key, program_idx);
+        Ok(program_idx)
+    }
+
+    fn gl_material(&mut self, material: &Material) -> Result<GlMaterial, String> {
+        let maps: Vec<(usize, MapSlot)> = material.maps().iter()
+            .filter_map(|(texture_idx, slot)| texture_idx.map(|texture_idx| (texture_idx, *slot)))
+            .collect();
+        let slots: Vec<MapSlot> = maps.iter().map(|(_, slot)| *slot).collect();
+        Ok(GlMaterial {
+            program: self.program_for(&material.shader, &slots, material.alpha_cutoff.is_some() && material.diffuse_map.is_some())?,
+            params: material.params,
+            maps: maps.iter().map(|(texture_idx, slot)| (*texture_idx, GL_TEXTURE0 + slot.texture_unit())).collect(),
+            alpha_cutoff: material.alpha_cutoff,
+            blend: material.blend,
+            depth_write: material.depth_write,
+        })
+    }
+
+    /* writes the instances of the batch into the instance buffer */
+    fn use_blend_state(&mut self, blend_state: (Option<BlendMode>, bool)) {
+        if self.blend_state != blend_state {
+            set_blend_state(blend_state.0, blend_state.1);
+            self.blend_state = blend_state;
+        }
+    }
+
+    fn upload_instances(&self, batch: &[DrawCall]) {
+        let data: Vec<f32> = batch.iter().flat_map(instance_data).collect();
+        self.instance_buffer.bind(BufferType::Array);
+        buffer_data(BufferType::Array, bytemuck::cast_slice(data.as_slice()), GL_STREAM_DRAW);
+    }
+
+    /* back to the current target after drawing somewhere else */
+    fn bind_target(&self) {
+        bind_target(&self.framebuffer, self.screen_size);
+    }
+}
+
+/* the image target when there is one, otherwise the window */
+fn bind_target(framebuffer: &Option<Framebuffer>, screen_size: (u32, u32)) {
+    match framebuffer {
+        Some(framebuffer) => framebuffer.bind(),
+        None => {
+            Framebuffer::clear_binding();
+            let (width, height) = screen_size;
+            unsafe { glViewport(0, 0, width as GLsizei, height as GLsizei); }
+        },
+    }
+}
+impl RenderBackend for GlBackend {
+    fn name(&self) -> &'static str { "OpenGL" }
+
+    fn upload_texture(&mut self, texture: &TextureData) -> Result<(), String> {
+        /* GL's first row is the bottom one */
+        let image = image::imageops::flip_vertical(&texture.image);
+        let gl_texture = Texture::new(GL_TEXTURE0).ok_or_else(|| "Couldn't make a new texture".to_string())?;
+        gl_texture.bind_and_set_params();
+        gl_texture.bind_and_set_data(image.height() as i32, image.width() as i32, image.as_raw(), true);
+        self.textures.push(gl_texture);
+        Ok(())
+    }
+
+    fn upload_material(&mut self, material: &Material) -> Result<(), String> {
+        let gl_material = self.gl_material(material)?;
+        self.materials.push(gl_material);
+        Ok(())
+    }
+
+    fn replace_material(&mut self, material_idx: usize, material: &Material) -> Result<(), String> {
+        if material_idx >= self.materials.len() {
+            return Err(format!("No material {} to replace", material_idx));
+        }
+        self.materials[material_idx] = self.gl_material(material)?;
+        Ok(())
+    }
+
+    fn upload_mesh(&mut self, mesh_data: &MeshDataGroup) -> Result<(), String> {
+        let mut drawable_group = vec![];
+        for mesh in &mesh_data.0 {
+            /* generate a Vertex Array Object and store ref in mutable vao variable
+            and bind the VAO making it the active */
+            let (vao, vbo, ebo) = {
+                let vao = VertexArray::new().ok_or_else(|| "Couldn't make a new VAO".to_string())?;
+                vao.bind();
+
+                /* generate a Buffer Object and store req in mutable vbo variable
+                and set the given buffer as the current active Array Buffer
+                and then initializes target's active buffer's storage with a size and initial data and a hint to its usage
+                and then initialize active Array Buffer with size of vertex array and pointer to vertex array */
+                let vbo = {
+                    let vbo = Buffer::new().ok_or_else(|| "Couldn't make a new buffer".to_string())?;
+                    vbo.bind(BufferType::Array);
+                    buffer_data(BufferType::Array, bytemuck::cast_slice(mesh.point_data.as_slice()), GL_STATIC_DRAW);
+                    vbo
+                    };
+
+                /* generate buffer to hold groups of vertexes that form triangles
+                set as the active element array buffer type
+                load in the data */
+                let ebo = {
+                    let ebo = Buffer::new().ok_or_else(|| "Couldn't make a new buffer".to_string())?;
+                    ebo.bind(BufferType::ElementArray);
+                    buffer_data(BufferType::ElementArray, bytemuck::cast_slice(mesh.point_indices.as_slice()), GL_STATIC_DRAW);
+                    ebo
+                    };
+
+                unsafe {
+                    glVertexAttribPointer(
+                        0,
+                        3,
+                        GL_FLOAT,
+                        GL_TRUE,
+                        size_of::<ModelVertex>().try_into().unwrap(),
+                        size_of::<[f32; 0]>() as *const _,
+                    );
+                    glEnableVertexAttribArray(0);
+                    glVertexAttribPointer(
+                        1,
+                        3,
+                        GL_FLOAT,
+                        GL_FALSE,
+                        size_of::<ModelVertex>().try_into().unwrap(),
+                        (size_of::<f32>() * 3) as *const _,
+                    );
+                    glEnableVertexAttribArray(1);
+                    glVertexAttribPointer(
+                        2,
+                        2,
+                        GL_FLOAT,
+                        GL_FALSE,
+                        size_of::<ModelVertex>().try_into().unwrap(),
+                        (size_of::<f32>() * 6) as *const _,
+                    );
+                    glEnableVertexAttribArray(2);
+
+                    /* InstanceData, advancing once per instance. Matrices take a location per column */
+                    self.instance_buffer.bind(BufferType::Array);
+                    for location in 3..13 {
+                        let offset = size_of::<[f32; 4]>() * (location - 3) as usize;
+                        glVertexAttribPointer(
+                            location,
+                            4,
+                            GL_FLOAT,
+                            GL_FALSE,
+                            size_of::<InstanceData>().try_into().unwrap(),
+                            offset as *const _,
+                        );
+                        glEnableVertexAttribArray(location);
+                        glVertexAttribDivisor(location, 1);
+                    }
+                }
+
+                (vao, vbo, ebo)
+            };
+            drawable_group.push(Drawable{
+                vao,
+                vbo,
+                ebo,
+                tri_count: mesh.tri_count,
+                material_idx: mesh.material_idx
+            });
+        }
+        self.drawable_groups.push(DrawableGroup(drawable_group));
+        Ok(())
+    }
+
+    fn upload_cubemap(&mut self, cubemap: &CubemapData) -> Result<(), String> {
+        self.cubemaps.push(Cubemap::from_data(cubemap.size, &cubemap.faces)?);
+        Ok(())
+    }
+
+    fn set_target(&mut self, target: RenderTarget) -> Result<(), String> {
+        if let Some(framebuffer) = self.framebuffer.take() {
+            Framebuffer::clear_binding();
+            framebuffer.delete();
+        }
+        match target {
+            RenderTarget::Screen => {
+                let (width, height) = self.screen_size;
+                unsafe { glViewport(0, 0, width as GLsizei, height as GLsizei); }
+                self.target_size = self.screen_size;
+            },
+            RenderTarget::Image(width, height) => {
+                let framebuffer = Framebuffer::new(width, height)?;
+                framebuffer.bind();
+                self.framebuffer = Some(framebuffer);
+                self.target_size = (width, height);
+            },
+        }
+        Ok(())
+    }
+
+    fn render_shadow_map(&mut self, shadow: &ShadowParams, casters: &[DrawCall]) {
+        let resolution = shadow.settings.resolution.max(1);
+        if self.failed_shadow_resolution == Some(resolution) {
+            return;
+        }
+        if self.shadow_map.as_ref().map(|shadow_map| shadow_map.size) != Some(resolution) {
+            if let Some(shadow_map) = self.shadow_map.take() {
+                shadow_map.delete();
+            }
+            match ShadowMap::new(resolution, GL_TEXTURE0 + SHADOW_MAP_UNIT) {
+                Ok(shadow_map) => self.shadow_map = Some(shadow_map),
+                Err(e) => {
+                    warn!("No shadows: {}", e);
+                    self.failed_shadow_resolution = Some(resolution);
+                    return;
+                },
+            }
+        }
+        let shadow_map = match &self.shadow_map {
+            Some(shadow_map) => shadow_map,
+            None => return,
+        };
+
+        shadow_map.bind();
+        unsafe { glClear(GL_DEPTH_BUFFER_BIT); }
+        self.shadow_program.set_4_float_matrix(UNI_ID[UniEnum::LightSpace as usize], &shadow.light_space);
+        /* only depth is drawn, so casters batch by mesh alone */
+        let mut batches: BTreeMap<usize, Vec<DrawCall>> = BTreeMap::new();
+        for call in casters {
+            batches.entry(call.mesh).or_default().push(call.clone());
+        }
+        for (mesh, batch) in &batches {
+            self.upload_instances(batch);
+            for drawable in &self.drawable_groups[*mesh].0 {
+                drawable.vao.bind();
+                unsafe { glDrawElementsInstanced(GL_TRIANGLES, drawable.tri_count as i32, GL_UNSIGNED_INT, std::ptr::null(), batch.len() as GLsizei); }
+            }
+        }
+        self.bind_target();
+    }
+
+    fn begin_frame(&mut self, frame: &FrameParams) {
+        self.uploaded_batch = None;
+        /* with post-processing the scene is drawn into its HDR target first */
+        self.post_passes = frame.post_process.clone();
+        if self.post_passes.is_empty() || !self.post_process.begin(self.target_size) {
+            self.post_passes.clear();
+            self.bind_target();
+        }
+        self.lights_ubo.bind(BufferType::Uniform);
+        buffer_sub_data(BufferType::Uniform, 0, bytemuck::cast_slice(pack_lights(&frame.lights).as_slice()));
+        Buffer::unbind(BufferType::Uniform);
+
+        let shadow = frame.shadow.filter(|_| self.shadow_map.is_some());
+        if let (Some(_), Some(shadow_map)) = (&shadow, &self.shadow_map) {
+            shadow_map.depth.activate_and_bind();
+        }
+        self.environment = frame.environment.filter(|environment| environment.cubemap < self.cubemaps.len());
+        if let Some(environment) = &self.environment {
+            self.cubemaps[environment.cubemap].bind_to_unit(GL_TEXTURE0 + ENVIRONMENT_MAP_UNIT);
+        }
+        let environment_intensity = self.environment.map_or(0.0, |environment| environment.intensity);
+
+        let [v1, v2, v3] = *(frame.view_pos.as_array());
+        for shader in &self.programs {
+            (*shader).set_3_float(UNI_ID[UniEnum::ViewPos as usize], v1, v2, v3);
+            match &shadow {
+                Some(shadow) => {
+                    (*shader).set_int_bool(UNI_ID[UniEnum::ShadowLight as usize], shadow.light_index as GLint);
+                    (*shader).set_4_float_matrix(UNI_ID[UniEnum::LightSpace as usize], &shadow.light_space);
+                    (*shader).set_1_float(UNI_ID[UniEnum::ShadowBias as usize], shadow.settings.bias);
+                    (*shader).set_1_float(UNI_ID[UniEnum::ShadowSlopeBias as usize], shadow.settings.slope_bias);
+                    (*shader).set_int_bool(UNI_ID[UniEnum::ShadowPcf as usize], shadow.settings.pcf_radius as GLint);
+                },
+                None => (*shader).set_int_bool(UNI_ID[UniEnum::ShadowLight as usize], -1),
+            }
+            (*shader).set_int_bool(UNI_ID[UniEnum::HasEnvironment as usize], self.environment.is_some() as GLint);
+            (*shader).set_1_float(UNI_ID[UniEnum::EnvironmentIntensity as usize], environment_intensity);
+            (*shader).set_4_float_matrix(UNI_ID[UniEnum::View as usize], &frame.view);
+            (*shader).set_4_float_matrix(UNI_ID[UniEnum::Projection as usize], &frame.projection);
+        }
+        self.sky_program.set_1_float(UNI_ID[UniEnum::EnvironmentIntensity as usize], environment_intensity);
+        self.sky_program.set_4_float_matrix(UNI_ID[UniEnum::View as usize], &frame.view);
+        self.sky_program.set_4_float_matrix(UNI_ID[UniEnum::Projection as usize], &frame.projection);
+
+        let [r, g, b, a] = frame.clear_color;
+        clear_color(r, g, b, a);
+        unsafe { glClear(GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT); }
+    }
+
+    fn program_of(&self, material_idx: usize) -> usize {
+        self.materials.get(material_idx).map_or(0, |material| material.program)
+    }
+
+    fn draw_item(&mut self, item: &DrawItem, batch: &[DrawCall], pre_draw: Option<&PreDraw>) {
+        let first = match batch.first() {
+            Some(first) => first,
+            None => return,
+        };
+        let blend_state = match (item.pass, self.materials.get(item.material)) {
+            (RenderPass::Transparent, Some(material)) => (Some(material.blend), material.depth_write),
+            _ => (None, true),
+        };
+        self.use_blend_state(blend_state);
+        let (drawable, material) = match (self.drawable_groups[first.mesh].0.get(item.part), self.materials.get(item.material)) {
+            (Some(drawable), Some(material)) => (drawable, material),
+            _ => return,
+        };
+        /* the items of a batch usually come one after the other, the instances only need writing once */
+        if self.uploaded_batch != Some(item.batch) {
+            self.upload_instances(batch);
+            self.uploaded_batch = Some(item.batch);
+        }
+        /* ambient and diffuse overrides come with each instance, the rest the batch shares */
+        let material_override = MaterialOverride { ambient: None, diffuse: None, ..first.object.material_override };
+        let shader = &self.programs[material.program];
+
+        shader.use_program();
+        shader.set_int_bool(UNI_ID[UniEnum::ReceiveShadows as usize], first.object.receive_shadows as GLint);
+        set_material_params(shader, &material_override.apply(&material.params));
+        if let Some(alpha_cutoff) = material.alpha_cutoff {
+            shader.set_1_float(UNI_ID[UniEnum::AlphaCutoff as usize], alpha_cutoff);
+        }
+
+        for (texture_idx, texture_unit) in &material.maps {
+            self.textures[*texture_idx].bind_to_unit(*texture_unit);
+        }
+
+        if let Some(pre_draw) = pre_draw {
+            pre_draw(shader, first.object);
+        }
+
+        drawable.vao.bind();
+        unsafe { glDrawElementsInstanced(GL_TRIANGLES, drawable.tri_count as i32, GL_UNSIGNED_INT, std::ptr::null(), batch.len() as GLsizei); }
+    }
+
+    /* on the far plane, so with LEQUAL it only passes where the depth is still cleared */
+    fn draw_sky(&mut self) {
+        if self.environment.is_none() {
+            return;
+        }
+        self.use_blend_state((None, false));
+        self.sky_program.use_program();
+        self.sky_cube.0.bind();
+        unsafe {
+            glDepthFunc(GL_LEQUAL);
+            glDrawElements(GL_TRIANGLES, SKY_CUBE_INDICES.len() as GLsizei, GL_UNSIGNED_INT, std::ptr::null());
+            glDepthFunc(GL_LESS);
+        }
+    }
+
+    /* back to how the shadow pass and clearing expect it, then the post-processing passes into the target */
+    fn end_frame(&mut self) {
+        self.use_blend_state((None, true));
+        if !self.post_passes.is_empty() {
+            let (framebuffer, screen_size) = (&self.framebuffer, self.screen_size);
+            self.post_process.run(&self.post_passes, &|| bind_target(framebuffer, screen_size));
+        }
+    }
+
+    fn read_pixels(&mut self) -> Result<RgbaImage, String> {
+        let (width, height) = self.target_size;
+        Ok(read_pixels(width, height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(mesh: usize) -> DrawableObject {
+        DrawableObject::new(vec::Vec3::zero(), vec::Vec3::zero(), vec::Vec3::one(), mesh)
+    }
+
+    fn call(object: &DrawableObject, x: f32) -> DrawCall<'_> {
+        DrawCall {
+            mesh: object.drawable_group_idx,
+            model: mat::Mat4::from_translation(vec::Vec3::new(x, 0.0, 0.0)),
+            rotation: mat::Mat4::identity(),
+            object,
+            bounds: None,
+        }
+    }
+
+    fn sizes(batches: &[Vec<DrawCall>]) -> Vec<usize> {
+        batches.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn shared_mesh_and_material_fold_into_one_batch() {
+        let plain = object(0);
+        let red = object(0).with_material_override(MaterialOverride::color(vec::Vec3::new(1.0, 0.0, 0.0)));
+        let swapped = object(0).with_material_override(MaterialOverride::material(1));
+        let calls = [call(&plain, 0.0), call(&red, 1.0), call(&swapped, 2.0), call(&plain, 3.0), call(&swapped, 4.0)];
+
+        /* colors go in the instance data, so tinted objects still batch with plain ones */
+        let batches = batch_calls(&calls);
+        assert_eq!(sizes(&batches), vec![3, 2]);
+        let xs: Vec<f32> = batches[0].iter().map(|call| call.model.cols[3].x).collect();
+        assert_eq!(xs, vec![0.0, 1.0, 3.0]);
+    }
+
+    #[test]
+    fn objects_that_differ_split() {
+        let plain = object(0);
+        let other_mesh = object(1);
+        let shiny = object(0).with_material_override(MaterialOverride::default().with_shininess(64.0));
+        let faded = object(0).with_material_override(MaterialOverride::default().with_opacity(0.5));
+        let unshadowed = object(0).with_shadows(true, false);
+        let calls = [
+            call(&plain, 0.0), call(&other_mesh, 0.0), call(&shiny, 0.0),
+            call(&faded, 0.0), call(&unshadowed, 0.0), call(&plain, 1.0),
+        ];
+        assert_eq!(sizes(&batch_calls(&calls)), vec![2, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn override_colors_fill_the_instance_data() {
+        let plain = object(0);
+        let tinted = object(0).with_material_override(MaterialOverride {
+            ambient: Some(vec::Vec3::new(0.1, 0.2, 0.3)),
+            diffuse: Some(vec::Vec3::new(0.4, 0.5, 0.6)),
+            ..MaterialOverride::default()
+        });
+
+        let data = instance_data(&call(&tinted, 2.0));
+        assert_eq!(&data[0..16], mat::Mat4::from_translation(vec::Vec3::new(2.0, 0.0, 0.0)).as_slice());
+        assert_eq!(&data[16..32], mat::Mat4::identity().as_slice());
+        assert_eq!(&data[32..36], &[0.1, 0.2, 0.3, 1.0]);
+        assert_eq!(&data[36..40], &[0.4, 0.5, 0.6, 1.0]);
+
+        let data = instance_data(&call(&plain, 0.0));
+        assert_eq!(&data[32..40], &[0.0; 8]);
+    }
+}
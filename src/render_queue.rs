@@ -0,0 +1,222 @@
+use std::cmp::Ordering;
+use serde::{Serialize, Deserialize};
+use ultraviolet::vec;
+use crate::gllib::*;
+use crate::materials::*;
+use crate::render::*;
+
+/* What a frame draws and in which order. render_frame puts every batch in view (see batch_calls)
+into a RenderQueue, which makes a DrawItem for each mesh of the batch's model. Items are drawn
+pass by pass. Opaque and alpha tested ones are sorted by shader program and material so the
+backend switches between them as little as possible, then front to back so the depth test
+can skip pixels that end up hidden. Transparent ones have to be drawn over what is behind them,
+so they aren't batched, every object gets its own item, and they are drawn back to front. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum RenderPass {
+    Opaque,
+    /* opaque but with holes where the diffuse map's alpha is below the material's alpha_cutoff */
+    AlphaTested,
+    /* dissolve below 1 */
+    Transparent,
+}
+
+/* one mesh of the model of every call in a batch */
+#[derive(Debug, Clone, Copy)]
+pub struct DrawItem {
+    pub pass: RenderPass,
+    /* the backend's program for the material, see RenderBackend::program_of */
+    pub program: usize,
+    /* index into Context::materials, with the calls' MaterialOverride already applied */
+    pub material: usize,
+    /* how far in front of the camera the batch is, its nearest object for opaque passes */
+    pub depth: f32,
+    /* index into RenderQueue::batches, items of the same batch draw the same instances */
+    pub batch: usize,
+    /* index of the mesh in the model's MeshDataGroup */
+    pub part: usize,
+}
+
+#[derive(Default)]
+pub struct RenderQueue<'a> {
+    pub batches: Vec<Vec<DrawCall<'a>>>,
+    pub items: Vec<DrawItem>,
+}
+impl<'a> RenderQueue<'a> {
+    pub fn new() -> Self {
+        Self { batches: vec![], items: vec![] }
+    }
+
+    /* queues every mesh of the batch's model, `camera` is the position and look direction depth is measured along */
+    pub fn push_batch(
+        &mut self,
+        batch: Vec<DrawCall<'a>>,
+        meshes: &[MeshDataGroup],
+        materials: &[Material],
+        renderer: &dyn RenderBackend,
+        camera: (vec::Vec3, vec::Vec3),
+    ) {
+        let first = match batch.first() {
+            Some(first) => first.clone(),
+            None => return,
+        };
+        let parts = match meshes.get(first.mesh) {
+            Some(parts) => &parts.0,
+            None => return,
+        };
+        let material_override = first.object.material_override;
+        let depth_of = |call: &DrawCall| view_depth(call, camera);
+
+        let batch_idx = self.batches.len();
+        self.batches.push(batch);
+        for (part, mesh) in parts.iter().enumerate() {
+            let material = material_override.material_for(mesh.material_idx, materials.len());
+            let pass = match materials.get(material) {
+                Some(material) => material.pass(&material_override.apply(&material.params)),
+                None => RenderPass::Opaque,
+            };
+            let program = renderer.program_of(material);
+            if pass == RenderPass::Transparent {
+                for call in self.batches[batch_idx].clone() {
+                    let depth = depth_of(&call);
+                    self.items.push(DrawItem { pass, program, material, depth, batch: self.batches.len(), part });
+                    self.batches.push(vec![call]);
+                }
+            } else {
+                let depth = self.batches[batch_idx].iter().map(depth_of).fold(f32::INFINITY, f32::min);
+                self.items.push(DrawItem { pass, program, material, depth, batch: batch_idx, part });
+            }
+        }
+    }
+
+    pub fn sort(&mut self) {
+        self.items.sort_by(compare_items);
+    }
+}
+
+/* distance along the camera's look direction to the center of the call's bounds */
+fn view_depth(call: &DrawCall, (view_pos, look_dir): (vec::Vec3, vec::Vec3)) -> f32 {
+    let center = match &call.bounds {
+        Some(bounds) => bounds.sphere.center,
+        None => call.model.cols[3].xyz(),
+    };
+    (center - view_pos).dot(look_dir)
+}
+
+fn compare_items(a: &DrawItem, b: &DrawItem) -> Ordering {
+    a.pass.cmp(&b.pass).then_with(|| match a.pass {
+        RenderPass::Transparent => b.depth.total_cmp(&a.depth),
+        RenderPass::Opaque | RenderPass::AlphaTested => a.program.cmp(&b.program)
+            .then(a.material.cmp(&b.material))
+            .then(a.depth.total_cmp(&b.depth)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ultraviolet::mat;
+    use crate::bounds::Bounds;
+    use crate::software_render::SoftwareBackend;
+
+    fn mesh(material_idx: usize) -> MeshData {
+        MeshData {
+            point_data: vec![],
+            point_indices: vec![],
+            tri_count: 0,
+            material_idx,
+            bounds: Bounds::from_points([vec::Vec3::zero()]),
+        }
+    }
+
+    fn materials() -> Vec<Material> {
+        vec![
+            Material::new("opaque", MaterialParams::default()),
+            Material::new("leaves", MaterialParams::default()).with_alpha_cutoff(0.5).with_map(MapSlot::Diffuse, 0),
+            Material::new("glass", MaterialParams { dissolve: 0.5, ..MaterialParams::default() }),
+        ]
+    }
+
+    /* a call `depth` in front of a camera at the origin looking down -z */
+    fn call(object: &DrawableObject, depth: f32) -> DrawCall<'_> {
+        DrawCall {
+            mesh: object.drawable_group_idx,
+            model: mat::Mat4::from_translation(vec::Vec3::new(0.0, 0.0, -depth)),
+            rotation: mat::Mat4::identity(),
+            object,
+            bounds: None,
+        }
+    }
+
+    const CAMERA: (vec::Vec3, vec::Vec3) = (vec::Vec3::new(0.0, 0.0, 0.0), vec::Vec3::new(0.0, 0.0, -1.0));
+
+    fn item(pass: RenderPass, program: usize, material: usize, depth: f32) -> DrawItem {
+        DrawItem { pass, program, material, depth, batch: 0, part: 0 }
+    }
+
+    #[test]
+    fn opaque_items_sort_by_program_then_material_then_depth() {
+        let mut queue = RenderQueue::new();
+        queue.items = vec![
+            item(RenderPass::Opaque, 1, 0, 1.0),
+            item(RenderPass::Opaque, 0, 1, 1.0),
+            item(RenderPass::Opaque, 0, 0, 5.0),
+            item(RenderPass::Transparent, 0, 0, 1.0),
+            item(RenderPass::Opaque, 0, 0, 2.0),
+            item(RenderPass::AlphaTested, 0, 0, 1.0),
+        ];
+        queue.sort();
+        let order: Vec<_> = queue.items.iter().map(|item| (item.pass, item.program, item.material, item.depth)).collect();
+        assert_eq!(order, vec![
+            (RenderPass::Opaque, 0, 0, 2.0),
+            (RenderPass::Opaque, 0, 0, 5.0),
+            (RenderPass::Opaque, 0, 1, 1.0),
+            (RenderPass::Opaque, 1, 0, 1.0),
+            (RenderPass::AlphaTested, 0, 0, 1.0),
+            (RenderPass::Transparent, 0, 0, 1.0),
+        ]);
+    }
+
+    #[test]
+    fn opaque_batch_is_one_item_at_its_nearest_object() {
+        let meshes = vec![MeshDataGroup(vec![mesh(0)])];
+        let renderer = SoftwareBackend::new(4, 4);
+        let object = DrawableObject::new(vec::Vec3::zero(), vec::Vec3::zero(), vec::Vec3::one(), 0);
+        let mut queue = RenderQueue::new();
+        queue.push_batch(vec![call(&object, 6.0), call(&object, 2.0)], &meshes, &materials(), &renderer, CAMERA);
+        assert_eq!(queue.items.len(), 1);
+        assert_eq!(queue.items[0].pass, RenderPass::Opaque);
+        assert_eq!(queue.items[0].depth, 2.0);
+        assert_eq!(queue.batches[queue.items[0].batch].len(), 2);
+    }
+
+    #[test]
+    fn transparent_items_get_one_call_each_and_sort_back_to_front() {
+        let meshes = vec![MeshDataGroup(vec![mesh(2)])];
+        let renderer = SoftwareBackend::new(4, 4);
+        let object = DrawableObject::new(vec::Vec3::zero(), vec::Vec3::zero(), vec::Vec3::one(), 0);
+        let mut queue = RenderQueue::new();
+        queue.push_batch(
+            vec![call(&object, 2.0), call(&object, 8.0), call(&object, 5.0)],
+            &meshes, &materials(), &renderer, CAMERA,
+        );
+        queue.sort();
+        assert_eq!(queue.items.len(), 3);
+        assert!(queue.items.iter().all(|item| item.pass == RenderPass::Transparent));
+        assert!(queue.items.iter().all(|item| queue.batches[item.batch].len() == 1));
+        let depths: Vec<_> = queue.items.iter().map(|item| item.depth).collect();
+        assert_eq!(depths, vec![8.0, 5.0, 2.0]);
+    }
+
+    #[test]
+    fn alpha_tested_meshes_get_their_own_pass() {
+        let meshes = vec![MeshDataGroup(vec![mesh(1), mesh(0)])];
+        let renderer = SoftwareBackend::new(4, 4);
+        let object = DrawableObject::new(vec::Vec3::zero(), vec::Vec3::zero(), vec::Vec3::one(), 0);
+        let mut queue = RenderQueue::new();
+        queue.push_batch(vec![call(&object, 3.0)], &meshes, &materials(), &renderer, CAMERA);
+        queue.sort();
+        let passes: Vec<_> = queue.items.iter().map(|item| (item.pass, item.part)).collect();
+        assert_eq!(passes, vec![(RenderPass::Opaque, 1), (RenderPass::AlphaTested, 0)]);
+    }
+}
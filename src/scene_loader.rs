@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fs;
+use rapier2d::prelude::*;
+use serde::Deserialize;
+use ultraviolet::vec;
+use crate::gllib::*;
+use crate::ecs::*;
+use crate::behaviors::*;
+use crate::lights::*;
+use crate::materials::*;
+use crate::render::*;
+use crate::environment::*;
+use crate::post_process::*;
+
+/* Scene files are RON documents describing everything make_scene_* functions used to set up by hand:
+camera and light start positions, clear color, the scene's materials and a list of game objects
+with their model, transforms, rigid body + collider, light, behaviors and behavior data.
+Behaviors are written as ("RegisteredName", (field: value, ...)) and created through the
+Context's BehaviorRegistry. Inside behavior data a string "@name" is replaced with the id
+of the object called `name` in the same file.
+See src/levels/physics.ron for an example. */
+
+fn zero3() -> [f32; 3] { [0.0, 0.0, 0.0] }
+fn one3() -> [f32; 3] { [1.0, 1.0, 1.0] }
+fn yes() -> bool { true }
+fn default_clear_color() -> [f32; 4] { [0.0, 0.0, 0.0, 1.0] }
+fn default_shader() -> String { DEFAULT_SHADER.to_string() }
+fn default_specular() -> [f32; 3] { MaterialParams::default().specular.into() }
+fn default_shininess() -> f32 { MaterialParams::default().shininess }
+fn one() -> f32 { 1.0 }
+
+#[derive(Deserialize, Debug)]
+pub struct SceneDesc {
+    #[serde(default)]
+    pub camera: CameraDesc,
+    #[serde(default = "default_clear_color")]
+    pub clear_color: [f32; 4],
+    /* drawn instead of the clear color, see environment.rs */
+    #[serde(default)]
+    pub skybox: Option<SkyboxDesc>,
+    /* see post_process.rs */
+    #[serde(default)]
+    pub post_process: Vec<PostPass>,
+    /* added before the objects are spawned, see Context::add_material */
+    #[serde(default)]
+    pub materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    pub objects: Vec<GameObjectDesc>,
+}
+
+/* a material in a scene file, the fields are the MaterialParams. One named like a material
+of a model's .mtl replaces it for every object with that model */
+#[derive(Deserialize, Debug)]
+pub struct MaterialDesc {
+    pub name: String,
+    /* folder under src/shaders */
+    #[serde(default = "default_shader")]
+    pub shader: String,
+    #[serde(default = "one3")]
+    pub ambient: [f32; 3],
+    #[serde(default = "one3")]
+    pub diffuse: [f32; 3],
+    #[serde(default = "default_specular")]
+    pub specular: [f32; 3],
+    #[serde(default = "default_shininess")]
+    pub shininess: f32,
+    #[serde(default = "one")]
+    pub optical_density: f32,
+    #[serde(default = "one")]
+    pub dissolve: f32,
+    /* mirrors the scene's skybox, 0 to 1 */
+    #[serde(default)]
+    pub reflectivity: f32,
+    /* image files, relative to the working directory like model paths */
+    #[serde(default)]
+    pub diffuse_map: Option<String>,
+    #[serde(default)]
+    pub specular_map: Option<String>,
+    #[serde(default)]
+    pub bump_map: Option<String>,
+    /* cuts out where the diffuse map's alpha is below it */
+    #[serde(default)]
+    pub alpha_cutoff: Option<f32>,
+    /* for materials drawn in the Transparent pass, see Material */
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub depth_write: bool,
+}
+
+/* fields left out keep the values of CameraDesc::default, the same as a new Context's camera */
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct CameraDesc {
+    pub view_pos: [f32; 3],
+    pub view_rot: [f32; 3],
+    pub light_position: [f32; 3],
+}
+impl Default for CameraDesc {
+    fn default() -> Self {
+        Self { view_pos: zero3(), view_rot: [0.0, 0.0, 90.0], light_position: [0.0, 10.0, -10.0] }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GameObjectDesc {
+    /* optional name other objects can refer to, e.g. as an AttractionTo target */
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "zero3")]
+    pub position: [f32; 3],
+    #[serde(default = "zero3")]
+    pub rotation: [f32; 3],
+    #[serde(default = "one3")]
+    pub scale: [f32; 3],
+    #[serde(default)]
+    pub model: Option<ModelDesc>,
+    #[serde(default)]
+    pub rigid_body: Option<RigidBodyDesc>,
+    /* adds the rigid body to the floor set so floor collision behaviors treat it as ground */
+    #[serde(default)]
+    pub floor: bool,
+    /* e.g. Some((kind: Point, color: (1.0, 0.8, 0.5), attenuation: (constant: 1.0, linear: 0.2, quadratic: 0.05))) */
+    #[serde(default)]
+    pub light: Option<Light>,
+    #[serde(default)]
+    pub behaviors: Vec<(String, ron::Value)>,
+    #[serde(default)]
+    pub repeat: Option<RepeatDesc>,
+    /* objects attached to this one, their transforms are relative to it */
+    #[serde(default)]
+    pub children: Vec<GameObjectDesc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModelDesc {
+    /* key into the model_map passed to the loader */
+    pub name: String,
+    #[serde(default = "zero3")]
+    pub position: [f32; 3],
+    #[serde(default = "zero3")]
+    pub rotation: [f32; 3],
+    #[serde(default = "one3")]
+    pub scale: [f32; 3],
+    #[serde(default = "yes")]
+    pub cast_shadows: bool,
+    #[serde(default = "yes")]
+    pub receive_shadows: bool,
+    /* draws the whole model with this material instead of its own */
+    #[serde(default)]
+    pub material: Option<String>,
+    /* replace single parameters of the materials for this object only, color sets Ka and Kd */
+    #[serde(default)]
+    pub color: Option<[f32; 3]>,
+    #[serde(default)]
+    pub specular: Option<[f32; 3]>,
+    #[serde(default)]
+    pub shininess: Option<f32>,
+    #[serde(default)]
+    pub dissolve: Option<f32>,
+    /* scales dissolve, 0 invisible to 1 as the material is */
+    #[serde(default)]
+    pub opacity: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum RigidBodyKind {
+    Dynamic,
+    Fixed,
+    KinematicPositionBased,
+    KinematicVelocityBased,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RigidBodyDesc {
+    pub kind: RigidBodyKind,
+    #[serde(default)]
+    pub lock_rotations: bool,
+    #[serde(default)]
+    pub collider: Option<ColliderDesc>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum ShapeDesc {
+    Ball(f32),
+    Cuboid(f32, f32),
+    Capsule(f32, f32),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ColliderDesc {
+    pub shape: ShapeDesc,
+    #[serde(default)]
+    pub friction: Option<f32>,
+    #[serde(default)]
+    pub restitution: Option<f32>,
+    #[serde(default)]
+    pub collision_events: bool,
+    /* a non-solid trigger volume, reports enter/exit to trigger behaviors instead of colliding */
+    #[serde(default)]
+    pub sensor: bool,
+}
+
+/* spawns `count` copies of the object, each offset by `step` from the previous one */
+#[derive(Deserialize, Debug)]
+pub struct RepeatDesc {
+    pub count: u32,
+    pub step: [f32; 3],
+}
+
+pub fn read_scene_file(path: &str) -> Result<SceneDesc, String> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read scene file {}: {}", path, e))?;
+    ron::from_str(&source)
+        .map_err(|e| format!("Could not parse scene file {}: {}", path, e))
+}
+
+pub fn load_scene_file(
+    ctx: &mut Context,
+    model_map: &HashMap<&str, usize>,
+    path: &str,
+) -> Result<Option<PreDraw>, String> {
+    let scene = read_scene_file(path)?;
+    load_scene(ctx, model_map, &scene)
+}
+
+pub fn load_scene(
+    ctx: &mut Context,
+    model_map: &HashMap<&str, usize>,
+    scene: &SceneDesc,
+) -> Result<Option<PreDraw>, String> {
+    ctx.camera.view_pos = vec::Vec3::from(scene.camera.view_pos);
+    ctx.camera.view_rot = vec::Vec3::from(scene.camera.view_rot);
+    ctx.camera.light_position = vec::Vec3::from(scene.camera.light_position);
+
+    ctx.clear_color = scene.clear_color;
+    ctx.environment = match &scene.skybox {
+        Some(skybox) => Some(Environment {
+            cubemap: ctx.load_cubemap(&skybox.source, skybox.face_size)?,
+            intensity: skybox.intensity,
+        }),
+        None => None,
+    };
+    ctx.post_process = scene.post_process.clone();
+
+    for desc in &scene.materials {
+        let material = build_material(ctx, desc)?;
+        ctx.add_material(material)?;
+    }
+
+    /* first pass spawns every object so names can be resolved,
+    second pass attaches behaviors since their data may reference other objects */
+    let mut names: HashMap<&str, GameObjectID> = HashMap::new();
+    let mut spawned: Vec<(GameObjectID, &GameObjectDesc)> = vec![];
+    for desc in &scene.objects {
+        spawn_desc(ctx, model_map, desc, None, &mut names, &mut spawned)?;
+    }
+
+    for (id, desc) in spawned {
+        let behaviors = ctx.game_obj_store.behaviors.get_mut(&id)
+            .ok_or_else(|| format!("Object {} vanished while loading the scene", id))?;
+        for (name, data) in &desc.behaviors {
+            let data = resolve_object_refs(data.clone(), &names)?;
+            behaviors.push(ctx.behavior_registry.create(name, data)?);
+        }
+    }
+
+    Ok(None)
+}
+
+/* spawns the object (every copy of it with repeat) and its children under `parent`,
+given as the parent's id and world position */
+fn spawn_desc<'d>(
+    ctx: &mut Context,
+    model_map: &HashMap<&str, usize>,
+    desc: &'d GameObjectDesc,
+    parent: Option<(GameObjectID, vec::Vec3)>,
+    names: &mut HashMap<&'d str, GameObjectID>,
+    spawned: &mut Vec<(GameObjectID, &'d GameObjectDesc)>,
+) -> Result<(), String> {
+    let (count, step) = match &desc.repeat {
+        Some(repeat) => (repeat.count, vec::Vec3::from(repeat.step)),
+        None => (1, vec::Vec3::zero()),
+    };
+    for i in 0..count {
+        let position = vec::Vec3::from(desc.position) + step * i as f32;
+        let origin = parent.map_or(vec::Vec3::zero(), |(_, origin)| origin);
+        let id = spawn_object(ctx, model_map, desc, position, origin)?;
+        if let Some((parent_id, _)) = parent {
+            ctx.game_obj_store.set_parent(&id, Some(parent_id))?;
+        }
+        if let Some(name) = &desc.name {
+            names.entry(name.as_str()).or_insert(id);
+        }
+        spawned.push((id, desc));
+        for child in &desc.children {
+            spawn_desc(ctx, model_map, child, Some((id, origin + position)), names, spawned)?;
+        }
+    }
+    Ok(())
+}
+
+/* position is local, origin is where the parent is in the world, used to place rigid bodies */
+fn spawn_object(
+    ctx: &mut Context,
+    model_map: &HashMap<&str, usize>,
+    desc: &GameObjectDesc,
+    position: vec::Vec3,
+    origin: vec::Vec3,
+) -> Result<GameObjectID, String> {
+    if let Some(Light { kind: LightKind::Point, shadow: Some(_), .. }) = &desc.light {
+        return Err(format!("Point light '{}' in scene file has shadow settings, only directional and spot lights cast shadows",
+            desc.name.as_deref().unwrap_or("unnamed")));
+    }
+    let mut go = GameObject::empty();
+    go.position = position;
+    go.rotation = vec::Vec3::from(desc.rotation);
+    go.scale = vec::Vec3::from(desc.scale);
+
+    if let Some(model) = &desc.model {
+        let drawable_group_idx = *model_map.get(model.name.as_str())
+            .ok_or_else(|| format!("Unknown model '{}' in scene file", model.name))?;
+        let material_override = MaterialOverride {
+            material: model.material.as_deref().map(|name| ctx.material_idx(name)).transpose()?,
+            ambient: model.color.map(vec::Vec3::from),
+            diffuse: model.color.map(vec::Vec3::from),
+            specular: model.specular.map(vec::Vec3::from),
+            shininess: model.shininess,
+            dissolve: model.dissolve,
+            opacity: model.opacity,
+        };
+        go.drawable_object = Some(DrawableObject::new(
+            vec::Vec3::from(model.position),
+            vec::Vec3::from(model.rotation),
+            vec::Vec3::from(model.scale),
+            drawable_group_idx
+        ).with_shadows(model.cast_shadows, model.receive_shadows).with_material_override(material_override));
+    }
+
+    if let Some(rb_desc) = &desc.rigid_body {
+        let builder = match rb_desc.kind {
+            RigidBodyKind::Dynamic => RigidBodyBuilder::dynamic(),
+            RigidBodyKind::Fixed => RigidBodyBuilder::fixed(),
+            RigidBodyKind::KinematicPositionBased => RigidBodyBuilder::kinematic_position_based(),
+            RigidBodyKind::KinematicVelocityBased => RigidBodyBuilder::kinematic_velocity_based(),
+        };
+        /* physics owns the transforms of rigid body objects, so they start out in world space */
+        let position = origin + position;
+        go.position = position;
+        let mut builder = builder.translation(vector![position.x, position.y]).rotation(desc.rotation[2]);
+        if rb_desc.lock_rotations {
+            builder = builder.lock_rotations();
+        }
+        let rb_handle = ctx.rigid_body_set.insert(builder.build());
+        if let Some(collider_desc) = &rb_desc.collider {
+            ctx.collider_set.insert_with_parent(build_collider(collider_desc), rb_handle, &mut ctx.rigid_body_set);
+        }
+        if desc.floor {
+            ctx.floor_set.insert(rb_handle);
+        }
+        go.rigid_body_handle = Some(rb_handle);
+    }
+    go.light = desc.light.clone();
+
+    Ok(ctx.game_obj_store.add(go))
+}
+
+/* loads the material's textures */
+pub fn build_material(ctx: &mut Context, desc: &MaterialDesc) -> Result<Material, String> {
+    let mut material = Material::new(&desc.name, MaterialParams {
+        ambient: vec::Vec3::from(desc.ambient),
+        diffuse: vec::Vec3::from(desc.diffuse),
+        specular: vec::Vec3::from(desc.specular),
+        shininess: desc.shininess,
+        optical_density: desc.optical_density,
+        dissolve: desc.dissolve,
+        reflectivity: desc.reflectivity,
+    }).with_shader(&desc.shader);
+    material.alpha_cutoff = desc.alpha_cutoff;
+    material = material.with_blend(desc.blend).with_depth_write(desc.depth_write);
+    for (path, slot) in [(&desc.diffuse_map, MapSlot::Diffuse), (&desc.specular_map, MapSlot::Specular), (&desc.bump_map, MapSlot::Bump)] {
+        if let Some(path) = path {
+            material = material.with_map(slot, ctx.load_texture(path)?);
+        }
+    }
+    Ok(material)
+}
+
+pub fn build_collider(desc: &ColliderDesc) -> Collider {
+    let mut builder = match desc.shape {
+        ShapeDesc::Ball(radius) => ColliderBuilder::ball(radius),
+        ShapeDesc::Cuboid(hx, hy) => ColliderBuilder::cuboid(hx, hy),
+        ShapeDesc::Capsule(half_height, radius) => ColliderBuilder::capsule_y(half_height, radius),
+    };
+    if let Some(friction) = desc.friction {
+        builder = builder.friction(friction);
+    }
+    if let Some(restitution) = desc.restitution {
+        builder = builder.restitution(restitution);
+    }
+    if desc.collision_events || desc.sensor {
+        builder = builder.active_events(ActiveEvents::COLLISION_EVENTS);
+    }
+    if desc.sensor {
+        builder = builder.sensor(true);
+    }
+    builder.build()
+}
+
+fn resolve_object_refs(value: ron::Value, names: &HashMap<&str, GameObjectID>) -> Result<ron::Value, String> {
+    Ok(match value {
+        ron::Value::String(text) if text.starts_with('@') => {
+            let id = names.get(&text[1..])
+                .ok_or_else(|| format!("'{}' does not name an object in the scene", text))?;
+            to_ron_value(id)?
+        },
+        ron::Value::Map(map) => {
+            let mut resolved = ron::Map::new();
+            for (key, value) in map.into_iter() {
+                resolved.insert(key, resolve_object_refs(value, names)?);
+            }
+            ron::Value::Map(resolved)
+        },
+        ron::Value::Seq(values) => ron::Value::Seq(
+            values.into_iter().map(|value| resolve_object_refs(value, names)).collect::<Result<_, _>>()?
+        ),
+        ron::Value::Option(Some(value)) => ron::Value::Option(Some(Box::new(resolve_object_refs(*value, names)?))),
+        value => value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_fields_left_out_keep_their_defaults() {
+        let scene: SceneDesc = ron::from_str("(camera: (view_pos: (1.0, 2.0, 3.0)))").unwrap();
+        assert_eq!(scene.camera.view_pos, [1.0, 2.0, 3.0]);
+        assert_eq!(scene.camera.view_rot, CameraDesc::default().view_rot);
+        assert_eq!(scene.camera.light_position, CameraDesc::default().light_position);
+
+        let scene: SceneDesc = ron::from_str("()").unwrap();
+        assert_eq!(scene.camera.view_rot, [0.0, 0.0, 90.0]);
+    }
+
+    /* the error loading the scene gives, with the context it was loaded into */
+    fn load_error(source: &str) -> (String, Context) {
+        let mut ctx = Context::headless(64, 64);
+        let scene: SceneDesc = ron::from_str(source).unwrap();
+        match load_scene(&mut ctx, &HashMap::new(), &scene) {
+            Ok(_) => panic!("{} loaded", source),
+            Err(error) => (error, ctx),
+        }
+    }
+
+    #[test]
+    fn unknown_behaviors_are_errors() {
+        let (error, _) = load_error(r#"(objects: [(behaviors: [("Teleport", (x: 1.0))])])"#);
+        assert!(error.contains("Teleport"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_behavior_data_is_an_error() {
+        let (error, _) = load_error(r#"(objects: [(behaviors: [("FadeOut", (seconds: "soon"))])])"#);
+        assert!(error.contains("FadeOut"), "{}", error);
+        let (error, _) = load_error(r#"(objects: [(behaviors: [("AttractionTo", ())])])"#);
+        assert!(error.contains("AttractionTo"), "{}", error);
+    }
+
+    #[test]
+    fn references_to_missing_objects_are_errors() {
+        let (error, _) = load_error(r#"(objects: [
+            (name: Some("player")),
+            (behaviors: [("TriggerDespawnOther", (only: Some("@plaeyr")))]),
+        ])"#);
+        assert!(error.contains("@plaeyr"), "{}", error);
+
+        /* and resolve when the object is there, wherever it is in the file */
+        let mut ctx = Context::headless(64, 64);
+        let scene: SceneDesc = ron::from_str(r#"(objects: [
+            (behaviors: [("TriggerDespawnOther", (only: Some("@player")))]),
+            (name: Some("player")),
+        ])"#).unwrap();
+        assert!(load_scene(&mut ctx, &HashMap::new(), &scene).is_ok());
+    }
+
+    #[test]
+    fn shadows_on_point_lights_are_rejected() {
+        let (error, mut ctx) = load_error(
+            "(objects: [(name: Some(\"bulb\"), light: Some((kind: Point, shadow: Some(()))))])"
+        );
+        assert!(error.contains("bulb"), "{}", error);
+        assert!(ctx.game_obj_store.is_empty());
+
+        let scene: SceneDesc = ron::from_str(
+            "(objects: [(light: Some((kind: Directional, shadow: Some(()))))])"
+        ).unwrap();
+        assert!(load_scene(&mut ctx, &HashMap::new(), &scene).is_ok());
+    }
+}
@@ -2,28 +2,216 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
 use std::time::Duration;
 use std::time::Instant;
+use log::warn;
 use rapier2d::prelude::*;
 use ultraviolet::vec;
 use crate::gllib::*;
+use crate::ecs::*;
 use crate::behaviors::*;
+use crate::lights::*;
+use crate::shadows::*;
+use crate::materials::*;
+use crate::environment::Environment;
+use crate::post_process::PostPass;
+use crate::scene_loader::load_scene_file;
 
-pub fn make_scene_empty<T>(
-    ctx: &mut Context<T>,
+pub type SceneBuilder = Box<dyn Fn(&mut Context, &HashMap<&str, usize>) -> Result<Option<PreDraw>, String>>;
+pub type SceneFileLoader = fn(&mut Context, &HashMap<&str, usize>, &str) -> Result<Option<PreDraw>, String>;
+/* called with the context, the scene being left (if any) and the scene being entered */
+pub type SceneTransitionHook = Box<dyn Fn(&mut Context, Option<&str>, &str)>;
+
+/* scenes the game can switch between at runtime, keyed by name */
+pub struct SceneRegistry {
+    scenes: HashMap<String, SceneBuilder>,
+    file_loader: Option<SceneFileLoader>,
+    before_unload: Vec<SceneTransitionHook>,
+    after_load: Vec<SceneTransitionHook>,
+}
+impl SceneRegistry {
+    pub fn new() -> Self {
+        Self { scenes: HashMap::new(), file_loader: None, before_unload: vec![], after_load: vec![] }
+    }
+    pub fn register(&mut self, name: &str, builder: impl Fn(&mut Context, &HashMap<&str, usize>) -> Result<Option<PreDraw>, String> + 'static) {
+        self.scenes.insert(name.to_string(), Box::new(builder));
+    }
+    /* used for register_scene_files and for switching to a .ron path that isn't registered */
+    pub fn set_file_loader(&mut self, file_loader: SceneFileLoader) {
+        self.file_loader = Some(file_loader);
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.scenes.contains_key(name)
+    }
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.scenes.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+    /* runs before the current scene is cleared, e.g. to carry state over or fade out */
+    pub fn on_before_unload(&mut self, hook: impl Fn(&mut Context, Option<&str>, &str) + 'static) {
+        self.before_unload.push(Box::new(hook));
+    }
+    /* runs after the new scene has been built */
+    pub fn on_after_load(&mut self, hook: impl Fn(&mut Context, Option<&str>, &str) + 'static) {
+        self.after_load.push(Box::new(hook));
+    }
+}
+impl SceneRegistry {
+    /* registers every .ron file in the folder as "levels/<file stem>" */
+    pub fn register_scene_files(&mut self, folder: &str) -> Result<(), String> {
+        let file_loader = self.file_loader.ok_or_else(|| "No scene file loader set on the registry".to_string())?;
+        let entries = fs::read_dir(folder).map_err(|e| format!("Could not read scene folder {}: {}", folder, e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "ron") {
+                if let (Some(stem), Some(path)) = (path.file_stem().and_then(|s| s.to_str()), path.to_str()) {
+                    let path = path.to_string();
+                    self.register(&format!("levels/{}", stem), move |ctx, model_map| file_loader(ctx, model_map, &path));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+impl Default for SceneRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn default_scene_registry() -> SceneRegistry {
+    let mut registry = SceneRegistry::new();
+    registry.set_file_loader(load_scene_file);
+    registry.register("empty", |ctx, model_map| Ok(make_scene_empty(ctx, model_map)));
+    registry.register("waves", |ctx, model_map| Ok(make_scene_waves(ctx, model_map)));
+    registry.register("physics", |ctx, model_map| Ok(make_scene_physics(ctx, model_map)));
+    registry.register("crowd", |ctx, model_map| Ok(make_scene_crowd(ctx, model_map)));
+    if let Err(e) = registry.register_scene_files("src/levels") {
+        warn!(target: LT_MAIN_LOOP, "{}", e);
+    }
+    registry
+}
+
+/* everything a scene puts into the world, see take_scene */
+struct SceneState {
+    game_obj_store: GameObjectStore,
+    rigid_body_set: RigidBodySet,
+    collider_set: ColliderSet,
+    floor_set: HashSet<RigidBodyHandle>,
+    physics: PhysicsState,
+    pre_draw: Option<PreDraw>,
+    current_scene: Option<String>,
+    reparents: Vec<(GameObjectID, Option<GameObjectID>)>,
+    clear_color: [f32; 4],
+    environment: Option<Environment>,
+    post_process: Vec<PostPass>,
+    camera: (vec::Vec3, vec::Vec3, vec::Vec3),
+}
+
+/* moves the current scene out of the context, leaving it empty */
+fn take_scene(ctx: &mut Context) -> SceneState {
+    SceneState {
+        game_obj_store: std::mem::take(&mut ctx.game_obj_store),
+        rigid_body_set: std::mem::replace(&mut ctx.rigid_body_set, RigidBodySet::new()),
+        collider_set: std::mem::replace(&mut ctx.collider_set, ColliderSet::new()),
+        floor_set: std::mem::take(&mut ctx.floor_set),
+        physics: std::mem::take(&mut ctx.physics),
+        pre_draw: ctx.pre_draw.take(),
+        current_scene: ctx.current_scene.take(),
+        reparents: std::mem::take(&mut ctx.reparents),
+        clear_color: std::mem::replace(&mut ctx.clear_color, [0.0, 0.0, 0.0, 1.0]),
+        environment: ctx.environment.take(),
+        post_process: std::mem::take(&mut ctx.post_process),
+        camera: (ctx.camera.view_pos, ctx.camera.view_rot, ctx.camera.light_position),
+    }
+}
+
+/* puts a scene taken with take_scene back, dropping whatever is in the context now */
+fn restore_scene(ctx: &mut Context, scene: SceneState) {
+    ctx.game_obj_store = scene.game_obj_store;
+    ctx.rigid_body_set = scene.rigid_body_set;
+    ctx.collider_set = scene.collider_set;
+    ctx.floor_set = scene.floor_set;
+    ctx.physics = scene.physics;
+    ctx.pre_draw = scene.pre_draw;
+    ctx.current_scene = scene.current_scene;
+    ctx.reparents = scene.reparents;
+    ctx.clear_color = scene.clear_color;
+    ctx.environment = scene.environment;
+    ctx.post_process = scene.post_process;
+    (ctx.camera.view_pos, ctx.camera.view_rot, ctx.camera.light_position) = scene.camera;
+}
+
+/* clears everything a scene put into the world */
+pub fn unload_scene(ctx: &mut Context) {
+    take_scene(ctx);
+}
+
+/* unloads the current scene and builds the named one, running the registry's transition hooks.
+Names that aren't registered but end in .ron are loaded as scene files directly.
+If building the new scene fails the old one is put back as it was and the error returned. */
+pub fn switch_scene(
+    ctx: &mut Context,
     model_map: &HashMap<&str, usize>,
-) -> Box<dyn Fn(&ShaderProgram, &DrawableObject)> {
+    name: &str,
+) -> Result<(), String> {
+    let is_file = !ctx.scenes.contains(name) && name.ends_with(".ron") && ctx.scenes.file_loader.is_some();
+    if !ctx.scenes.contains(name) && !is_file {
+        return Err(format!("No scene named '{}', available scenes: {:?}", name, ctx.scenes.names()));
+    }
+    if is_file && !Path::new(name).exists() {
+        return Err(format!("Scene file {} does not exist", name));
+    }
+
+    /* the registry is taken out of the context while it runs so builders and hooks can borrow ctx mutably */
+    let registry = std::mem::take(&mut ctx.scenes);
+    let previous = ctx.current_scene.clone();
+    for hook in &registry.before_unload {
+        hook(ctx, previous.as_deref(), name);
+    }
+    let old_scene = take_scene(ctx);
+    let built = match (registry.scenes.get(name), registry.file_loader) {
+        (Some(builder), _) => builder(ctx, model_map),
+        (None, Some(file_loader)) => file_loader(ctx, model_map, name),
+        (None, None) => unreachable!(),
+    };
+    let result = match built {
+        Ok(pre_draw) => {
+            ctx.pre_draw = pre_draw;
+            ctx.current_scene = Some(name.to_string());
+            for hook in &registry.after_load {
+                hook(ctx, previous.as_deref(), name);
+            }
+            Ok(())
+        },
+        Err(e) => {
+            restore_scene(ctx, old_scene);
+            Err(e)
+        },
+    };
+    ctx.scenes = registry;
+    result
+}
+
+pub fn make_scene_empty(
+    ctx: &mut Context,
+    model_map: &HashMap<&str, usize>,
+) -> Option<PreDraw> {
     ctx.camera.view_pos = vec::Vec3::new(-20.0, 20.0, 20.0);
     ctx.camera.view_rot = vec::Vec3::new(0.0, -20.0, -45.0);
     ctx.camera.light_position = vec::Vec3::new(0.0, 50.0, 0.0);
 
-    Box::new(move |shader: &ShaderProgram, draw: &DrawableObject| {})
+    None
 }
 
-pub fn make_scene_waves<T>(
-    ctx: &mut Context<T>,
+pub fn make_scene_waves(
+    ctx: &mut Context,
     model_map: &HashMap<&str, usize>,
-) -> Box<dyn Fn(&ShaderProgram, &DrawableObject)> {
+) -> Option<PreDraw> {
     ctx.camera.view_pos = vec::Vec3::new(-20.0, 10.0, 20.0);
     ctx.camera.view_rot = vec::Vec3::new(0.0, -20.0, -45.0);
     ctx.camera.light_position = vec::Vec3::new(0.0, 50.0, 0.0);
@@ -42,35 +230,82 @@ pub fn make_scene_waves<T>(
     for x in -10..10 {
         for y in -10..10 {
             let x_off = if ((x % 2) == 0) != ((y % 2) == 0) {1} else {0};
-            ctx.game_obj_store.add(make_go(
-                vec::Vec3::new( 
+            let position = vec::Vec3::new( 
                 ((2*x)) as f32, 
                 -2.0 - x_off as f32, 
-                (2*y) as f32), 
+                (2*y) as f32);
+            let mut tile = make_go(
+                position, 
                 vec::Vec3::zero(),
                 vec::Vec3::one(),
                 vec::Vec3::zero(),
                 vec::Vec3::zero(),
                 vec::Vec3::one(),
                 1
-            ));
+            );
+            /* colored by where the tile is */
+            let color = MaterialOverride {
+                diffuse: Some(vec::Vec3::new(position.x / 10.0 + 1.0, position.y + 3.0, position.z / 10.0 + 1.0)),
+                ..MaterialOverride::default()
+            };
+            tile.drawable_object = tile.drawable_object.map(|draw| draw.with_material_override(color));
+            ctx.game_obj_store.add(tile);
+        }
+    }
+
+    None
+}
+
+/* 10000 cubes of one model and material, drawn as a single instanced batch */
+pub fn make_scene_crowd(
+    ctx: &mut Context,
+    model_map: &HashMap<&str, usize>,
+) -> Option<PreDraw> {
+    ctx.camera.view_pos = vec::Vec3::new(-45.0, 30.0, 45.0);
+    ctx.camera.view_rot = vec::Vec3::new(0.0, -30.0, -45.0);
+    ctx.camera.light_position = vec::Vec3::new(0.0, 50.0, 0.0);
+
+    ctx.game_obj_store.add(GameObject::empty().add_light(
+        Light::directional([-0.5, -1.0, -0.3], [1.0, 1.0, 1.0], 1.0)
+    ));
+
+    let side = 100;
+    for x in 0..side {
+        for z in 0..side {
+            let (u, v) = (x as f32 / side as f32, z as f32 / side as f32);
+            let position = vec::Vec3::new((u - 0.5) * 70.0, (u * 12.0).sin() + (v * 9.0).cos(), (v - 0.5) * 70.0);
+            let mut go = make_go(
+                position,
+                vec::Vec3::zero(),
+                vec::Vec3::one(),
+                vec::Vec3::zero(),
+                vec::Vec3::zero(),
+                vec::Vec3::one() * 0.3,
+                model_map["cube"]
+            );
+            let color = MaterialOverride::color(vec::Vec3::new(u, 0.5, v));
+            go.drawable_object = go.drawable_object.map(|draw| draw.with_material_override(color));
+            ctx.game_obj_store.add(go);
         }
     }
 
-    Box::new(move |shader: &ShaderProgram, draw: &DrawableObject| {
-        shader.set_3_float(UNI_ID[UniEnum::DiffuseColor as usize], draw.position.x / 10.0 + 1.0, draw.position.y + 3.0, draw.position.z / 10.0 + 1.0);
-    })
+    None
 }
 
 pub fn make_scene_physics(
-    ctx: &mut Context<BehaviorDataContainerEnum>,
+    ctx: &mut Context,
     model_map: &HashMap<&str, usize>,
-) -> Box<dyn Fn(&ShaderProgram, &DrawableObject)> {
+) -> Option<PreDraw> {
     ctx.camera.view_pos = vec::Vec3::new(0.0, 1.0, 5.0);
     ctx.camera.view_rot = vec::Vec3::new(0.0, 0.0, -90.0);
     ctx.camera.light_position = vec::Vec3::new(100.0, 100.0, 0.0);
 
-    clear_color(0.5, 0.5, 1.0, 1.0);
+    ctx.clear_color = [0.5, 0.5, 1.0, 1.0];
+
+    /* sun, casts the shadows */
+    ctx.game_obj_store.add(GameObject::empty().add_light(
+        Light::directional([-1.0, -1.0, -0.4], [1.0, 1.0, 1.0], 1.0).with_shadow(ShadowSettings::default())
+    ));
 
     /* floor collider */
     let position = vec::Vec3::new(0.0,0.0,0.0);
@@ -101,7 +336,7 @@ pub fn make_scene_physics(
         ball_body_handle2, 
         &mut ctx.rigid_body_set
     );
-    let id2 = ctx.game_obj_store.add(make_go_rb::<BehaviorDataContainerEnum>(
+    let id2 = ctx.game_obj_store.add(make_go_rb(
         vec::Vec3::zero(), 
         vec::Vec3::zero(),
         vec::Vec3::one(),
@@ -111,25 +346,23 @@ pub fn make_scene_physics(
         model_map["cone_ring"],
         ball_body_handle2
         )
-        .add_behavior(Behaviors::BArrowControl)
-        .add_behavior_data(
-            Behaviors::BArrowControl, 
-            BehaviorDataContainerEnum::ArrowControlData(ArrowControlData{accel: 10.0, max_speed: 5.0})
-        )
-        .add_behavior(Behaviors::BCameraTracking)
-        .add_behavior_data(
-            Behaviors::BCameraTracking, 
-            BehaviorDataContainerEnum::CameraTrackingData(CameraTrackingData{x_off: 0.0, y_off: 2.0, z_off: 20.0})
-        )
-        .add_behavior(Behaviors::BSpawnBall)
-        .add_behavior_data(
-            Behaviors::BSpawnBall, 
-            BehaviorDataContainerEnum::SpawnBallData(SpawnBallData{
-                cooldown_length: Duration::from_secs(2), 
-                last_use: Instant::now() - Duration::from_secs(2)
-            })
-        )
-        .add_collision_behavior(CollisionBehaviors::CHandleFloorCollision)
+        .add_behavior(ArrowControl{accel: 10.0, max_speed: 5.0})
+        .add_behavior(CameraTracking{x_off: 0.0, y_off: 2.0, z_off: 20.0})
+        .add_behavior(SpawnBall{
+            cooldown_length: Duration::from_secs(2), 
+            last_use: Instant::now() - Duration::from_secs(2)
+        })
+        .add_behavior(GroundDetection::new(45.0, false))
+        /* hat, follows the player around */
+        .add_child(make_go(
+            vec::Vec3::new(0.0, 1.5, 0.0),
+            vec::Vec3::zero(),
+            vec::Vec3::one(),
+            vec::Vec3::zero(),
+            vec::Vec3::zero(),
+            vec::Vec3::one() * 0.5,
+            model_map["cone"]
+        ))
     );
 
     /* obstacles */
@@ -152,14 +385,10 @@ pub fn make_scene_physics(
             vec::Vec3::one(),
             model_map["cube"],
             cube_body_handle
-            ).add_behavior(Behaviors::BAttractionTo)
-            .add_behavior_data(
-                Behaviors::BAttractionTo, 
-                BehaviorDataContainerEnum::AttractionToData(AttractionToData{
-                    target: id2,
-                    force: 0.25
-                })
-            )
+            ).add_behavior(AttractionTo{
+                target: id2,
+                force: 0.25
+            })
         );
         ctx.floor_set.insert(cube_body_handle);
     }   
@@ -182,7 +411,93 @@ pub fn make_scene_physics(
         }
     }
     
-    Box::new(move |shader: &ShaderProgram, draw: &DrawableObject| {
-        // shader.set_3_float(UNI_ID[UniEnum::DiffuseColor as usize], draw.position.x / 10.0 + 1.0, draw.position.y + 3.0, draw.position.z / 10.0 + 1.0);
-    })
-}
\ No newline at end of file
+    None
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /* scenes that put `count` bodies into the world, or fail after starting to */
+    fn registry(log: &Rc<RefCell<Vec<String>>>) -> SceneRegistry {
+        let mut registry = SceneRegistry::new();
+        for (name, count, fails) in [("first", 1, false), ("second", 2, false), ("broken", 3, true)] {
+            let log = log.clone();
+            registry.register(name, move |ctx, _| {
+                log.borrow_mut().push(format!("build {} over {} objects", name, ctx.game_obj_store.len()));
+                for i in 0..count {
+                    let mut go = GameObject::empty();
+                    go.rigid_body_handle = Some(ctx.rigid_body_set.insert(RigidBodyBuilder::fixed().translation(vector![i as f32, 0.0]).build()));
+                    ctx.game_obj_store.add(go);
+                }
+                ctx.camera.view_pos = vec::Vec3::broadcast(count as f32);
+                ctx.clear_color = [1.0, 1.0, 1.0, 1.0];
+                if fails {
+                    return Err(format!("{} could not be built", name));
+                }
+                Ok(None)
+            });
+        }
+        let before = log.clone();
+        registry.on_before_unload(move |ctx, from, to| {
+            before.borrow_mut().push(format!("before {:?} to {} with {} objects", from, to, ctx.game_obj_store.len()));
+        });
+        let after = log.clone();
+        registry.on_after_load(move |ctx, from, to| {
+            after.borrow_mut().push(format!("after {:?} to {} with {} objects", from, to, ctx.game_obj_store.len()));
+        });
+        registry
+    }
+
+    #[test]
+    fn hooks_run_around_the_build() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut ctx = Context::headless(64, 64);
+        ctx.scenes = registry(&log);
+        switch_scene(&mut ctx, &HashMap::new(), "first").unwrap();
+        switch_scene(&mut ctx, &HashMap::new(), "second").unwrap();
+
+        assert_eq!(*log.borrow(), vec![
+            "before None to first with 0 objects",
+            "build first over 0 objects",
+            "after None to first with 1 objects",
+            "before Some(\"first\") to second with 1 objects",
+            "build second over 0 objects",
+            "after Some(\"first\") to second with 2 objects",
+        ]);
+        assert_eq!(ctx.current_scene.as_deref(), Some("second"));
+        assert_eq!(ctx.rigid_body_set.len(), 2);
+        assert!(ctx.scenes.contains("first"));
+    }
+
+    #[test]
+    fn failed_switch_puts_the_old_scene_back() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut ctx = Context::headless(64, 64);
+        ctx.scenes = registry(&log);
+        switch_scene(&mut ctx, &HashMap::new(), "first").unwrap();
+        ctx.clear_color = [0.2, 0.3, 0.4, 1.0];
+        let ids = ctx.game_obj_store.ids();
+        log.borrow_mut().clear();
+
+        assert_eq!(switch_scene(&mut ctx, &HashMap::new(), "broken"), Err("broken could not be built".to_string()));
+        /* no after_load for a scene that never loaded */
+        assert_eq!(*log.borrow(), vec![
+            "before Some(\"first\") to broken with 1 objects",
+            "build broken over 0 objects",
+        ]);
+        assert_eq!(ctx.current_scene.as_deref(), Some("first"));
+        assert_eq!(ctx.game_obj_store.ids(), ids);
+        assert_eq!(ctx.rigid_body_set.len(), 1);
+        assert_eq!(ctx.camera.view_pos, vec::Vec3::one());
+        assert_eq!(ctx.clear_color, [0.2, 0.3, 0.4, 1.0]);
+        assert!(ctx.scenes.contains("broken"));
+
+        /* unknown names are turned down before anything is unloaded */
+        log.borrow_mut().clear();
+        assert!(switch_scene(&mut ctx, &HashMap::new(), "missing").is_err());
+        assert!(log.borrow().is_empty());
+        assert_eq!(ctx.game_obj_store.ids(), ids);
+    }
+}
@@ -0,0 +1,511 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+use beryllium::*;
+use log::warn;
+use rapier2d::prelude::*;
+use rhai::{Dynamic, Engine, Map, Scope, AST, CallFnOptions, FLOAT, INT};
+use serde::{Serialize, Deserialize};
+use ultraviolet::vec;
+use crate::gllib::*;
+use crate::ecs::*;
+use crate::behaviors::*;
+use crate::collisions::*;
+
+/* Behaviors written in Rhai. A script file can define any of
+
+    fn update(obj) { ... }
+    fn on_spawn(obj) { ... }
+    fn on_despawn(obj) { ... }
+    fn on_collision_enter(obj, other_id, contact) { ... }
+    fn on_collision_stay(obj, other_id, contact) { ... }
+    fn on_collision_exit(obj, other_id, contact) { ... }
+    fn on_trigger_enter(obj, other_id, is_sensor) { ... }
+    fn on_trigger_exit(obj, other_id, is_sensor) { ... }
+
+`obj` is a ScriptApi exposing the owning object's position, velocity, grounded flag,
+the frame's held keys and time, and commands to apply impulses, spawn and despawn objects
+and offset the camera. Inside those functions `this` is a map that persists between calls
+for the object, for script-side state. Files are reloaded when they change on disk and
+errors are logged with their line instead of stopping the game. `contact` in the collision
+hooks is a map of normal_x, normal_y (pointing towards the other object), depth and impulse.
+See src/scripts/. */
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+thread_local! {
+    static ENGINE: Engine = make_engine();
+}
+
+/* things a script asked for, applied to the world once the script function returns */
+#[derive(Clone, Debug)]
+enum ScriptCommand {
+    ApplyImpulse(f32, f32),
+    SetVelocity(f32, f32),
+    SetGrounded(bool),
+    Spawn { model: String, position: vec::Vec3 },
+    SpawnBall { model: String, position: vec::Vec3, radius: f32 },
+    Despawn(GameObjectID),
+    SetCameraOffset(vec::Vec3),
+}
+
+#[derive(Clone)]
+pub struct ScriptApi {
+    id: GameObjectID,
+    position: vec::Vec3,
+    velocity: Option<(f32, f32)>,
+    grounded: bool,
+    keys_held: Rc<HashSet<Keycode>>,
+    deltasecs: f32,
+    game_time: f32,
+    targets: Rc<HashMap<String, vec::Vec3>>,
+    commands: Rc<RefCell<Vec<ScriptCommand>>>,
+}
+impl ScriptApi {
+    fn push(&mut self, command: ScriptCommand) {
+        self.commands.borrow_mut().push(command);
+    }
+}
+
+fn vec3_map(v: vec::Vec3) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("x".into(), Dynamic::from_float(v.x as FLOAT));
+    map.insert("y".into(), Dynamic::from_float(v.y as FLOAT));
+    map.insert("z".into(), Dynamic::from_float(v.z as FLOAT));
+    Dynamic::from_map(map)
+}
+
+/* keys are named like SDL: single characters ("A", "7") or "SPACE", "LEFT", "LSHIFT" etc. */
+pub fn keycode_from_name(name: &str) -> Option<Keycode> {
+    let upper = name.to_ascii_uppercase();
+    let mut chars = upper.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_alphanumeric() {
+            return Some(Keycode(c.to_ascii_lowercase() as u32));
+        }
+    }
+    Some(match upper.as_str() {
+        "SPACE" => Keycode::SPACE,
+        "RETURN" | "ENTER" => Keycode::RETURN,
+        "ESCAPE" => Keycode::ESCAPE,
+        "TAB" => Keycode::TAB,
+        "BACKSPACE" => Keycode::BACKSPACE,
+        "LEFT" => Keycode::LEFT,
+        "RIGHT" => Keycode::RIGHT,
+        "UP" => Keycode::UP,
+        "DOWN" => Keycode::DOWN,
+        "LSHIFT" => Keycode::LSHIFT,
+        "RSHIFT" => Keycode::RSHIFT,
+        "LCTRL" => Keycode::LCTRL,
+        "RCTRL" => Keycode::RCTRL,
+        "LALT" => Keycode::LALT,
+        "RALT" => Keycode::RALT,
+        _ => return None,
+    })
+}
+
+fn make_engine() -> Engine {
+    let mut engine = Engine::new();
+    /* scripts run every frame, keep runaway loops from hanging the game */
+    engine.set_max_operations(1_000_000);
+    engine.register_type_with_name::<ScriptApi>("Object")
+        .register_get("id", |api: &mut ScriptApi| api.id.to_bits() as INT)
+        .register_get("x", |api: &mut ScriptApi| api.position.x as FLOAT)
+        .register_get("y", |api: &mut ScriptApi| api.position.y as FLOAT)
+        .register_get("z", |api: &mut ScriptApi| api.position.z as FLOAT)
+        .register_get("vx", |api: &mut ScriptApi| api.velocity.map_or(0.0, |v| v.0) as FLOAT)
+        .register_get("vy", |api: &mut ScriptApi| api.velocity.map_or(0.0, |v| v.1) as FLOAT)
+        .register_get("grounded", |api: &mut ScriptApi| api.grounded)
+        .register_get("dt", |api: &mut ScriptApi| api.deltasecs as FLOAT)
+        .register_get("time", |api: &mut ScriptApi| api.game_time as FLOAT)
+        .register_fn("key_held", |api: &mut ScriptApi, name: &str| {
+            keycode_from_name(name).is_some_and(|key| api.keys_held.contains(&key))
+        })
+        .register_fn("target", |api: &mut ScriptApi, name: &str| {
+            api.targets.get(name).map_or(Dynamic::UNIT, |position| vec3_map(*position))
+        })
+        .register_fn("apply_impulse", |api: &mut ScriptApi, x: FLOAT, y: FLOAT| {
+            api.push(ScriptCommand::ApplyImpulse(x as f32, y as f32));
+        })
+        .register_fn("set_velocity", |api: &mut ScriptApi, x: FLOAT, y: FLOAT| {
+            api.velocity = Some((x as f32, y as f32));
+            api.push(ScriptCommand::SetVelocity(x as f32, y as f32));
+        })
+        .register_fn("set_grounded", |api: &mut ScriptApi, grounded: bool| {
+            api.grounded = grounded;
+            api.push(ScriptCommand::SetGrounded(grounded));
+        })
+        .register_fn("spawn", |api: &mut ScriptApi, model: &str, x: FLOAT, y: FLOAT, z: FLOAT| {
+            api.push(ScriptCommand::Spawn { model: model.to_string(), position: vec::Vec3::new(x as f32, y as f32, z as f32) });
+        })
+        .register_fn("spawn_ball", |api: &mut ScriptApi, model: &str, x: FLOAT, y: FLOAT, radius: FLOAT| {
+            api.push(ScriptCommand::SpawnBall { model: model.to_string(), position: vec::Vec3::new(x as f32, y as f32, 0.0), radius: radius as f32 });
+        })
+        .register_fn("despawn", |api: &mut ScriptApi| {
+            let id = api.id;
+            api.push(ScriptCommand::Despawn(id));
+        })
+        .register_fn("despawn", |api: &mut ScriptApi, id: INT| {
+            api.push(ScriptCommand::Despawn(GameObjectID::from_bits(id as u64)));
+        })
+        .register_fn("set_camera_offset", |api: &mut ScriptApi, x: FLOAT, y: FLOAT, z: FLOAT| {
+            api.push(ScriptCommand::SetCameraOffset(vec::Vec3::new(x as f32, y as f32, z as f32)));
+        });
+    engine
+}
+
+struct LoadedScript {
+    ast: Option<AST>,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+    /* set after a runtime error so it's only reported once, cleared when the file changes */
+    broken: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Script {
+    pub path: String,
+    /* objects the script can look up by name with obj.target("name"), "@name" in scene files */
+    #[serde(default)]
+    pub targets: HashMap<String, GameObjectID>,
+    /* when update runs, see Stage */
+    #[serde(default)]
+    pub stage: Stage,
+    #[serde(default)]
+    pub priority: i32,
+    /* the script's `this` map */
+    #[serde(default)]
+    pub state: Dynamic,
+    #[serde(skip)]
+    loaded: Option<LoadedScript>,
+}
+impl Script {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            targets: HashMap::new(),
+            stage: Stage::default(),
+            priority: PRIORITY_DEFAULT,
+            state: Dynamic::UNIT,
+            loaded: None,
+        }
+    }
+
+    pub fn in_stage(mut self, stage: Stage, priority: i32) -> Self {
+        self.stage = stage;
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_target(mut self, name: &str, target: GameObjectID) -> Self {
+        self.targets.insert(name.to_string(), target);
+        self
+    }
+
+    fn modified_time(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|meta| meta.modified()).ok()
+    }
+
+    /* (re)compiles the file when it's new or changed, keeping the last good version on errors */
+    fn reload_if_changed(&mut self) {
+        if let Some(loaded) = &self.loaded {
+            if loaded.last_checked.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+        }
+        let modified = self.modified_time();
+        let previous = self.loaded.take();
+        let unchanged = previous.as_ref().is_some_and(|loaded| loaded.modified == modified);
+        if unchanged {
+            self.loaded = previous.map(|loaded| LoadedScript { last_checked: Instant::now(), ..loaded });
+            return;
+        }
+        let mut ast = previous.and_then(|loaded| loaded.ast);
+        match fs::read_to_string(&self.path) {
+            Ok(source) => match ENGINE.with(|engine| engine.compile(source)) {
+                Ok(new_ast) => ast = Some(new_ast),
+                Err(e) => warn!(target: LT_BEHAVIORS, "{}:{}: {}", self.path, e.position().line().unwrap_or(0), e),
+            },
+            Err(e) => warn!(target: LT_BEHAVIORS, "could not read script {}: {}", self.path, e),
+        }
+        self.loaded = Some(LoadedScript { ast, modified, last_checked: Instant::now(), broken: false });
+    }
+
+    fn target_positions(&self, loop_ctx: &LoopContext) -> HashMap<String, vec::Vec3> {
+        let mut positions = HashMap::new();
+        for (name, id) in &self.targets {
+            let position = if *id == loop_ctx.go.id {
+                Some(loop_ctx.go.position)
+            } else {
+                loop_ctx.game_obj_store.transforms.get(id).map(|transform| transform.position)
+            };
+            if let Some(position) = position {
+                positions.insert(name.clone(), position);
+            }
+        }
+        positions
+    }
+
+    fn call(&mut self, loop_ctx: &mut LoopContext, fn_name: &str, extra_args: Vec<Dynamic>) -> BehaviorOutput {
+        self.reload_if_changed();
+        let arg_count = extra_args.len() + 1;
+        let runnable = match &self.loaded {
+            Some(LoadedScript { ast: Some(ast), broken: false, .. }) =>
+                ast.iter_functions().any(|f| f.name == fn_name && f.params.len() == arg_count),
+            _ => false,
+        };
+        if !runnable {
+            return (vec![], vec![]);
+        }
+
+        let api = ScriptApi {
+            id: loop_ctx.go.id,
+            position: loop_ctx.go.position,
+            velocity: loop_ctx.go.rigid_body_handle.map(|rb_handle| {
+                let linvel = loop_ctx.rigid_body_set[rb_handle].linvel();
+                (linvel.x, linvel.y)
+            }),
+            grounded: loop_ctx.go.grounded,
+            keys_held: Rc::new(loop_ctx.keys_held.clone()),
+            deltasecs: loop_ctx.deltasecs,
+            game_time: loop_ctx.game_time,
+            targets: Rc::new(self.target_positions(loop_ctx)),
+            commands: Rc::new(RefCell::new(vec![])),
+        };
+        let commands = api.commands.clone();
+        let mut args = vec![Dynamic::from(api)];
+        args.extend(extra_args);
+
+        let mut this = std::mem::take(&mut self.state);
+        if !this.is_map() {
+            this = Dynamic::from_map(Map::new());
+        }
+        let loaded = self.loaded.as_mut().unwrap();
+        let ast = loaded.ast.as_ref().unwrap();
+        let result = ENGINE.with(|engine| {
+            let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut this);
+            engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, fn_name, args)
+        });
+        self.state = this;
+        if let Err(e) = result {
+            warn!(target: LT_BEHAVIORS, "{}:{}: {} (script disabled until the file changes)", self.path, e.position().line().unwrap_or(0), e);
+            loaded.broken = true;
+            return (vec![], vec![]);
+        }
+
+        let commands = commands.borrow().clone();
+        apply_script_commands(loop_ctx, commands)
+    }
+}
+
+/* other_id plus #{ normal_x, normal_y, depth, impulse } for the collision hooks */
+fn collision_args(info: &CollisionInfo) -> Vec<Dynamic> {
+    let mut contact = Map::new();
+    contact.insert("normal_x".into(), Dynamic::from_float(info.normal.x as FLOAT));
+    contact.insert("normal_y".into(), Dynamic::from_float(info.normal.y as FLOAT));
+    contact.insert("depth".into(), Dynamic::from_float(info.depth() as FLOAT));
+    contact.insert("impulse".into(), Dynamic::from_float(info.impulse() as FLOAT));
+    vec![Dynamic::from_int(info.other.to_bits() as INT), Dynamic::from_map(contact)]
+}
+
+fn apply_script_commands(loop_ctx: &mut LoopContext, commands: Vec<ScriptCommand>) -> BehaviorOutput {
+    let mut objs_to_remove = vec![];
+    let mut objs_to_add = vec![];
+    for command in commands {
+        match command {
+            ScriptCommand::ApplyImpulse(x, y) => {
+                if let Some(rb_handle) = loop_ctx.go.rigid_body_handle {
+                    loop_ctx.rigid_body_set[rb_handle].apply_impulse(vector![x, y], true);
+                }
+            },
+            ScriptCommand::SetVelocity(x, y) => {
+                if let Some(rb_handle) = loop_ctx.go.rigid_body_handle {
+                    loop_ctx.rigid_body_set[rb_handle].set_linvel(vector![x, y], true);
+                }
+            },
+            ScriptCommand::SetGrounded(grounded) => loop_ctx.go.grounded = grounded,
+            ScriptCommand::Spawn { model, position } => match loop_ctx.model_map.get(model.as_str()) {
+                Some(model_idx) => objs_to_add.push(make_go(
+                    position,
+                    vec::Vec3::zero(),
+                    vec::Vec3::one(),
+                    vec::Vec3::zero(),
+                    vec::Vec3::zero(),
+                    vec::Vec3::one(),
+                    *model_idx
+                )),
+                None => warn!(target: LT_BEHAVIORS, "script tried to spawn unknown model {}", model),
+            },
+            ScriptCommand::SpawnBall { model, position, radius } => match loop_ctx.model_map.get(model.as_str()) {
+                Some(model_idx) => {
+                    let ball_body_handle = loop_ctx.rigid_body_set.insert(
+                        RigidBodyBuilder::dynamic().translation(vector![position.x, position.y]).build()
+                    );
+                    loop_ctx.collider_set.insert_with_parent(
+                        ColliderBuilder::ball(radius).active_events(ActiveEvents::COLLISION_EVENTS).build(),
+                        ball_body_handle,
+                        loop_ctx.rigid_body_set
+                    );
+                    objs_to_add.push(make_go_rb(
+                        position,
+                        vec::Vec3::zero(),
+                        vec::Vec3::one(),
+                        vec::Vec3::zero(),
+                        vec::Vec3::zero(),
+                        vec::Vec3::one() * radius,
+                        *model_idx,
+                        ball_body_handle
+                    ));
+                },
+                None => warn!(target: LT_BEHAVIORS, "script tried to spawn unknown model {}", model),
+            },
+            ScriptCommand::Despawn(id) => objs_to_remove.push(id),
+            ScriptCommand::SetCameraOffset(offset) => loop_ctx.camera.view_pos = loop_ctx.go.position + offset,
+        }
+    }
+    (objs_to_remove, objs_to_add)
+}
+
+impl Behavior for Script {
+    fn name(&self) -> &'static str { "Script" }
+
+    fn stage(&self) -> Stage { self.stage }
+
+    fn priority(&self) -> i32 { self.priority }
+
+    fn update(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        self.call(loop_ctx, "update", vec![])
+    }
+
+    fn on_collision_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.call(loop_ctx, "on_collision_enter", collision_args(info))
+    }
+
+    fn on_collision_stay(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.call(loop_ctx, "on_collision_stay", collision_args(info))
+    }
+
+    fn on_collision_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.call(loop_ctx, "on_collision_exit", collision_args(info))
+    }
+
+    fn on_trigger_enter(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.call(loop_ctx, "on_trigger_enter", vec![Dynamic::from_int(info.other.to_bits() as INT), Dynamic::from_bool(info.is_sensor)])
+    }
+
+    fn on_trigger_exit(&mut self, loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.call(loop_ctx, "on_trigger_exit", vec![Dynamic::from_int(info.other.to_bits() as INT), Dynamic::from_bool(info.is_sensor)])
+    }
+
+    fn on_spawn(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        self.call(loop_ctx, "on_spawn", vec![])
+    }
+
+    fn on_despawn(&mut self, loop_ctx: &mut LoopContext) -> BehaviorOutput {
+        self.call(loop_ctx, "on_despawn", vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::*;
+
+    /* a script file of its own under the system temp dir for each test */
+    fn script_file(test: &str, source: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rustgraphics-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("script.rhai").to_string_lossy().into_owned();
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    /* a world with one ball running the script */
+    fn scripted_ball(path: &str) -> (Context, GameObjectID, RigidBodyHandle) {
+        let mut ctx = Context::headless(64, 64);
+        let rb_handle = ctx.rigid_body_set.insert(RigidBodyBuilder::dynamic().translation(vector![0.0, 5.0]).build());
+        ctx.collider_set.insert_with_parent(ColliderBuilder::ball(0.5).build(), rb_handle, &mut ctx.rigid_body_set);
+        let mut go = GameObject::empty().add_behavior(Script::new(path));
+        go.position = vec::Vec3::new(0.0, 5.0, 0.0);
+        go.rigid_body_handle = Some(rb_handle);
+        let id = ctx.game_obj_store.add(go);
+        (ctx, id, rb_handle)
+    }
+
+    fn script<'a>(ctx: &'a mut Context, id: &GameObjectID) -> &'a mut Script {
+        ctx.game_obj_store.behaviors.get_mut(id).unwrap().iter_mut()
+            .find_map(|behavior| behavior.as_any_mut().downcast_mut::<Script>())
+            .unwrap()
+    }
+
+    fn run(ctx: &mut Context, steps: u64) {
+        HeadlessRun::new(ScriptedInput::new()).run(ctx, &HashMap::new(), steps).unwrap();
+    }
+
+    /* how many times update ran, counted by the script in `this` */
+    fn count(ctx: &mut Context, id: &GameObjectID) -> INT {
+        script(ctx, id).state.as_map_ref().unwrap().get("count").unwrap().as_int().unwrap()
+    }
+
+    const COUNT_THEN_FAIL: &str = r#"
+        fn update(obj) {
+            if "count" in this { this.count += 1; } else { this.count = 1; }
+            if this.count >= 3 { throw "out of patience"; }
+        }
+    "#;
+
+    #[test]
+    fn runtime_errors_disable_the_script_until_it_changes() {
+        let path = script_file("script-error", COUNT_THEN_FAIL);
+        let (mut ctx, id, _) = scripted_ball(&path);
+        run(&mut ctx, 10);
+        assert_eq!(count(&mut ctx, &id), 3);
+        assert!(script(&mut ctx, &id).loaded.as_ref().unwrap().broken);
+
+        /* an edit brings it back, with its state */
+        let fixed = COUNT_THEN_FAIL.replace(">= 3", ">= 5");
+        fs::write(&path, fixed).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        script(&mut ctx, &id).loaded.as_mut().unwrap().last_checked -= RELOAD_CHECK_INTERVAL;
+        run(&mut ctx, 10);
+        assert_eq!(count(&mut ctx, &id), 5);
+        assert!(script(&mut ctx, &id).loaded.as_ref().unwrap().broken);
+    }
+
+    #[test]
+    fn runaway_loops_are_stopped() {
+        let path = script_file("script-loop", "fn update(obj) { loop { this.spins = 1; } }");
+        let (mut ctx, id, _) = scripted_ball(&path);
+        run(&mut ctx, 3);
+        assert!(script(&mut ctx, &id).loaded.as_ref().unwrap().broken);
+    }
+
+    #[test]
+    fn commands_are_applied_after_the_call() {
+        let path = script_file("script-commands", r#"
+            fn update(obj) {
+                if "count" in this { this.count += 1; } else { this.count = 1; }
+                obj.set_velocity(3.0, 0.0);
+                obj.set_grounded(false);
+                obj.set_camera_offset(0.0, 1.0, 10.0);
+                if this.count == 4 { obj.despawn(); }
+            }
+        "#);
+        let (mut ctx, id, rb_handle) = scripted_ball(&path);
+        run(&mut ctx, 1);
+        assert_eq!(ctx.rigid_body_set[rb_handle].linvel().x, 3.0);
+        assert!(!*ctx.game_obj_store.grounded.get(&id).unwrap());
+        let position = ctx.game_obj_store.transforms.get(&id).unwrap().position;
+        assert_eq!(ctx.camera.view_pos, position + vec::Vec3::new(0.0, 1.0, 10.0));
+
+        run(&mut ctx, 2);
+        assert!(ctx.game_obj_store.contains(&id));
+        run(&mut ctx, 1);
+        assert!(!ctx.game_obj_store.contains(&id));
+        assert!(!ctx.rigid_body_set.contains(rb_handle));
+    }
+}
@@ -0,0 +1,197 @@
+use serde::{Serialize, Deserialize};
+use ultraviolet::{mat, vec, projection};
+use crate::gllib::*;
+use crate::lights::*;
+
+/* Shadows come from one light per frame: the first gathered light that has shadow settings,
+e.g. `light: Some((kind: Directional, shadow: Some((resolution: 4096))))` in a scene file.
+Before the frame is drawn the renderer draws the depth of every object that casts shadows
+as seen from that light into a shadow map, then while shading compares each pixel's depth
+from the light against it. Only directional and spot lights can cast shadows, a point light
+would need a map for every direction, so scene files that give one shadow settings don't load. */
+
+fn default_resolution() -> u32 { 2048 }
+fn default_bias() -> f32 { 0.0005 }
+fn default_slope_bias() -> f32 { 0.002 }
+fn default_pcf_radius() -> u32 { 1 }
+fn default_extent() -> f32 { 30.0 }
+fn default_range() -> f32 { 100.0 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ShadowSettings {
+    /* width and height of the shadow map in texels */
+    #[serde(default = "default_resolution")]
+    pub resolution: u32,
+    /* depth a surface is moved towards the light before the comparison, against shadow acne.
+    slope_bias is added on top for surfaces the light hits at a grazing angle */
+    #[serde(default = "default_bias")]
+    pub bias: f32,
+    #[serde(default = "default_slope_bias")]
+    pub slope_bias: f32,
+    /* PCF, the comparison is averaged over (2 * pcf_radius + 1)^2 texels to soften the edges */
+    #[serde(default = "default_pcf_radius")]
+    pub pcf_radius: u32,
+    /* directional lights cover a square 2 * extent wide in front of the camera */
+    #[serde(default = "default_extent")]
+    pub extent: f32,
+    /* how deep the shadow map reaches along the light */
+    #[serde(default = "default_range")]
+    pub range: f32,
+}
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: default_resolution(),
+            bias: default_bias(),
+            slope_bias: default_slope_bias(),
+            pcf_radius: default_pcf_radius(),
+            extent: default_extent(),
+            range: default_range(),
+        }
+    }
+}
+
+/* the shadow of one frame */
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowParams {
+    /* index of the shadow light in the frame's lights */
+    pub light_index: usize,
+    /* world space to the light's clip space */
+    pub light_space: mat::Mat4,
+    pub settings: ShadowSettings,
+}
+impl ShadowParams {
+    pub fn for_frame(lights: &[WorldLight], camera: &CameraParams) -> Option<Self> {
+        lights.iter().enumerate()
+            .find_map(|(light_index, light)| {
+                let settings = light.shadow?;
+                let light_space = light_space_matrix(light, &settings, camera)?;
+                Some(Self { light_index, light_space, settings })
+            })
+    }
+}
+
+/* what the light sees, None for point lights */
+pub fn light_space_matrix(light: &WorldLight, settings: &ShadowSettings, camera: &CameraParams) -> Option<mat::Mat4> {
+    /* look_at needs an up that isn't along the light */
+    let up = if light.direction.dot(vec::Vec3::unit_y()).abs() > 0.99 { vec::Vec3::unit_z() } else { vec::Vec3::unit_y() };
+    match light.kind {
+        LightKind::Point => None,
+        LightKind::Directional => {
+            let rotation = mat::Mat4::look_at(vec::Vec3::zero(), light.direction, up);
+            let center = camera.view_pos + camera.look_dir() * settings.extent;
+            /* the box moves in whole texels, so shadow edges don't shimmer as the camera moves */
+            let texel = 2.0 * settings.extent / settings.resolution.max(1) as f32;
+            let center = rotation.transform_point3(center);
+            let (x, y) = ((center.x / texel).floor() * texel, (center.y / texel).floor() * texel);
+            let projection = projection::orthographic_gl(
+                x - settings.extent, x + settings.extent,
+                y - settings.extent, y + settings.extent,
+                -center.z - settings.range / 2.0, -center.z + settings.range / 2.0,
+            );
+            Some(projection * rotation)
+        },
+        LightKind::Spot { outer_angle, .. } => {
+            let view = mat::Mat4::look_at(light.position, light.position + light.direction, up);
+            let fov = (2.0 * outer_angle).clamp(1.0, 160.0).to_radians();
+            Some(projection::perspective_gl(fov, 1.0, 0.1, settings.range) * view)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* at the origin looking along +z */
+    fn camera(view_pos: vec::Vec3) -> CameraParams {
+        CameraParams::new(view_pos, vec::Vec3::new(0.0, 0.0, 90.0), vec::Vec3::zero(), mat::Mat4::identity())
+    }
+
+    fn settings() -> ShadowSettings {
+        /* one texel per world unit */
+        ShadowSettings { resolution: 20, extent: 10.0, range: 40.0, ..ShadowSettings::default() }
+    }
+
+    fn world(light: Light, position: vec::Vec3) -> WorldLight {
+        light.to_world(&mat::Mat4::from_translation(position), &mat::Mat4::identity())
+    }
+
+    fn ndc(matrix: &mat::Mat4, point: vec::Vec3) -> vec::Vec3 {
+        let clip = *matrix * point.into_homogeneous_point();
+        clip.xyz() / clip.w
+    }
+
+    fn inside(point: vec::Vec3) -> bool {
+        point.x.abs() < 1.0 && point.y.abs() < 1.0 && point.z.abs() < 1.0
+    }
+
+    #[test]
+    fn directional_box_covers_extent_in_front_of_the_camera() {
+        let sun = world(Light::directional([0.0, -1.0, 0.0], [1.0, 1.0, 1.0], 1.0), vec::Vec3::zero());
+        let matrix = light_space_matrix(&sun, &settings(), &camera(vec::Vec3::zero())).unwrap();
+        let center = vec::Vec3::new(0.0, 0.0, 10.0);
+
+        let at_center = ndc(&matrix, center);
+        assert!(at_center.x.abs() <= 0.1 && at_center.y.abs() <= 0.1 && at_center.z.abs() < 0.01, "{:?}", at_center);
+        for offset in [vec::Vec3::unit_x(), vec::Vec3::unit_z()] {
+            assert!(inside(ndc(&matrix, center + offset * 8.5)));
+            assert!(inside(ndc(&matrix, center - offset * 8.5)));
+            assert!(!inside(ndc(&matrix, center + offset * 11.5)));
+            assert!(!inside(ndc(&matrix, center - offset * 11.5)));
+        }
+        /* range is centered on the camera's focus along the light */
+        assert!(inside(ndc(&matrix, center + vec::Vec3::unit_y() * 19.0)));
+        assert!(!inside(ndc(&matrix, center + vec::Vec3::unit_y() * 21.0)));
+    }
+
+    #[test]
+    fn directional_box_moves_in_whole_texels() {
+        let sun = world(Light::directional([0.0, -1.0, 0.0], [1.0, 1.0, 1.0], 1.0), vec::Vec3::zero());
+        let at = |x: f32| light_space_matrix(&sun, &settings(), &camera(vec::Vec3::new(x, 0.0, 0.0))).unwrap();
+
+        /* moving within a texel keeps the box where it is */
+        assert_eq!(at(0.2), at(0.5));
+        /* moving across one shifts it by exactly one texel, a tenth of the half width */
+        let probe = vec::Vec3::new(0.0, 0.0, 10.0);
+        let shift = ndc(&at(1.2), probe).x - ndc(&at(0.5), probe).x;
+        assert!((shift.abs() - 0.1).abs() < 1e-4, "{}", shift);
+    }
+
+    #[test]
+    fn spot_sees_its_cone() {
+        let spot = world(Light::spot([0.0, -1.0, 0.0], 30.0, 45.0, [1.0, 1.0, 1.0], 1.0), vec::Vec3::new(0.0, 5.0, 0.0));
+        let matrix = light_space_matrix(&spot, &settings(), &camera(vec::Vec3::zero())).unwrap();
+
+        let below = ndc(&matrix, vec::Vec3::zero());
+        assert!(below.x.abs() < 1e-4 && below.y.abs() < 1e-4 && below.z.abs() < 1.0);
+        /* a 90 degree field of view for the 45 degree cone */
+        for offset in [vec::Vec3::unit_x(), vec::Vec3::unit_z()] {
+            assert!(inside(ndc(&matrix, offset * 4.8)));
+            assert!(!inside(ndc(&matrix, offset * 5.2)));
+        }
+        assert!(!inside(ndc(&matrix, vec::Vec3::new(0.0, 6.0, 0.0))));
+        assert!(!inside(ndc(&matrix, vec::Vec3::new(0.0, -36.0, 0.0))));
+    }
+
+    #[test]
+    fn point_lights_have_no_shadow_map() {
+        let point = world(Light::point([1.0, 1.0, 1.0], 1.0).with_shadow(settings()), vec::Vec3::zero());
+        assert!(light_space_matrix(&point, &settings(), &camera(vec::Vec3::zero())).is_none());
+    }
+
+    #[test]
+    fn frame_uses_the_first_light_that_casts_shadows() {
+        let lights = [
+            world(Light::directional([0.0, -1.0, 0.0], [1.0, 1.0, 1.0], 1.0), vec::Vec3::zero()),
+            world(Light::point([1.0, 1.0, 1.0], 1.0).with_shadow(settings()), vec::Vec3::zero()),
+            world(Light::spot([0.0, -1.0, 0.0], 30.0, 45.0, [1.0, 1.0, 1.0], 1.0).with_shadow(settings()), vec::Vec3::unit_y()),
+            world(Light::directional([0.0, -1.0, 0.0], [1.0, 1.0, 1.0], 1.0).with_shadow(ShadowSettings::default()), vec::Vec3::zero()),
+        ];
+        let shadow = ShadowParams::for_frame(&lights, &camera(vec::Vec3::zero())).unwrap();
+        assert_eq!(shadow.light_index, 2);
+        assert_eq!(shadow.settings, settings());
+
+        assert!(ShadowParams::for_frame(&lights[..2], &camera(vec::Vec3::zero())).is_none());
+    }
+}
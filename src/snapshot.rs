@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use rapier2d::prelude::*;
+use ron::ser::PrettyConfig;
+use serde::{Serialize, Deserialize};
+use crate::gllib::*;
+use crate::ecs::*;
+
+/* A snapshot is the whole live world written out as RON: camera, every game object
+(transforms, behaviors and their data), the rapier body and collider sets including velocities,
+the floor set and the rest of the physics state. Restoring one puts the world back exactly
+as it was, which covers save games, attaching state to bug reports and resetting a level. */
+
+/* behaviors of one object as (registered name, saved state) pairs */
+type SavedBehaviors = Vec<(String, ron::Value)>;
+
+#[derive(Serialize)]
+struct WorldSnapshotRef<'a> {
+    camera: &'a CameraParams,
+    game_obj_store: &'a GameObjectStore,
+    behaviors: Vec<(GameObjectID, SavedBehaviors)>,
+    rigid_body_set: &'a RigidBodySet,
+    collider_set: &'a ColliderSet,
+    floor_set: &'a HashSet<RigidBodyHandle>,
+    physics: &'a PhysicsState,
+}
+
+#[derive(Deserialize)]
+struct WorldSnapshot {
+    camera: CameraParams,
+    game_obj_store: GameObjectStore,
+    behaviors: Vec<(GameObjectID, SavedBehaviors)>,
+    rigid_body_set: RigidBodySet,
+    collider_set: ColliderSet,
+    floor_set: HashSet<RigidBodyHandle>,
+    physics: PhysicsState,
+}
+
+pub fn save_snapshot(ctx: &Context) -> Result<String, String> {
+    let mut behaviors = vec![];
+    for (id, obj_behaviors) in ctx.game_obj_store.behaviors.iter() {
+        let mut saved = vec![];
+        for b in obj_behaviors {
+            saved.push((b.name().to_string(), b.save_state()?));
+        }
+        behaviors.push((id, saved));
+    }
+    let snapshot = WorldSnapshotRef {
+        camera: &ctx.camera,
+        game_obj_store: &ctx.game_obj_store,
+        behaviors,
+        rigid_body_set: &ctx.rigid_body_set,
+        collider_set: &ctx.collider_set,
+        floor_set: &ctx.floor_set,
+        physics: &ctx.physics,
+    };
+    ron::ser::to_string_pretty(&snapshot, PrettyConfig::new().compact_arrays(true))
+        .map_err(|e| format!("Could not serialize snapshot: {}", e))
+}
+
+pub fn restore_snapshot(ctx: &mut Context, snapshot: &str) -> Result<(), String> {
+    let mut snapshot: WorldSnapshot = ron::from_str(snapshot)
+        .map_err(|e| format!("Could not parse snapshot: {}", e))?;
+    /* rebuild every behavior before touching the world so a bad snapshot leaves it untouched */
+    for (id, saved) in snapshot.behaviors {
+        if !snapshot.game_obj_store.contains(&id) {
+            return Err(format!("Snapshot has behaviors for missing object {}", id));
+        }
+        let mut obj_behaviors = vec![];
+        for (name, state) in saved {
+            obj_behaviors.push(ctx.behavior_registry.create(&name, state)?);
+        }
+        snapshot.game_obj_store.behaviors.insert(id, obj_behaviors);
+    }
+    ctx.camera = snapshot.camera;
+    ctx.game_obj_store = snapshot.game_obj_store;
+    ctx.rigid_body_set = snapshot.rigid_body_set;
+    ctx.collider_set = snapshot.collider_set;
+    ctx.floor_set = snapshot.floor_set;
+    ctx.physics = snapshot.physics;
+    Ok(())
+}
+
+pub fn write_snapshot_file(ctx: &Context, path: &str) -> Result<(), String> {
+    let snapshot = save_snapshot(ctx)?;
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Could not create snapshot folder for {}: {}", path, e))?;
+    }
+    fs::write(path, snapshot).map_err(|e| format!("Could not write snapshot file {}: {}", path, e))
+}
+
+pub fn read_snapshot_file(ctx: &mut Context, path: &str) -> Result<(), String> {
+    let snapshot = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read snapshot file {}: {}", path, e))?;
+    restore_snapshot(ctx, &snapshot)
+}
+
+/* Instants are opaque, so they are stored as how long before the snapshot they were
+and rebuilt relative to the moment the snapshot is restored. This keeps cooldowns like
+SpawnBall::last_use exactly as far along as they were when saved. */
+pub mod serde_instant {
+    use std::time::{Duration, Instant};
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        instant.elapsed().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        Ok(before_now(Duration::deserialize(deserializer)?))
+    }
+
+    /* `elapsed` before now, or the earliest instant there is when the clock doesn't reach that far
+    back, e.g. a saved cooldown longer than the machine has been up. It then still reads as at
+    least as long ago as anything the clock can tell apart, instead of just now */
+    pub fn before_now(elapsed: Duration) -> Instant {
+        let now = Instant::now();
+        if let Some(instant) = now.checked_sub(elapsed) {
+            return instant;
+        }
+        /* the longest duration that can still be subtracted lies between these two */
+        let (mut reachable, mut unreachable) = (Duration::ZERO, elapsed);
+        while unreachable - reachable > Duration::from_nanos(1) {
+            let middle = reachable + (unreachable - reachable) / 2;
+            if now.checked_sub(middle).is_some() {
+                reachable = middle;
+            } else {
+                unreachable = middle;
+            }
+        }
+        now - reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use serde::{Serialize, Deserialize};
+    use super::{serde_instant, save_snapshot, restore_snapshot};
+    use rapier2d::prelude::*;
+    use ultraviolet::vec;
+    use crate::gllib::*;
+    use crate::behaviors::FadeOut;
+
+    #[derive(Serialize, Deserialize)]
+    struct Saved {
+        #[serde(with = "serde_instant")]
+        at: Instant,
+    }
+
+    #[test]
+    fn instants_come_back_as_far_in_the_past() {
+        let saved = Saved { at: serde_instant::before_now(Duration::from_secs(5)) };
+        let restored: Saved = ron::from_str(&ron::to_string(&saved).unwrap()).unwrap();
+        let elapsed = restored.at.elapsed();
+        assert!(elapsed >= Duration::from_secs(5) && elapsed < Duration::from_secs(6), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn instants_too_far_back_saturate() {
+        let earliest = serde_instant::before_now(Duration::MAX);
+        assert!(earliest < Instant::now());
+        /* nothing before it can be made */
+        assert!(earliest.checked_sub(Duration::from_nanos(2)).is_none());
+        assert!(earliest.elapsed() >= serde_instant::before_now(Duration::from_secs(1)).elapsed());
+    }
+
+    /* a body with a FadeOut */
+    fn world() -> Context {
+        let mut ctx = Context::headless(64, 64);
+        let rb_handle = ctx.rigid_body_set.insert(RigidBodyBuilder::dynamic().translation(vector![1.0, 2.0]).build());
+        let mut go = GameObject::empty().add_behavior(FadeOut::new(2.0));
+        go.position = vec::Vec3::new(1.0, 2.0, 0.0);
+        go.rigid_body_handle = Some(rb_handle);
+        ctx.game_obj_store.add(go);
+        ctx
+    }
+
+    /* what a failed restore must leave as it was */
+    fn state(ctx: &Context) -> (Vec<String>, usize, vec::Vec3) {
+        let objects = ctx.game_obj_store.transforms.iter().map(|(id, transform)| {
+            let behaviors: Vec<_> = ctx.game_obj_store.behaviors.get(&id).unwrap().iter().map(|b| b.name()).collect();
+            format!("{} at {:?} with {:?}", id, transform.position, behaviors)
+        }).collect();
+        (objects, ctx.rigid_body_set.len(), ctx.camera.view_pos)
+    }
+
+    #[test]
+    fn bad_snapshots_leave_the_world_untouched() {
+        let saved = save_snapshot(&world()).unwrap();
+        assert!(saved.contains("\"FadeOut\""));
+
+        let mut ctx = world();
+        ctx.game_obj_store.add(GameObject::empty());
+        ctx.camera.view_pos = vec::Vec3::new(7.0, 8.0, 9.0);
+        let before = state(&ctx);
+        for corrupt in [
+            saved[..saved.len() / 2].to_string(),
+            saved.replace("\"FadeOut\"", "\"FadeAway\""),
+            "not a snapshot".to_string(),
+        ] {
+            assert!(restore_snapshot(&mut ctx, &corrupt).is_err());
+            assert_eq!(state(&ctx), before);
+        }
+
+        restore_snapshot(&mut ctx, &saved).unwrap();
+        assert_eq!(state(&ctx), state(&world()));
+    }
+}
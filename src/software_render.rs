@@ -0,0 +1,717 @@
+use image::RgbaImage;
+use ultraviolet::{mat, vec};
+use crate::gllib::*;
+use crate::render::*;
+use crate::shadows::*;
+use crate::materials::*;
+use crate::render_queue::*;
+use crate::environment::*;
+
+/* A RenderBackend that draws on the CPU: triangles are clipped against the near plane,
+rasterized with a depth buffer and shaded per pixel the same way as param_blinn_phong_shader,
+shadow map, PCF, material maps, alpha testing, blending and the skybox included.
+It is much slower than GL and ignores the scene's pre_draw (there is no shader to hand it) and post-processing,
+but needs nothing besides the CPU, so tests and build servers can render with it. */
+
+/* a mesh of a model as positions, normals and uvs, with triangles as index triples.
+Its material comes with each DrawItem, see RenderQueue */
+struct SoftwareMesh {
+    positions: Vec<vec::Vec3>,
+    normals: Vec<vec::Vec3>,
+    uvs: Vec<vec::Vec2>,
+    indices: Vec<u32>,
+}
+
+/* a vertex after the vertex stage, everything the pixels of its triangles interpolate */
+#[derive(Clone, Copy)]
+struct ClipVertex {
+    clip: vec::Vec4,
+    world: vec::Vec3,
+    normal: vec::Vec3,
+    uv: vec::Vec2,
+}
+impl ClipVertex {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            clip: self.clip + (other.clip - self.clip) * t,
+            world: self.world + (other.world - self.world) * t,
+            normal: self.normal + (other.normal - self.normal) * t,
+            uv: self.uv + (other.uv - self.uv) * t,
+        }
+    }
+}
+
+/* a vertex in pixel coordinates, with 1/w to interpolate perspective correctly */
+#[derive(Clone, Copy)]
+struct ScreenVertex {
+    x: f32,
+    y: f32,
+    depth: f32,
+    inv_w: f32,
+    world: vec::Vec3,
+    normal: vec::Vec3,
+    uv: vec::Vec2,
+}
+
+/* what every pixel of a triangle shares */
+#[derive(Clone, Copy)]
+struct Surface {
+    material_idx: usize,
+    material_override: MaterialOverride,
+    receive_shadows: bool,
+    /* how it's mixed with the pixels under it in the Transparent pass, None for the others */
+    blend: Option<BlendMode>,
+    depth_write: bool,
+    /* world space directions of increasing u and v, for bump maps */
+    tangent: vec::Vec3,
+    bitangent: vec::Vec3,
+}
+
+/* one pixel of a triangle, interpolated */
+struct Fragment {
+    world: vec::Vec3,
+    normal: vec::Vec3,
+    uv: vec::Vec2,
+}
+
+/* depths as seen from the shadow light, rows top to bottom like the color buffer */
+struct SoftwareShadowMap {
+    size: u32,
+    depth: Vec<f32>,
+}
+
+pub struct SoftwareBackend {
+    screen_size: (u32, u32),
+    width: u32,
+    height: u32,
+    color: Vec<[u8; 4]>,
+    depth: Vec<f32>,
+    textures: Vec<RgbaImage>,
+    materials: Vec<Material>,
+    meshes: Vec<Vec<SoftwareMesh>>,
+    cubemaps: Vec<CubemapData>,
+    frame: Option<FrameParams>,
+    shadow_map: SoftwareShadowMap,
+}
+impl SoftwareBackend {
+    pub fn new(screen_width: u32, screen_height: u32) -> Self {
+        let mut backend = Self {
+            screen_size: (screen_width, screen_height),
+            width: 0,
+            height: 0,
+            color: vec![],
+            depth: vec![],
+            textures: vec![],
+            materials: vec![],
+            meshes: vec![],
+            cubemaps: vec![],
+            frame: None,
+            shadow_map: SoftwareShadowMap { size: 0, depth: vec![] },
+        };
+        backend.resize(screen_width, screen_height);
+        backend
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.color = vec![[0, 0, 0, 255]; (width * height) as usize];
+        self.depth = vec![1.0; (width * height) as usize];
+    }
+
+    /* the item's mesh of the call's model, with the material the render queue worked out for it */
+    fn draw_call(&mut self, call: &DrawCall, item: &DrawItem) {
+        let view_projection: mat::Mat4 = match &self.frame {
+            Some(frame) => frame.projection * frame.view,
+            None => return,
+        };
+        let mesh = match self.meshes.get(call.mesh).and_then(|meshes| meshes.get(item.part)) {
+            Some(mesh) => mesh,
+            None => return,
+        };
+        let (blend, depth_write) = match (item.pass, self.materials.get(item.material)) {
+            (RenderPass::Transparent, Some(material)) => (Some(material.blend), material.depth_write),
+            _ => (None, true),
+        };
+        let mut triangles = vec![];
+        {
+            let vertices: Vec<ClipVertex> = mesh.positions.iter().zip(mesh.normals.iter()).zip(mesh.uvs.iter())
+                .map(|((position, normal), uv)| {
+                    let world = call.model * position.into_homogeneous_point();
+                    ClipVertex {
+                        clip: view_projection * world,
+                        world: world.xyz(),
+                        normal: (call.rotation * normal.into_homogeneous_vector()).xyz(),
+                        uv: *uv,
+                    }
+                })
+                .collect();
+            for triangle in mesh.indices.chunks_exact(3) {
+                if let (Some(a), Some(b), Some(c)) = (vertices.get(triangle[0] as usize), vertices.get(triangle[1] as usize), vertices.get(triangle[2] as usize)) {
+                    let triangle = [*a, *b, *c];
+                    let (tangent, bitangent) = tangent_frame(&triangle);
+                    triangles.push((triangle, Surface {
+                        material_idx: item.material,
+                        material_override: call.object.material_override,
+                        receive_shadows: call.object.receive_shadows,
+                        blend,
+                        depth_write,
+                        tangent,
+                        bitangent,
+                    }));
+                }
+            }
+        }
+        for (triangle, surface) in triangles {
+            self.draw_triangle(triangle, surface);
+        }
+    }
+
+    fn draw_triangle(&mut self, triangle: [ClipVertex; 3], surface: Surface) {
+        let screen: Vec<ScreenVertex> = clip_near(triangle).iter().map(|v| to_screen(v, self.width, self.height)).collect();
+        for i in 1..screen.len().saturating_sub(1) {
+            self.fill_triangle([screen[0], screen[i], screen[i + 1]], surface);
+        }
+    }
+
+    fn fill_triangle(&mut self, [a, b, c]: [ScreenVertex; 3], surface: Surface) {
+        let area = edge(&a, &b, c.x, c.y);
+        if area.abs() < f32::EPSILON {
+            return;
+        }
+        let (min_x, min_y, max_x, max_y) = pixel_bounds(&[a, b, c], self.width, self.height);
+        let frame = match &self.frame {
+            Some(frame) => frame,
+            None => return,
+        };
+        let material = match self.materials.get(surface.material_idx) {
+            Some(material) => material,
+            None => return,
+        };
+        let params = surface.material_override.apply(&material.params);
+        let environment = frame.environment
+            .and_then(|environment| self.cubemaps.get(environment.cubemap).map(|cubemap| (cubemap, environment.intensity)));
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                /* barycentric weights, all the same sign as the area inside the triangle */
+                let wa = edge(&b, &c, px, py) / area;
+                let wb = edge(&c, &a, px, py) / area;
+                let wc = edge(&a, &b, px, py) / area;
+                if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                    continue;
+                }
+                let depth = wa * a.depth + wb * b.depth + wc * c.depth;
+                let i = (y * self.width + x) as usize;
+                if !(0.0..=1.0).contains(&depth) || depth >= self.depth[i] {
+                    continue;
+                }
+                let inv_w = wa * a.inv_w + wb * b.inv_w + wc * c.inv_w;
+                let world = (a.world * wa + b.world * wb + c.world * wc) / inv_w;
+                let normal = (a.normal * wa + b.normal * wb + c.normal * wc) / inv_w;
+                let uv = (a.uv * wa + b.uv * wb + c.uv * wc) / inv_w;
+                let shadow_map = Some(&self.shadow_map).filter(|_| surface.receive_shadows);
+                if let Some(color) = shade(material, &params, &self.textures, &surface, frame, shadow_map, environment, &Fragment { world, normal, uv }) {
+                    if surface.depth_write {
+                        self.depth[i] = depth;
+                    }
+                    self.color[i] = match surface.blend {
+                        Some(blend) => blend_pixel(blend, color, self.color[i]),
+                        None => color,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/* only the near plane is clipped against, everything else outside is skipped per pixel.
+What is left of the triangle is a convex polygon, empty when all of it is behind the camera */
+fn clip_near(triangle: [ClipVertex; 3]) -> Vec<ClipVertex> {
+    let mut polygon: Vec<ClipVertex> = vec![];
+    for i in 0..3 {
+        let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+        let (da, db) = (a.clip.z + a.clip.w, b.clip.z + b.clip.w);
+        if da >= 0.0 {
+            polygon.push(a);
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            polygon.push(a.lerp(&b, da / (da - db)));
+        }
+    }
+    if polygon.len() < 3 {
+        polygon.clear();
+    }
+    polygon
+}
+
+fn to_screen(v: &ClipVertex, width: u32, height: u32) -> ScreenVertex {
+    let inv_w = 1.0 / v.clip.w;
+    let ndc = v.clip.xyz() * inv_w;
+    ScreenVertex {
+        x: (ndc.x * 0.5 + 0.5) * width as f32,
+        /* rows go top to bottom, GL's y goes up */
+        y: (0.5 - ndc.y * 0.5) * height as f32,
+        depth: ndc.z * 0.5 + 0.5,
+        inv_w,
+        world: v.world * inv_w,
+        normal: v.normal * inv_w,
+        uv: v.uv * inv_w,
+    }
+}
+
+/* the pixels the triangle's bounding box covers, clamped to the target */
+fn pixel_bounds(triangle: &[ScreenVertex; 3], width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let [a, b, c] = triangle;
+    let min_x = a.x.min(b.x).min(c.x).floor().max(0.0) as u32;
+    let min_y = a.y.min(b.y).min(c.y).floor().max(0.0) as u32;
+    let max_x = (a.x.max(b.x).max(c.x).ceil() as i64).clamp(0, width as i64) as u32;
+    let max_y = (a.y.max(b.y).max(c.y).ceil() as i64).clamp(0, height as i64) as u32;
+    (min_x, min_y, max_x, max_y)
+}
+
+/* rasterizes only the depth of the triangle, keeping the nearest */
+fn fill_depth(shadow_map: &mut SoftwareShadowMap, triangle: [ScreenVertex; 3]) {
+    let [a, b, c] = triangle;
+    let area = edge(&a, &b, c.x, c.y);
+    if area.abs() < f32::EPSILON {
+        return;
+    }
+    let (min_x, min_y, max_x, max_y) = pixel_bounds(&triangle, shadow_map.size, shadow_map.size);
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let wa = edge(&b, &c, px, py) / area;
+            let wb = edge(&c, &a, px, py) / area;
+            let wc = edge(&a, &b, px, py) / area;
+            if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                continue;
+            }
+            let depth = wa * a.depth + wb * b.depth + wc * c.depth;
+            let i = (y * shadow_map.size + x) as usize;
+            if (0.0..=1.0).contains(&depth) && depth < shadow_map.depth[i] {
+                shadow_map.depth[i] = depth;
+            }
+        }
+    }
+}
+
+/* twice the signed area of the triangle a, b, p */
+fn edge(a: &ScreenVertex, b: &ScreenVertex, px: f32, py: f32) -> f32 {
+    (b.x - a.x) * (py - a.y) - (b.y - a.y) * (px - a.x)
+}
+
+/* same lighting as param_blinn_phong_shader's fragment shader, None where alpha testing discards the fragment.
+`params` are the material's with the object's override applied */
+#[allow(clippy::too_many_arguments)]
+fn shade(
+    material: &Material,
+    params: &MaterialParams,
+    textures: &[RgbaImage],
+    surface: &Surface,
+    frame: &FrameParams,
+    shadow_map: Option<&SoftwareShadowMap>,
+    environment: Option<(&CubemapData, f32)>,
+    fragment: &Fragment,
+) -> Option<[u8; 4]> {
+    let (world, uv) = (fragment.world, fragment.uv);
+    let map = |map: Option<usize>| map.and_then(|texture_idx| textures.get(texture_idx)).map(|texture| sample(texture, uv));
+    let mut normal = fragment.normal.normalized();
+    if let Some(mapped) = map(material.bump_map) {
+        let mapped = mapped.xyz() * 2.0 - vec::Vec3::one();
+        /* the triangle's tangent frame, made perpendicular to the interpolated normal */
+        let tangent = (surface.tangent - normal * normal.dot(surface.tangent)).normalized();
+        let bitangent = (surface.bitangent - normal * normal.dot(surface.bitangent)).normalized();
+        let bumped = tangent * mapped.x + bitangent * mapped.y + normal * mapped.z;
+        if bumped.mag_sq() > 0.0 && bumped.x.is_finite() {
+            normal = bumped.normalized();
+        }
+    }
+    let (mut ambient, mut diffuse, mut specular) = (params.ambient, params.diffuse, params.specular);
+    let mut alpha = params.dissolve;
+    if let Some(albedo) = map(material.diffuse_map) {
+        if material.alpha_cutoff.is_some_and(|alpha_cutoff| albedo.w < alpha_cutoff) {
+            return None;
+        }
+        ambient *= albedo.xyz();
+        diffuse *= albedo.xyz();
+        alpha *= albedo.w;
+    }
+    if let Some(specular_map) = map(material.specular_map) {
+        specular *= specular_map.xyz();
+    }
+
+    let view_dir = (frame.view_pos - world).normalized();
+    let mut result = ambient * 0.05;
+    for (i, light) in frame.lights.iter().enumerate() {
+        let (light_dir, mut strength) = light.towards(world);
+        if let (Some(shadow), Some(shadow_map)) = (&frame.shadow, shadow_map) {
+            if shadow.light_index == i {
+                strength *= 1.0 - shadow_factor(shadow, shadow_map, world, normal, light_dir);
+            }
+        }
+        let diff = light_dir.dot(normal).max(0.0);
+        let spec = if diff > 0.0 {
+            let halfway_dir = (light_dir + view_dir).normalized();
+            normal.dot(halfway_dir).max(0.0).powf(params.shininess.max(1.0))
+        } else {
+            0.0
+        };
+        result += light.color * strength * (diffuse * diff + specular * spec);
+    }
+    if let Some((cubemap, intensity)) = environment.filter(|_| params.reflectivity > 0.0) {
+        let reflected = normal * 2.0 * normal.dot(view_dir) - view_dir;
+        let reflectivity = params.reflectivity;
+        result = result * (1.0 - reflectivity) + cubemap.sample(reflected) * intensity * reflectivity;
+    }
+    let [r, g, b] = *result.as_array();
+    Some([to_unorm(r), to_unorm(g), to_unorm(b), to_unorm(alpha)])
+}
+
+/* same as shadow_factor in param_blinn_phong_shader's fragment shader */
+fn shadow_factor(shadow: &ShadowParams, shadow_map: &SoftwareShadowMap, world: vec::Vec3, normal: vec::Vec3, light_dir: vec::Vec3) -> f32 {
+    let light_clip = shadow.light_space * world.into_homogeneous_point();
+    let proj = light_clip.xyz() / light_clip.w * 0.5 + vec::Vec3::broadcast(0.5);
+    if proj.z > 1.0 || shadow_map.size == 0 {
+        return 0.0;
+    }
+    let bias = shadow.settings.bias + shadow.settings.slope_bias * (1.0 - normal.dot(light_dir).max(0.0));
+    let size = shadow_map.size as i64;
+    let (x, y) = ((proj.x * size as f32).floor() as i64, ((1.0 - proj.y) * size as f32).floor() as i64);
+    let radius = shadow.settings.pcf_radius as i64;
+    let mut shadowed = 0.0;
+    for dx in -radius..=radius {
+        for dy in -radius..=radius {
+            let (sx, sy) = (x + dx, y - dy);
+            /* outside the map is the far plane, like GL's border color */
+            let closest = if (0..size).contains(&sx) && (0..size).contains(&sy) {
+                shadow_map.depth[(sy * size + sx) as usize]
+            } else {
+                1.0
+            };
+            if proj.z - bias > closest {
+                shadowed += 1.0;
+            }
+        }
+    }
+    shadowed / ((2 * radius + 1) * (2 * radius + 1)) as f32
+}
+
+/* bilinear with wrap around, like GL_LINEAR and GL_REPEAT. v goes up from the bottom row */
+fn sample(texture: &RgbaImage, uv: vec::Vec2) -> vec::Vec4 {
+    let (width, height) = (texture.width() as i64, texture.height() as i64);
+    if width == 0 || height == 0 {
+        return vec::Vec4::one();
+    }
+    let x = uv.x * width as f32 - 0.5;
+    let y = (1.0 - uv.y) * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |tx: i64, ty: i64| {
+        let pixel = texture.get_pixel(tx.rem_euclid(width) as u32, ty.rem_euclid(height) as u32).0;
+        vec::Vec4::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32, pixel[3] as f32) / 255.0
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+    let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/* directions of increasing u and v along the triangle, zero when its uvs don't span an area */
+fn tangent_frame(triangle: &[ClipVertex; 3]) -> (vec::Vec3, vec::Vec3) {
+    let [a, b, c] = triangle;
+    let (e1, e2) = (b.world - a.world, c.world - a.world);
+    let (d1, d2) = (b.uv - a.uv, c.uv - a.uv);
+    let det = d1.x * d2.y - d2.x * d1.y;
+    if det.abs() < f32::EPSILON {
+        return (vec::Vec3::zero(), vec::Vec3::zero());
+    }
+    ((e1 * d2.y - e2 * d1.y) / det, (e2 * d1.x - e1 * d2.x) / det)
+}
+
+fn to_unorm(channel: f32) -> u8 {
+    (channel.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/* what set_blend_state has GL do, the color mixed by its alpha and the alpha under it kept */
+fn blend_pixel(blend: BlendMode, [r, g, b, a]: [u8; 4], under: [u8; 4]) -> [u8; 4] {
+    let alpha = a as f32 / 255.0;
+    let mix = |over: u8, under: u8| {
+        let (over, under) = (over as f32 / 255.0, under as f32 / 255.0);
+        match blend {
+            BlendMode::Alpha => to_unorm(over * alpha + under * (1.0 - alpha)),
+            BlendMode::Additive => to_unorm(over * alpha + under),
+        }
+    };
+    [mix(r, under[0]), mix(g, under[1]), mix(b, under[2]), under[3]]
+}
+
+impl RenderBackend for SoftwareBackend {
+    fn name(&self) -> &'static str { "Software" }
+
+    fn upload_texture(&mut self, texture: &TextureData) -> Result<(), String> {
+        self.textures.push(texture.image.clone());
+        Ok(())
+    }
+
+    /* every material is shaded like param_blinn_phong_shader, whatever its shader */
+    fn upload_material(&mut self, material: &Material) -> Result<(), String> {
+        self.materials.push(material.clone());
+        Ok(())
+    }
+
+    fn replace_material(&mut self, material_idx: usize, material: &Material) -> Result<(), String> {
+        match self.materials.get_mut(material_idx) {
+            Some(old) => *old = material.clone(),
+            None => return Err(format!("No material {} to replace", material_idx)),
+        }
+        Ok(())
+    }
+
+    fn upload_mesh(&mut self, mesh_data: &MeshDataGroup) -> Result<(), String> {
+        let mut meshes = vec![];
+        for mesh in &mesh_data.0 {
+            /* point_data is position, normal and uv for every vertex, see combine_loaded_data */
+            let vertices = mesh.point_data.chunks_exact(8);
+            meshes.push(SoftwareMesh {
+                positions: vertices.clone().map(|v| vec::Vec3::new(v[0], v[1], v[2])).collect(),
+                normals: vertices.clone().map(|v| vec::Vec3::new(v[3], v[4], v[5])).collect(),
+                uvs: vertices.map(|v| vec::Vec2::new(v[6], v[7])).collect(),
+                indices: mesh.point_indices.clone(),
+            });
+        }
+        self.meshes.push(meshes);
+        Ok(())
+    }
+
+    fn upload_cubemap(&mut self, cubemap: &CubemapData) -> Result<(), String> {
+        self.cubemaps.push(cubemap.clone());
+        Ok(())
+    }
+
+    fn set_target(&mut self, target: RenderTarget) -> Result<(), String> {
+        let (width, height) = match target {
+            RenderTarget::Screen => self.screen_size,
+            RenderTarget::Image(width, height) => (width, height),
+        };
+        self.resize(width, height);
+        Ok(())
+    }
+
+    fn render_shadow_map(&mut self, shadow: &ShadowParams, casters: &[DrawCall]) {
+        let size = shadow.settings.resolution.max(1);
+        self.shadow_map.size = size;
+        self.shadow_map.depth.clear();
+        self.shadow_map.depth.resize((size * size) as usize, 1.0);
+        for call in casters {
+            let meshes = match self.meshes.get(call.mesh) {
+                Some(meshes) => meshes,
+                None => continue,
+            };
+            let light_model = shadow.light_space * call.model;
+            for mesh in meshes {
+                let vertices: Vec<ClipVertex> = mesh.positions.iter()
+                    .map(|position| ClipVertex {
+                        clip: light_model * position.into_homogeneous_point(),
+                        world: vec::Vec3::zero(),
+                        normal: vec::Vec3::zero(),
+                        uv: vec::Vec2::zero(),
+                    })
+                    .collect();
+                for triangle in mesh.indices.chunks_exact(3) {
+                    if let (Some(a), Some(b), Some(c)) = (vertices.get(triangle[0] as usize), vertices.get(triangle[1] as usize), vertices.get(triangle[2] as usize)) {
+                        let screen: Vec<ScreenVertex> = clip_near([*a, *b, *c]).iter().map(|v| to_screen(v, size, size)).collect();
+                        for i in 1..screen.len().saturating_sub(1) {
+                            fill_depth(&mut self.shadow_map, [screen[0], screen[i], screen[i + 1]]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn begin_frame(&mut self, frame: &FrameParams) {
+        let [r, g, b, a] = frame.clear_color;
+        self.color.fill([to_unorm(r), to_unorm(g), to_unorm(b), to_unorm(a)]);
+        self.depth.fill(1.0);
+        self.frame = Some(frame.clone());
+    }
+
+    /* one object at a time, there is nothing to gain from batches on the CPU */
+    fn draw_item(&mut self, item: &DrawItem, batch: &[DrawCall], _pre_draw: Option<&PreDraw>) {
+        for call in batch {
+            self.draw_call(call, item);
+        }
+    }
+
+    /* every pixel nothing was drawn on looks along its own direction into the cubemap */
+    fn draw_sky(&mut self) {
+        let frame = match &self.frame {
+            Some(frame) => frame,
+            None => return,
+        };
+        let (cubemap, intensity) = match frame.environment.and_then(|environment| self.cubemaps.get(environment.cubemap).map(|cubemap| (cubemap, environment.intensity))) {
+            Some(environment) => environment,
+            None => return,
+        };
+        /* the view without its translation, like the skybox shader's */
+        let mut view = frame.view;
+        view.cols[3] = vec::Vec4::new(0.0, 0.0, 0.0, 1.0);
+        let inverse = (frame.projection * view).inversed();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = (y * self.width + x) as usize;
+                if self.depth[i] < 1.0 {
+                    continue;
+                }
+                let ndc_x = (x as f32 + 0.5) / self.width as f32 * 2.0 - 1.0;
+                let ndc_y = 1.0 - (y as f32 + 0.5) / self.height as f32 * 2.0;
+                let far = inverse * vec::Vec4::new(ndc_x, ndc_y, 1.0, 1.0);
+                let [r, g, b] = *(cubemap.sample(far.xyz() / far.w) * intensity).as_array();
+                self.color[i] = [to_unorm(r), to_unorm(g), to_unorm(b), 255];
+            }
+        }
+    }
+
+    fn read_pixels(&mut self) -> Result<RgbaImage, String> {
+        let pixels = self.color.iter().flatten().copied().collect();
+        RgbaImage::from_raw(self.width, self.height, pixels)
+            .ok_or_else(|| "Software color buffer has the wrong size".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounds::Bounds;
+
+    /* an 8x8 target looking straight down -z with identity matrices, so clip space is the scene */
+    fn backend() -> SoftwareBackend {
+        let mut backend = SoftwareBackend::new(8, 8);
+        backend.upload_material(&Material::new("white", MaterialParams::default())).unwrap();
+        backend.begin_frame(&FrameParams {
+            view: mat::Mat4::identity(),
+            projection: mat::Mat4::identity(),
+            view_pos: vec::Vec3::new(0.0, 0.0, 1.0),
+            lights: vec![],
+            shadow: None,
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            environment: None,
+            post_process: vec![],
+        });
+        backend
+    }
+
+    fn triangle(corners: [(f32, f32); 3], z: f32) -> [ClipVertex; 3] {
+        corners.map(|(x, y)| ClipVertex {
+            clip: vec::Vec4::new(x, y, z, 1.0),
+            world: vec::Vec3::new(x, y, z),
+            normal: vec::Vec3::unit_z(),
+            uv: vec::Vec2::zero(),
+        })
+    }
+
+    fn surface() -> Surface {
+        Surface {
+            material_idx: 0,
+            material_override: MaterialOverride::default(),
+            receive_shadows: false,
+            blend: None,
+            depth_write: true,
+            tangent: vec::Vec3::unit_x(),
+            bitangent: vec::Vec3::unit_y(),
+        }
+    }
+
+    #[test]
+    fn triangle_covers_pixels_on_its_side_of_the_diagonal() {
+        let mut backend = backend();
+        /* the lower left half of the target, at NDC depth 0 */
+        backend.draw_triangle(triangle([(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0)], 0.0), surface());
+
+        for y in 0..8u32 {
+            for x in 0..8u32 {
+                let i = (y * 8 + x) as usize;
+                /* rows go top to bottom, the diagonal runs from the top left corner to the bottom right */
+                if x < y {
+                    assert_eq!(backend.depth[i], 0.5, "pixel {},{} not covered", x, y);
+                    assert_ne!(backend.color[i], [0, 0, 0, 255], "pixel {},{} not shaded", x, y);
+                } else if x > y {
+                    assert_eq!(backend.depth[i], 1.0, "pixel {},{} covered", x, y);
+                    assert_eq!(backend.color[i], [0, 0, 0, 255]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearer_triangles_win_the_depth_test() {
+        let mut backend = backend();
+        let full = [(-1.0, -1.0), (3.0, -1.0), (-1.0, 3.0)];
+        backend.draw_triangle(triangle(full, 0.0), surface());
+        backend.draw_triangle(triangle(full, 0.5), surface());
+        assert!(backend.depth.iter().all(|depth| *depth == 0.5));
+
+        backend.draw_triangle(triangle(full, -0.5), surface());
+        assert!(backend.depth.iter().all(|depth| *depth == 0.25));
+
+        /* behind the near plane, clipped away entirely */
+        backend.draw_triangle(triangle(full, -2.0), surface());
+        assert!(backend.depth.iter().all(|depth| *depth == 0.25));
+    }
+
+    /* one triangle over the whole target, facing the camera */
+    fn screen_mesh() -> MeshDataGroup {
+        let corner = |x: f32, y: f32| [x, y, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+        MeshDataGroup(vec![MeshData {
+            point_data: [corner(-1.0, -1.0), corner(3.0, -1.0), corner(-1.0, 3.0)].concat(),
+            point_indices: vec![0, 1, 2],
+            tri_count: 1,
+            material_idx: 0,
+            bounds: Bounds::from_points([vec::Vec3::zero()]),
+        }])
+    }
+
+    /* queues and draws the screen mesh with the object's overrides, returning the pass it went in */
+    fn draw_object(backend: &mut SoftwareBackend, object: &DrawableObject) -> RenderPass {
+        let meshes = vec![screen_mesh()];
+        backend.upload_mesh(&meshes[0]).unwrap();
+        let call = DrawCall {
+            mesh: object.drawable_group_idx,
+            model: mat::Mat4::identity(),
+            rotation: mat::Mat4::identity(),
+            object,
+            bounds: None,
+        };
+        let materials = [Material::new("white", MaterialParams::default())];
+        let mut queue = RenderQueue::new();
+        queue.push_batch(vec![call], &meshes, &materials, backend, (vec::Vec3::new(0.0, 0.0, 1.0), -vec::Vec3::unit_z()));
+        assert_eq!(queue.items.len(), 1);
+        let item = queue.items[0];
+        backend.draw_item(&item, &queue.batches[item.batch], None);
+        item.pass
+    }
+
+    #[test]
+    fn see_through_objects_are_blended_without_writing_depth() {
+        let faded = [
+            MaterialOverride::default().with_dissolve(0.5),
+            /* what FadeOut sets */
+            MaterialOverride::default().with_opacity(0.5),
+        ];
+        for material_override in faded {
+            let mut backend = backend();
+            let object = DrawableObject::new(vec::Vec3::zero(), vec::Vec3::zero(), vec::Vec3::one(), 0)
+                .with_material_override(material_override);
+            assert_eq!(draw_object(&mut backend, &object), RenderPass::Transparent);
+            assert!(backend.depth.iter().all(|depth| *depth == 1.0), "{:?} wrote depth", material_override);
+            assert!(backend.color.iter().all(|color| *color != [0, 0, 0, 255]), "{:?} not drawn", material_override);
+        }
+
+        let mut backend = backend();
+        let object = DrawableObject::new(vec::Vec3::zero(), vec::Vec3::zero(), vec::Vec3::one(), 0);
+        assert_eq!(draw_object(&mut backend, &object), RenderPass::Opaque);
+        assert!(backend.depth.iter().all(|depth| *depth == 0.5));
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+use rustproject::gllib::*;
+use rustproject::offscreen::*;
+use rustproject::scenes::*;
+
+/* Renders scenes with the software backend, so these run without a GPU, and compares them with
+the goldens under tests/golden. Set RUSTGRAPHICS_UPDATE_GOLDEN to rewrite them after a change
+to how things are drawn, and look at the new images before committing them. */
+
+fn render_scene(name: &str, width: u32, height: u32) -> image::RgbaImage {
+    let mut ctx = Context::software(width, height);
+    let mut model_map: HashMap<&str, usize> = HashMap::new();
+    for (name, path) in [
+        ("cone", "src/models/cone.obj"),
+        ("cube", "src/models/cube.obj"),
+        ("cone_ring", "src/models/cone_ring.obj"),
+        ("plane", "src/models/plane.obj"),
+        ("ball", "src/models/ball.obj"),
+        ("crate", "src/models/crate.obj"),
+    ] {
+        model_map.insert(name, ctx.load_model(path));
+    }
+    ctx.scenes = default_scene_registry();
+    switch_scene(&mut ctx, &model_map, name).expect("scene should load");
+    ctx.game_obj_store.update_world_transforms(1.0);
+    render_to_image(&mut ctx, width, height).expect("software rendering should work")
+}
+
+#[test]
+fn physics_scene_matches_golden() {
+    let image = render_scene("physics", 160, 120);
+    check_golden(&image, "tests/golden/physics.png", DiffTolerance::default()).unwrap();
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use beryllium::Keycode;
+use rapier2d::prelude::*;
+use rustproject::ecs::GameObjectID;
+use rustproject::gllib::*;
+use rustproject::headless::*;
+use rustproject::scenes::*;
+use rustproject::snapshot::*;
+use rustproject::scene_loader::*;
+use rustproject::behaviors::{Behavior, BehaviorOutput};
+use rustproject::collisions::CollisionInfo;
+use serde::{Serialize, Deserialize};
+
+/* the physics scene with its models loaded CPU side, like main does for --headless */
+fn physics_scene() -> (Context, HashMap<&'static str, usize>) {
+    let mut ctx = Context::headless(800, 600);
+    let mut model_map: HashMap<&str, usize> = HashMap::new();
+    for (name, path) in [
+        ("cone", "src/models/cone.obj"),
+        ("cube", "src/models/cube.obj"),
+        ("cone_ring", "src/models/cone_ring.obj"),
+        ("plane", "src/models/plane.obj"),
+        ("ball", "src/models/ball.obj"),
+        ("crate", "src/models/crate.obj"),
+    ] {
+        model_map.insert(name, ctx.load_model(path));
+    }
+    ctx.scenes = default_scene_registry();
+    switch_scene(&mut ctx, &model_map, "physics").expect("physics scene should load");
+    (ctx, model_map)
+}
+
+/* the object of the rigid body that starts at `start` */
+fn body_at(ctx: &Context, start: Vector<Real>) -> (GameObjectID, RigidBodyHandle) {
+    let (handle, _) = ctx.rigid_body_set.iter()
+        .find(|(_, body)| (body.translation() - start).norm() < 1e-4)
+        .expect("no rigid body at the start position");
+    (ctx.game_obj_store.lookup_by_rb_handle(&handle).expect("rigid body without an object"), handle)
+}
+
+#[test]
+fn physics_scene_settles() {
+    let (mut ctx, model_map) = physics_scene();
+    let objects = ctx.game_obj_store.len();
+    let (player, player_body) = body_at(&ctx, vector![0.0, 5.0]);
+    let (floor, _) = body_at(&ctx, vector![0.0, 0.0]);
+
+    let mut run = HeadlessRun::new(ScriptedInput::new());
+    run.run(&mut ctx, &model_map, 180).unwrap();
+    assert_eq!(run.steps_run(), 180);
+
+    /* nothing spawns or despawns without input */
+    assert_eq!(ctx.game_obj_store.len(), objects);
+    assert_eq!(ctx.current_scene.as_deref(), Some("physics"));
+
+    /* the floor is kinematic and stays put, the player falls onto it: floor top at 1, ball radius 1 */
+    let floor_pos = ctx.game_obj_store.transforms.get(&floor).unwrap().position;
+    assert!(floor_pos.y.abs() < 1e-4, "floor moved to {:?}", floor_pos);
+    let player_pos = ctx.game_obj_store.transforms.get(&player).unwrap().position;
+    assert!((player_pos.y - 2.0).abs() < 0.1, "player at {:?}", player_pos);
+    assert!(ctx.rigid_body_set[player_body].linvel().norm() < 0.5);
+
+    /* every body ends up on or above the floor */
+    for (id, handle) in ctx.game_obj_store.rigid_bodies.iter() {
+        let y = ctx.rigid_body_set[*handle].translation().y;
+        assert!(y > -0.1, "object {} fell through the floor to {}", id, y);
+    }
+}
+
+#[test]
+fn scripted_input_moves_the_player() {
+    let (mut ctx, model_map) = physics_scene();
+    let (player, _) = body_at(&ctx, vector![0.0, 5.0]);
+
+    let mut run = HeadlessRun::new(ScriptedInput::new().hold(Keycode::LEFT, 60, 50));
+    run.run(&mut ctx, &model_map, 60).unwrap();
+    let before = ctx.game_obj_store.transforms.get(&player).unwrap().position;
+    run.run(&mut ctx, &model_map, 60).unwrap();
+    let after = ctx.game_obj_store.transforms.get(&player).unwrap().position;
+
+    assert!(!run.keys_held().contains(&Keycode::LEFT));
+    assert!(after.x < before.x - 1.0, "player went from {:?} to {:?}", before, after);
+}
+
+/* every transform and body of the world, in store order */
+fn world_state(ctx: &Context) -> Vec<(GameObjectID, [f32; 6], [f32; 4])> {
+    ctx.game_obj_store.transforms.iter().map(|(id, transform)| {
+        let (p, r) = (transform.position, transform.rotation);
+        let body = ctx.game_obj_store.rigid_bodies.get(&id).map_or([0.0; 4], |handle| {
+            let body = &ctx.rigid_body_set[*handle];
+            [body.translation().x, body.translation().y, body.linvel().x, body.linvel().y]
+        });
+        (id, [p.x, p.y, p.z, r.x, r.y, r.z], body)
+    }).collect()
+}
+
+/* the physics scene after a scripted run */
+fn scripted_run() -> Vec<(GameObjectID, [f32; 6], [f32; 4])> {
+    let (mut ctx, model_map) = physics_scene();
+    let input = ScriptedInput::new()
+        .hold(Keycode::RIGHT, 0, 40)
+        .at(50, InputEvent::Press(Keycode::SPACE))
+        .at(55, InputEvent::Release(Keycode::SPACE))
+        .hold(Keycode::LEFT, 80, 40);
+    let mut run = HeadlessRun::new(input);
+    run.run(&mut ctx, &model_map, 200).unwrap();
+    world_state(&ctx)
+}
+
+#[test]
+fn same_input_gives_the_same_world() {
+    let first = scripted_run();
+    let second = scripted_run();
+    assert_eq!(first.len(), second.len());
+    for (a, b) in first.iter().zip(&second) {
+        assert_eq!(a, b, "runs diverged at object {}", a.0);
+    }
+}
+
+#[test]
+fn snapshots_restore_the_world() {
+    let (mut ctx, model_map) = physics_scene();
+    HeadlessRun::new(ScriptedInput::new().hold(Keycode::RIGHT, 0, 30)).run(&mut ctx, &model_map, 60).unwrap();
+    let saved = save_snapshot(&ctx).unwrap();
+
+    /* into a world that is somewhere else entirely */
+    let (mut restored, _) = physics_scene();
+    restore_snapshot(&mut restored, &saved).unwrap();
+    assert_eq!(world_state(&restored), world_state(&ctx));
+    assert_eq!(restored.floor_set, ctx.floor_set);
+    assert_eq!(restored.physics.timestep.time(), ctx.physics.timestep.time());
+    for (id, behaviors) in ctx.game_obj_store.behaviors.iter() {
+        let names = |behaviors: &Vec<Box<dyn rustproject::behaviors::Behavior>>| behaviors.iter().map(|b| b.name()).collect::<Vec<_>>();
+        assert_eq!(names(restored.game_obj_store.behaviors.get(&id).unwrap()), names(behaviors));
+    }
+
+    /* and both carry on the same */
+    HeadlessRun::new(ScriptedInput::new()).run(&mut ctx, &model_map, 60).unwrap();
+    HeadlessRun::new(ScriptedInput::new()).run(&mut restored, &model_map, 60).unwrap();
+    assert_eq!(world_state(&restored), world_state(&ctx));
+}
+
+#[test]
+fn despawned_floors_leave_the_floor_set() {
+    let (mut ctx, model_map) = physics_scene();
+    /* the lowest obstacle, a floor for GroundDetection */
+    let (cube, cube_body) = body_at(&ctx, vector![5.0, 2.0]);
+    assert!(ctx.floor_set.contains(&cube_body));
+    ctx.game_obj_store.behaviors.get_mut(&cube).unwrap().push(Box::new(rustproject::behaviors::FadeOut::new(0.1)));
+
+    HeadlessRun::new(ScriptedInput::new()).run(&mut ctx, &model_map, 20).unwrap();
+    assert!(!ctx.game_obj_store.contains(&cube));
+    assert!(!ctx.rigid_body_set.contains(cube_body));
+    assert!(!ctx.floor_set.contains(&cube_body));
+    assert_eq!(ctx.floor_set.len(), 10);
+}
+
+#[test]
+fn fade_out_lowers_opacity_then_despawns() {
+    let (mut ctx, model_map) = physics_scene();
+    let (player, player_body) = body_at(&ctx, vector![0.0, 5.0]);
+    ctx.game_obj_store.behaviors.get_mut(&player).unwrap().push(Box::new(rustproject::behaviors::FadeOut::new(0.5)));
+
+    let mut run = HeadlessRun::new(ScriptedInput::new());
+    let mut opacities = vec![];
+    while ctx.game_obj_store.contains(&player) {
+        assert!(run.steps_run() < 60, "still there after {} steps", run.steps_run());
+        run.step(&mut ctx, &model_map).unwrap();
+        if let Some(drawable) = ctx.game_obj_store.drawables.get(&player) {
+            let material_override = drawable.material_override;
+            opacities.push(material_override.opacity.unwrap());
+            /* see-through as soon as it starts fading */
+            for mesh in &ctx.meshes[drawable.drawable_group_idx].0 {
+                let material = &ctx.materials[material_override.material_for(mesh.material_idx, ctx.materials.len())];
+                assert_eq!(material.pass(&material_override.apply(&material.params)), rustproject::render_queue::RenderPass::Transparent);
+            }
+        }
+    }
+    assert!(opacities.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", opacities);
+    assert!(opacities[0] < 1.0 && *opacities.last().unwrap() < 0.1, "{:?}", opacities);
+    assert!(!ctx.rigid_body_set.contains(player_body));
+}
+
+#[test]
+fn despawning_a_parent_despawns_its_subtree() {
+    let mut ctx = Context::headless(64, 64);
+    let model_map = HashMap::new();
+    let leaf = || GameObject::empty();
+    let parent = GameObject::empty()
+        .add_child(GameObject::empty().add_child(leaf()).add_child(leaf()))
+        .add_child(leaf())
+        .add_behavior(rustproject::behaviors::FadeOut::new(0.01));
+    let parent = ctx.game_obj_store.add(parent);
+    let bystander = ctx.game_obj_store.add(leaf());
+    assert_eq!(ctx.game_obj_store.len(), 6);
+
+    HeadlessRun::new(ScriptedInput::new()).run(&mut ctx, &model_map, 2).unwrap();
+    assert!(!ctx.game_obj_store.contains(&parent));
+    assert!(ctx.game_obj_store.contains(&bystander));
+    assert_eq!(ctx.game_obj_store.len(), 1);
+    assert!(ctx.game_obj_store.hierarchy.is_empty());
+}
+
+/* writes down every collision and trigger hook its object gets, as (hook, other object) */
+#[derive(Default, Serialize, Deserialize)]
+struct Recorder {
+    #[serde(default)]
+    events: Vec<(String, GameObjectID)>,
+}
+impl Recorder {
+    fn record(&mut self, hook: &str, info: &CollisionInfo) -> BehaviorOutput {
+        self.events.push((hook.to_string(), info.other));
+        (vec![], vec![])
+    }
+}
+impl Behavior for Recorder {
+    fn name(&self) -> &'static str { "Recorder" }
+
+    fn on_collision_enter(&mut self, _loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.record("enter", info)
+    }
+    fn on_collision_stay(&mut self, _loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.record("stay", info)
+    }
+    fn on_collision_exit(&mut self, _loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.record("exit", info)
+    }
+    fn on_trigger_enter(&mut self, _loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.record("trigger enter", info)
+    }
+    fn on_trigger_exit(&mut self, _loop_ctx: &mut LoopContext, info: &CollisionInfo) -> BehaviorOutput {
+        self.record("trigger exit", info)
+    }
+}
+
+/* a scene file of bodies without models, Recorder can be attached to them */
+fn test_scene(source: &str) -> Context {
+    let mut ctx = Context::headless(64, 64);
+    ctx.behavior_registry.register::<Recorder>("Recorder");
+    let scene: SceneDesc = ron::from_str(source).expect("test scene should parse");
+    if let Err(e) = load_scene(&mut ctx, &HashMap::new(), &scene) {
+        panic!("test scene should load: {}", e);
+    }
+    ctx
+}
+
+/* the hooks the object's Recorder got so far */
+fn recorded(ctx: &Context, id: &GameObjectID) -> Vec<(String, GameObjectID)> {
+    ctx.game_obj_store.behaviors.get(id).unwrap().iter()
+        .find_map(|behavior| behavior.as_any().downcast_ref::<Recorder>())
+        .expect("no Recorder on the object")
+        .events.clone()
+}
+
+#[test]
+fn collisions_enter_stay_and_exit() {
+    let mut ctx = test_scene(r#"(objects: [
+        (floor: true, rigid_body: Some((kind: Fixed, collider: Some((shape: Cuboid(10.0, 0.5), collision_events: true))))),
+        (position: (0.0, 2.5, 0.0), rigid_body: Some((kind: Fixed, collider: Some((shape: Cuboid(10.0, 0.25), sensor: true))))),
+        (position: (0.0, 4.0, 0.0),
+            rigid_body: Some((kind: Dynamic, lock_rotations: true, collider: Some((shape: Ball(0.5), collision_events: true)))),
+            behaviors: [("GroundDetection", (max_slope: 45.0)), ("Recorder", (events: []))]),
+    ])"#);
+    let model_map = HashMap::new();
+    let (floor, floor_body) = body_at(&ctx, vector![0.0, 0.0]);
+    let (sensor, _) = body_at(&ctx, vector![0.0, 2.5]);
+    let (ball, _) = body_at(&ctx, vector![0.0, 4.0]);
+    let grounded = |ctx: &Context| *ctx.game_obj_store.grounded.get(&ball).unwrap();
+
+    let mut run = HeadlessRun::new(ScriptedInput::new());
+    run.step(&mut ctx, &model_map).unwrap();
+    assert!(!grounded(&ctx), "grounded while falling");
+    run.run(&mut ctx, &model_map, 119).unwrap();
+    assert!(grounded(&ctx), "not grounded on the floor");
+
+    /* on the way down it passes through the sensor, which only reports Enter and Exit */
+    let (triggers, events): (Vec<_>, Vec<_>) = recorded(&ctx, &ball).into_iter().partition(|(hook, _)| hook.starts_with("trigger"));
+    assert_eq!(triggers, vec![("trigger enter".to_string(), sensor), ("trigger exit".to_string(), sensor)]);
+
+    /* one Enter when it lands, then a Stay every step it rests there */
+    assert_eq!(events[0], ("enter".to_string(), floor));
+    assert!(events.len() > 30, "{:?}", events);
+    assert!(events[1..].iter().all(|event| *event == ("stay".to_string(), floor)), "{:?}", events);
+
+    /* the floor goes away under it, the last support */
+    ctx.game_obj_store.behaviors.get_mut(&floor).unwrap().push(Box::new(rustproject::behaviors::FadeOut::new(0.01)));
+    run.run(&mut ctx, &model_map, 3).unwrap();
+    assert!(!ctx.rigid_body_set.contains(floor_body));
+    let events = recorded(&ctx, &ball);
+    assert_eq!(events.last().unwrap(), &("exit".to_string(), floor));
+    assert_eq!(events.iter().filter(|(hook, _)| hook == "exit").count(), 1);
+    assert!(!grounded(&ctx), "still grounded without a floor");
+}
+
+#[test]
+fn bodies_trigger_a_sensor_once_on_the_way_through() {
+    let mut ctx = test_scene(r#"(objects: [
+        (rigid_body: Some((kind: Fixed, collider: Some((shape: Cuboid(10.0, 0.5), sensor: true)))),
+            behaviors: [("Recorder", (events: [])), ("TriggerApplyForce", (x: 2.0, y: 0.0, on_exit: true))]),
+        (position: (0.0, 3.0, 0.0),
+            rigid_body: Some((kind: Dynamic, lock_rotations: true, collider: Some((shape: Ball(0.5))))),
+            behaviors: [("Recorder", (events: []))]),
+    ])"#);
+    let model_map = HashMap::new();
+    let (sensor, _) = body_at(&ctx, vector![0.0, 0.0]);
+    let (ball, ball_body) = body_at(&ctx, vector![0.0, 3.0]);
+
+    HeadlessRun::new(ScriptedInput::new()).run(&mut ctx, &model_map, 120).unwrap();
+    let body = &ctx.rigid_body_set[ball_body];
+    assert!(body.translation().y < -5.0, "ball stopped at {:?}", body.translation());
+
+    /* both sides hear of it, once each way */
+    let through = |other| vec![("trigger enter".to_string(), other), ("trigger exit".to_string(), other)];
+    assert_eq!(recorded(&ctx, &sensor), through(ball));
+    assert_eq!(recorded(&ctx, &ball), through(sensor));
+    /* the exit pushed it sideways exactly once */
+    assert!((body.linvel().x - 2.0 / body.mass()).abs() < 1e-3, "{:?} with mass {}", body.linvel(), body.mass());
+}